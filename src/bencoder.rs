@@ -184,6 +184,11 @@ fn decode_str(input: &mut &[u8]) -> Result<Entry, NoneError> {
     let start = input.clone();
     let str_len = decode_num(input, &|i| i != ':')? as usize;
     skip(input, 1);
+
+    if str_len > input.len() {
+        return Err(NoneError);
+    }
+
     let res = &input[0..str_len];
     *input = &input[str_len..];
 
@@ -1,5 +1,23 @@
-use bencoder::EntryData;
+use bencoder::{Entry, EntryData};
 
+impl Entry {
+    /** An entry built for encoding, it has no source text **/
+    pub fn new(data: EntryData) -> Entry {
+        Entry {
+            data: data,
+            src: Vec::new()
+        }
+    }
+
+    /** Decoded entries re-encode exactly as they were received **/
+    pub fn bencode(&self) -> Vec<u8> {
+        if self.src.is_empty() {
+            self.data.bencode()
+        } else {
+            self.src.clone()
+        }
+    }
+}
 
 impl EntryData {
     //TODO: The bencode for List and Dictionary looks horrible, could be an iterator
@@ -10,7 +28,7 @@ impl EntryData {
             &EntryData::List(ref v) => {
                 let mut res = Vec::new();
                 res.extend("l".as_bytes());
-                v.iter().for_each(|i| res.extend(&i.data.bencode()));
+                v.iter().for_each(|i| res.extend(&i.bencode()));
                 res.extend("e".as_bytes());
                 res                
             },
@@ -18,9 +36,14 @@ impl EntryData {
                 let mut res = Vec::new();
                 res.extend("d".as_bytes());
  
-                for (name, data) in v {
+                //Keys must be sorted for the encoding to be canonical
+                let mut names: Vec<&String> = v.keys().collect();
+                names.sort();
+
+                for name in names {
+                    let data = &v[name];
                     res.extend(&EntryData::Str(name.as_bytes().to_vec()).bencode());
-                    res.extend(&data.data.bencode());
+                    res.extend(&data.bencode());
                 }
 
                 res.extend("e".as_bytes());
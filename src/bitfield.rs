@@ -1,39 +1,300 @@
+/**
+ * Piece bitfield stored as big-endian 64 bit words so bit 0 is the high bit of the first byte
+ * (matching the peer-wire layout) and bulk operations work a word at a time
+ */
+
+const WORD_BITS: usize = 64;
+
 #[derive(Clone)]
 pub struct Bitfield {
-    data: Vec<u8>
+    data: Vec<u64>,
+    len: usize
 }
 
 impl Bitfield {
-    pub fn new(data: Vec<u8>) -> Bitfield {
+    /** An empty bitfield of len bits **/
+    pub fn new(len: usize) -> Bitfield {
         Bitfield {
-            data: data
+            data: vec![0; (len + WORD_BITS - 1) / WORD_BITS],
+            len: len
+        }
+    }
+
+    /** Decode a peer-wire bitfield, which must be exactly the right size with no spare bits set **/
+    pub fn from_bytes(data: &[u8], len: usize) -> Result<Bitfield, String> {
+        if data.len() != (len + 7) / 8 {
+            return Err(format!("Bitfield of {} bytes, expected {}", data.len(), (len + 7) / 8));
+        }
+
+        let words: Vec<u64> = data.chunks(8).map(|chunk| {
+            chunk.iter().enumerate().fold(0u64, |word, (i, &b)| word | ((b as u64) << (56 - (i * 8))))
+        }).collect();
+
+        let field = Bitfield {
+            data: words,
+            len: len
+        };
+
+        if field.spare_bits() != 0 {
+            return Err("Bitfield has spare bits set".to_string());
+        }
+
+        Ok(field)
+    }
+
+    /** Encode as a peer-wire bitfield **/
+    pub fn to_bytes(&self) -> Vec<u8> {
+        (0..(self.len + 7) / 8).map(|i| (self.data[i / 8] >> (56 - ((i % 8) * 8))) as u8).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /** Bits set beyond len in the final word **/
+    fn spare_bits(&self) -> u64 {
+        let tail = self.len - self.data.len().saturating_sub(1) * WORD_BITS;
+        match self.data.last() {
+            Some(&word) => word & !Bitfield::head_mask(tail),
+            None => 0
+        }
+    }
+
+    fn check(&self, piece: usize) -> Result<(), String> {
+        if piece < self.len {
+            Ok(())
+        } else {
+            Err(format!("Piece {} out of range ({} pieces)", piece, self.len))
         }
     }
 
     fn chunk(piece: usize) -> usize {
-        piece / 8
+        piece / WORD_BITS
     }
 
-    fn bit(piece: usize) -> usize {
-        piece % 8
+    fn mask(piece: usize) -> u64 {
+        1 << (WORD_BITS - 1 - (piece % WORD_BITS))
     }
 
-    pub fn get(&self, piece: usize) -> bool {
-        let idx = Bitfield::chunk(piece);
+    /** Mask covering the first n bits of a word **/
+    fn head_mask(n: usize) -> u64 {
+        if n >= WORD_BITS {
+            !0
+        } else if n == 0 {
+            0
+        } else {
+            !0 << (WORD_BITS - n)
+        }
+    }
 
-        if idx >= self.data.len() {
+    pub fn get(&self, piece: usize) -> bool {
+        if piece >= self.len {
             false
         } else {
-            let bit = Bitfield::bit(piece); 
-            let mask = 1 << (7 - bit);
-            self.data[idx] & mask != 0
+            self.data[Bitfield::chunk(piece)] & Bitfield::mask(piece) != 0
+        }
+    }
+
+    pub fn set(&mut self, piece: usize) -> Result<(), String> {
+        self.check(piece)?;
+        self.data[Bitfield::chunk(piece)] |= Bitfield::mask(piece);
+        Ok(())
+    }
+
+    pub fn clear(&mut self, piece: usize) -> Result<(), String> {
+        self.check(piece)?;
+        self.data[Bitfield::chunk(piece)] &= !Bitfield::mask(piece);
+        Ok(())
+    }
+
+    pub fn set_all(&mut self) {
+        let len = self.len;
+        for (i, word) in self.data.iter_mut().enumerate() {
+            *word = Bitfield::head_mask(len - i * WORD_BITS);
+        }
+    }
+
+    pub fn clear_all(&mut self) {
+        self.data.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /** Number of set bits **/
+    pub fn count_ones(&self) -> usize {
+        self.data.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /** True if any bit is set **/
+    pub fn any(&self) -> bool {
+        self.data.iter().any(|&word| word != 0)
+    }
+
+    /** Number of unset bits **/
+    pub fn count_zeros(&self) -> usize {
+        self.len - self.count_ones()
+    }
+
+    /** True if every bit is set **/
+    pub fn is_complete(&self) -> bool {
+        self.count_zeros() == 0
+    }
+
+    fn zip_with<F: Fn(u64, u64) -> u64>(&self, other: &Bitfield, op: F) -> Bitfield {
+        Bitfield {
+            data: self.data.iter().enumerate().map(|(i, &word)| op(word, *other.data.get(i).unwrap_or(&0))).collect(),
+            len: self.len
+        }.truncated()
+    }
+
+    /** Clear any spare bits an operation may have set **/
+    fn truncated(mut self) -> Bitfield {
+        let tail = self.len - self.data.len().saturating_sub(1) * WORD_BITS;
+        if let Some(word) = self.data.last_mut() {
+            *word &= Bitfield::head_mask(tail);
+        }
+        self
+    }
+
+    /** Pieces set in both fields **/
+    pub fn and(&self, other: &Bitfield) -> Bitfield {
+        self.zip_with(other, |a, b| a & b)
+    }
+
+    /** Pieces set in either field (bits beyond our length are dropped) **/
+    pub fn or(&self, other: &Bitfield) -> Bitfield {
+        self.zip_with(other, |a, b| a | b)
+    }
+
+    /** Pieces set in this field but not the other **/
+    pub fn and_not(&self, other: &Bitfield) -> Bitfield {
+        self.zip_with(other, |a, b| a & !b)
+    }
+
+    /** Iterate the indices of set bits in ascending order **/
+    pub fn ones(&self) -> Ones {
+        Ones {
+            data: &self.data,
+            idx: 0,
+            current: self.data.get(0).cloned().unwrap_or(0),
+            len: self.len,
+            invert: false
+        }
+    }
+
+    /** Iterate the indices of unset bits in ascending order **/
+    pub fn zeros(&self) -> Ones {
+        Ones {
+            data: &self.data,
+            idx: 0,
+            current: !self.data.get(0).cloned().unwrap_or(0),
+            len: self.len,
+            invert: true
+        }
+    }
+}
+
+/**
+ * Word-at-a-time scan over a bitfield, yields set bits (or unset bits if inverted) below len
+ */
+pub struct Ones<'a> {
+    data: &'a [u64],
+    idx: usize,
+    current: u64,
+    len: usize,
+    invert: bool
+}
+
+impl<'a> Iterator for Ones<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        loop {
+            if self.idx * WORD_BITS >= self.len {
+                return None;
+            }
+
+            if self.current != 0 {
+                let offset = self.current.leading_zeros() as usize;
+                let piece = self.idx * WORD_BITS + offset;
+
+                if piece >= self.len {
+                    return None;
+                }
+
+                self.current &= !(1 << (WORD_BITS - 1 - offset));
+                return Some(piece);
+            }
+
+            self.idx += 1;
+
+            let word = self.data.get(self.idx).cloned().unwrap_or(0);
+            self.current = if self.invert { !word } else { word };
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitfield::Bitfield;
+
+    #[test]
+    fn get_set_clear() {
+        let mut field = Bitfield::from_bytes(&[0b1000_0001, 0, 0, 0, 0, 0, 0, 0, 0b0100_0000], 71).unwrap();
+        assert!(field.get(0) && field.get(7) && field.get(65));
+        assert!(!field.get(1) && !field.get(64) && !field.get(1000));
+
+        field.set(70).unwrap();
+        field.clear(0).unwrap();
+        assert!(field.get(70) && !field.get(0));
+        assert_eq!(field.count_ones(), 3);
+
+        assert!(field.set(71).is_err());
+        assert!(field.clear(1000).is_err());
+    }
+
+    #[test]
+    fn complete() {
+        let mut field = Bitfield::new(75);
+        assert!(!field.is_complete());
+        field.set_all();
+        assert!(field.is_complete());
+        assert_eq!(field.count_ones(), 75);
+        field.clear(74).unwrap();
+        assert!(!field.is_complete());
+        assert_eq!(field.count_zeros(), 1);
+    }
+
+    #[test]
+    fn set_operations() {
+        let a = Bitfield::from_bytes(&[0b1100_0000, 0b0000_0001], 16).unwrap();
+        let b = Bitfield::from_bytes(&[0b1010_0000, 0b0000_0001], 16).unwrap();
+
+        assert_eq!(a.and(&b).ones().collect::<Vec<_>>(), vec![0, 15]);
+        assert_eq!(a.or(&b).ones().collect::<Vec<_>>(), vec![0, 1, 2, 15]);
+        assert_eq!(a.and_not(&b).ones().collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn iterate() {
+        let mut field = Bitfield::new(131);
+        field.set(3).unwrap();
+        field.set(64).unwrap();
+        field.set(130).unwrap();
+        assert_eq!(field.ones().collect::<Vec<_>>(), vec![3, 64, 130]);
+
+        let mut zeros = field.zeros();
+        assert_eq!(zeros.next(), Some(0));
+        assert_eq!(zeros.count(), 127);
+        assert_eq!(field.zeros().last(), Some(129));
+    }
+
+    #[test]
+    fn wire_format() {
+        assert!(Bitfield::from_bytes(&[0, 0], 17).is_err());
+        assert!(Bitfield::from_bytes(&[0, 0, 0, 0], 17).is_err());
+        assert!(Bitfield::from_bytes(&[0, 0, 0b0100_0000], 17).is_err());
 
-    pub fn set(&mut self, piece: usize) {
-        let idx = Bitfield::chunk(piece);
-        let bit = Bitfield::bit(piece);
-        let mask = 1 << (7 - bit);
-        self.data[idx] = self.data[idx] | mask;
+        let bytes = [0xff, 0, 0x12, 0, 0, 0, 0, 0, 0xff, 0b1000_0000];
+        let field = Bitfield::from_bytes(&bytes, 73).unwrap();
+        assert_eq!(field.to_bytes(), bytes.to_vec());
     }
 }
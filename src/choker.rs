@@ -0,0 +1,141 @@
+/**
+ * Tit-for-tat choker deciding which interested peers we upload to.
+ * Every round the peers giving us the most (or taking the most when seeding) get the regular
+ * slots, and a few optimistic slots rotate between the rest so new peers get a chance to prove themselves.
+ */
+
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+use picker::PeerKey;
+
+/** A connected peer as seen by one choking round **/
+pub struct Candidate {
+    pub key: PeerKey,
+    pub interested: bool,
+    pub snubbed: bool, /* Only eligible for an optimistic slot */
+    pub rate: f64 /* Their rate to us when leeching, ours to them when seeding */
+}
+
+pub struct Choker {
+    slots: usize,
+    optimistic_slots: usize,
+    interval: Duration,
+    optimistic_interval: Duration,
+    next_round: Instant,
+    next_rotation: Instant,
+    optimistic: Vec<PeerKey>
+}
+
+impl Choker {
+    pub fn new(slots: usize, optimistic_slots: usize, interval: Duration, optimistic_interval: Duration) -> Choker {
+        let now = Instant::now();
+
+        Choker {
+            slots: slots,
+            optimistic_slots: optimistic_slots,
+            interval: interval,
+            optimistic_interval: optimistic_interval,
+            next_round: now,
+            next_rotation: now,
+            optimistic: Vec::new()
+        }
+    }
+
+    /**
+     * Run a round if one is due, returning the peers that should be unchoked.
+     * Everyone else should be choked.
+     */
+    pub fn run(&mut self, now: Instant, candidates: &[Candidate]) -> Option<Vec<PeerKey>> {
+        if now < self.next_round {
+            return None;
+        }
+
+        self.next_round = now + self.interval;
+
+        let mut interested: Vec<&Candidate> = candidates.iter().filter(|c| c.interested).collect();
+        interested.sort_by(|a, b| b.rate.partial_cmp(&a.rate).unwrap_or(::std::cmp::Ordering::Equal));
+
+        let mut unchoked: Vec<PeerKey> = interested.iter().filter(|c| !c.snubbed).take(self.slots).map(|c| c.key).collect();
+
+        //Optimistic slots stay with their peers between rotations unless they earned a regular slot or left
+        if now >= self.next_rotation {
+            self.next_rotation = now + self.optimistic_interval;
+            self.optimistic.clear();
+        }
+
+        self.optimistic.retain(|key| interested.iter().any(|c| c.key == *key) && !unchoked.contains(key));
+
+        let mut others: Vec<PeerKey> = interested.iter()
+            .map(|c| c.key)
+            .filter(|key| !unchoked.contains(key) && !self.optimistic.contains(key))
+            .collect();
+
+        thread_rng().shuffle(&mut others);
+
+        while self.optimistic.len() < self.optimistic_slots && !others.is_empty() {
+            self.optimistic.push(others.pop().unwrap());
+        }
+
+        unchoked.extend(self.optimistic.iter().cloned());
+        Some(unchoked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use choker::{Choker, Candidate};
+    use std::time::{Duration, Instant};
+
+    fn candidate(key: usize, interested: bool, rate: f64) -> Candidate {
+        Candidate {
+            key: key,
+            interested: interested,
+            snubbed: false,
+            rate: rate
+        }
+    }
+
+    #[test]
+    fn unchokes_fastest_and_rotates_optimistic() {
+        let mut choker = Choker::new(2, 1, Duration::from_secs(10), Duration::from_secs(30));
+        let start = Instant::now();
+
+        let peers = vec![
+            candidate(1, true, 10.0),
+            candidate(2, true, 30.0),
+            candidate(3, false, 100.0),
+            candidate(4, true, 20.0),
+            candidate(5, true, 0.0)
+        ];
+
+        //Peers 2 and 4 are fastest, 1 or 5 gets the optimistic slot
+        let first = choker.run(start, &peers).unwrap();
+        assert_eq!(&first[0..2], &[2, 4]);
+        assert_eq!(first.len(), 3);
+        assert!(first[2] == 1 || first[2] == 5);
+
+        assert!(choker.run(start + Duration::from_secs(5), &peers).is_none());
+
+        //The optimistic peer is kept until the rotation is due
+        assert_eq!(choker.run(start + Duration::from_secs(10), &peers).unwrap(), first);
+
+        let mut seen = vec![first[2]];
+        for round in 1..20 {
+            let unchoked = choker.run(start + Duration::from_secs(30 * round), &peers).unwrap();
+            seen.push(unchoked[2]);
+        }
+
+        assert!(seen.contains(&1) && seen.contains(&5));
+    }
+
+    #[test]
+    fn snubbed_peers_only_optimistic() {
+        let mut choker = Choker::new(1, 1, Duration::from_secs(10), Duration::from_secs(30));
+
+        let mut snubbed = candidate(1, true, 50.0);
+        snubbed.snubbed = true;
+
+        let peers = vec![snubbed, candidate(2, true, 10.0)];
+        assert_eq!(choker.run(Instant::now(), &peers).unwrap(), vec![2, 1]);
+    }
+}
@@ -0,0 +1,191 @@
+/**
+ * Arbitrary data stored in the DHT (BEP 44). Immutable items are found by the hash of their
+ * value, mutable ones by the hash of an ed25519 public key and salt and carry a signed sequence
+ * number so only the key holder can publish newer versions.
+ */
+
+use sha1;
+use ed25519_dalek::{SecretKey, PublicKey, ExpandedSecretKey, Signature, Verifier};
+use bencoder::decode;
+use dht::routing::NodeId;
+
+pub const MAX_VALUE_LEN: usize = 1000;
+pub const MAX_SALT_LEN: usize = 64;
+
+pub const ERROR_VALUE_TOO_BIG: i64 = 205;
+pub const ERROR_INVALID_SIGNATURE: i64 = 206;
+pub const ERROR_SALT_TOO_BIG: i64 = 207;
+pub const ERROR_CAS_MISMATCH: i64 = 301;
+pub const ERROR_SEQ_TOO_LOW: i64 = 302;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    pub value: Vec<u8>, /* Bencoded */
+    pub key: Option<Vec<u8>>, /* ed25519 public key, mutable items only */
+    pub signature: Option<Vec<u8>>,
+    pub seq: i64,
+    pub salt: Vec<u8>
+}
+
+fn sha1(parts: &[&[u8]]) -> NodeId {
+    let mut digest = sha1::Sha1::new();
+    parts.iter().for_each(|p| digest.update(p));
+    digest.digest().bytes()
+}
+
+/** The value must be a single bencoded entry, small enough to fit in a UDP packet **/
+fn check_value(value: &[u8]) -> Result<(), String> {
+    if value.len() > MAX_VALUE_LEN {
+        return Err("Value too big".to_string());
+    }
+
+    let mut rest = value;
+
+    if decode(&mut rest).is_err() || !rest.is_empty() {
+        return Err("Value is not a bencoded entry".to_string());
+    }
+
+    Ok(())
+}
+
+pub fn immutable_target(value: &[u8]) -> NodeId {
+    sha1(&[value])
+}
+
+pub fn mutable_target(key: &[u8], salt: &[u8]) -> NodeId {
+    sha1(&[key, salt])
+}
+
+/** Public key for a 32 byte ed25519 secret **/
+pub fn public_key(secret: &[u8; 32]) -> Vec<u8> {
+    let secret = SecretKey::from_bytes(secret).unwrap();
+    PublicKey::from(&secret).to_bytes().to_vec()
+}
+
+/** What a mutable item's signature covers, the bencoded salt, seq and v fields without the outer dictionary **/
+fn signed_buffer(salt: &[u8], seq: i64, value: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+
+    if !salt.is_empty() {
+        buffer.extend(format!("4:salt{}:", salt.len()).as_bytes());
+        buffer.extend(salt);
+    }
+
+    buffer.extend(format!("3:seqi{}e1:v", seq).as_bytes());
+    buffer.extend(value);
+    buffer
+}
+
+impl Item {
+    pub fn immutable(value: Vec<u8>) -> Result<Item, String> {
+        check_value(&value)?;
+
+        Ok(Item {
+            value: value,
+            key: None,
+            signature: None,
+            seq: 0,
+            salt: Vec::new()
+        })
+    }
+
+    /** A mutable item signed with a 32 byte ed25519 secret **/
+    pub fn mutable(secret: &[u8; 32], salt: Vec<u8>, seq: i64, value: Vec<u8>) -> Result<Item, String> {
+        check_value(&value)?;
+
+        if salt.len() > MAX_SALT_LEN {
+            return Err("Salt too big".to_string());
+        }
+
+        let secret = SecretKey::from_bytes(secret).map_err(|e| e.to_string())?;
+        let public = PublicKey::from(&secret);
+        let signature = ExpandedSecretKey::from(&secret).sign(&signed_buffer(&salt, seq, &value), &public);
+
+        Ok(Item {
+            value: value,
+            key: Some(public.to_bytes().to_vec()),
+            signature: Some(signature.to_bytes().to_vec()),
+            seq: seq,
+            salt: salt
+        })
+    }
+
+    pub fn is_mutable(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn target(&self) -> NodeId {
+        match self.key {
+            Some(ref key) => mutable_target(key, &self.salt),
+            None => immutable_target(&self.value)
+        }
+    }
+
+    /** Check an item from another node, returning the KRPC error code to reply with if it is bad **/
+    pub fn verify(&self) -> Result<(), (i64, String)> {
+        if self.value.len() > MAX_VALUE_LEN {
+            return Err((ERROR_VALUE_TOO_BIG, "Message (v field) too big".to_string()));
+        }
+
+        if self.salt.len() > MAX_SALT_LEN {
+            return Err((ERROR_SALT_TOO_BIG, "Salt (salt field) too big".to_string()));
+        }
+
+        if let Some(ref key) = self.key {
+            let invalid = || (ERROR_INVALID_SIGNATURE, "Invalid signature".to_string());
+
+            let key = PublicKey::from_bytes(key).map_err(|_| invalid())?;
+            let signature = self.signature.as_ref().and_then(|s| Signature::from_bytes(s).ok()).ok_or(invalid())?;
+
+            key.verify(&signed_buffer(&self.salt, self.seq, &self.value), &signature).map_err(|_| invalid())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dht::item::{Item, immutable_target, mutable_target};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn bep44_vectors() {
+        assert_eq!(immutable_target(b"12:Hello World!").to_vec(), hex("e5f96f6f38320f0f33959cb4d3d656452117aadb"));
+
+        let key = hex("77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548");
+        assert_eq!(mutable_target(&key, b"").to_vec(), hex("4a533d47ec9c7d95b1ad75f576cffc641853b750"));
+        assert_eq!(mutable_target(&key, b"foobar").to_vec(), hex("411eba73b6f087ca51a3795d9c8c938d365e32c1"));
+
+        let mut item = Item {
+            value: b"12:Hello World!".to_vec(),
+            key: Some(key),
+            signature: Some(hex("305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01")),
+            seq: 1,
+            salt: Vec::new()
+        };
+
+        assert!(item.verify().is_ok());
+
+        item.salt = b"foobar".to_vec();
+        assert!(item.verify().is_err());
+
+        item.signature = Some(hex("6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08"));
+        assert!(item.verify().is_ok());
+    }
+
+    #[test]
+    fn sign_and_tamper() {
+        let mut item = Item::mutable(&[9; 32], b"salt".to_vec(), 4, b"i42e".to_vec()).unwrap();
+        assert!(item.verify().is_ok());
+
+        item.seq = 5;
+        assert!(item.verify().is_err());
+
+        assert!(Item::immutable(b"i42".to_vec()).is_err());
+        assert!(Item::immutable(b"i42ei1e".to_vec()).is_err());
+    }
+}
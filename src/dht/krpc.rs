@@ -0,0 +1,364 @@
+/**
+ * KRPC, the bencoded query/response protocol DHT nodes speak over UDP
+ */
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use bencoder::{Entry, EntryData, decode};
+use tracker::PeerAddress;
+use dht::routing::{NodeId, ID_LEN, id_from_slice};
+use dht::item::Item;
+
+pub const ERROR_GENERIC: i64 = 201;
+pub const ERROR_PROTOCOL: i64 = 203;
+pub const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+/** Compact node info is a 20 byte id followed by a 6 byte IPv4 address and port **/
+const COMPACT_NODE_LEN: usize = ID_LEN + 6;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Ping,
+    FindNode(NodeId),
+    GetPeers(NodeId),
+    AnnouncePeer { info_hash: NodeId, port: u16, implied_port: bool, token: Vec<u8> },
+    Get { target: NodeId, seq: Option<i64> }, /* With seq only newer mutable items are returned */
+    Put { token: Vec<u8>, item: Item, cas: Option<i64> }, /* With cas the put only replaces that sequence number */
+    Unknown(Vec<u8>) /* Method name, answered with an error */
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    pub nodes: Vec<(NodeId, SocketAddr)>,
+    pub values: Vec<PeerAddress>,
+    pub token: Option<Vec<u8>>,
+    pub item: Option<Item> /* Salt is not sent back, the requester knows it */
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Body {
+    Query(Query),
+    Response(Response),
+    Error(i64, String)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KrpcMessage {
+    pub tid: Vec<u8>,
+    pub id: NodeId, /* Sender's node id, zero for errors which don't carry one */
+    pub body: Body
+}
+
+fn bytes(data: &[u8]) -> Entry {
+    Entry::new(EntryData::Str(data.to_vec()))
+}
+
+fn int(n: i64) -> Entry {
+    Entry::new(EntryData::Int(n))
+}
+
+fn field_bytes(entry: &Entry, key: &str) -> Option<Vec<u8>> {
+    match entry.field(key) {
+        Ok(Entry { data: EntryData::Str(v), .. }) => Some(v),
+        _ => None
+    }
+}
+
+fn field_int(entry: &Entry, key: &str) -> Option<i64> {
+    match entry.field(key) {
+        Ok(Entry { data: EntryData::Int(n), .. }) => Some(n),
+        _ => None
+    }
+}
+
+/** The raw bencoded value of a field **/
+fn field_raw(entry: &Entry, key: &str) -> Option<Vec<u8>> {
+    entry.field(key).ok().map(|v| v.src)
+}
+
+/** Add an item's fields to a query or response dictionary **/
+fn insert_item(dict: &mut HashMap<String, Entry>, item: &Item) {
+    if let Ok(value) = decode(&mut &item.value[..]) {
+        dict.insert("v".to_string(), value);
+    }
+
+    if let (&Some(ref key), &Some(ref signature)) = (&item.key, &item.signature) {
+        dict.insert("k".to_string(), bytes(key));
+        dict.insert("sig".to_string(), bytes(signature));
+        dict.insert("seq".to_string(), int(item.seq));
+    }
+}
+
+/** An item from a query or response dictionary, mutable if it has a key **/
+fn item_fields(entry: &Entry, salt: Vec<u8>) -> Option<Item> {
+    Some(Item {
+        value: field_raw(entry, "v")?,
+        key: field_bytes(entry, "k"),
+        signature: field_bytes(entry, "sig"),
+        seq: field_int(entry, "seq").unwrap_or(0),
+        salt: salt
+    })
+}
+
+fn field_id(entry: &Entry, key: &str) -> Result<NodeId, String> {
+    field_bytes(entry, key).and_then(|v| id_from_slice(&v)).ok_or(format!("Missing or bad {}", key))
+}
+
+pub fn encode_nodes(nodes: &[(NodeId, SocketAddr)]) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for &(ref id, addr) in nodes.iter() {
+        if let SocketAddr::V4(addr) = addr {
+            data.extend_from_slice(id);
+            data.extend_from_slice(&addr.ip().octets());
+            data.push((addr.port() >> 8) as u8);
+            data.push(addr.port() as u8);
+        }
+    }
+
+    data
+}
+
+pub fn decode_nodes(data: &[u8]) -> Vec<(NodeId, SocketAddr)> {
+    data.chunks(COMPACT_NODE_LEN).filter(|c| c.len() == COMPACT_NODE_LEN).map(|c| {
+        let ip = Ipv4Addr::new(c[20], c[21], c[22], c[23]);
+        let port = ((c[24] as u16) << 8) | c[25] as u16;
+        (id_from_slice(&c[0..ID_LEN]).unwrap(), SocketAddr::new(IpAddr::V4(ip), port))
+    }).collect()
+}
+
+impl KrpcMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+        dict.insert("t".to_string(), bytes(&self.tid));
+
+        match self.body {
+            Body::Query(ref query) => {
+                let mut args = HashMap::new();
+                args.insert("id".to_string(), bytes(&self.id));
+
+                let method = match query {
+                    &Query::Ping => &b"ping"[..],
+                    &Query::FindNode(ref target) => {
+                        args.insert("target".to_string(), bytes(target));
+                        &b"find_node"[..]
+                    },
+                    &Query::GetPeers(ref info_hash) => {
+                        args.insert("info_hash".to_string(), bytes(info_hash));
+                        &b"get_peers"[..]
+                    },
+                    &Query::AnnouncePeer { ref info_hash, port, implied_port, ref token } => {
+                        args.insert("info_hash".to_string(), bytes(info_hash));
+                        args.insert("port".to_string(), int(port as i64));
+                        args.insert("implied_port".to_string(), int(implied_port as i64));
+                        args.insert("token".to_string(), bytes(token));
+                        &b"announce_peer"[..]
+                    },
+                    &Query::Get { ref target, seq } => {
+                        args.insert("target".to_string(), bytes(target));
+
+                        if let Some(seq) = seq {
+                            args.insert("seq".to_string(), int(seq));
+                        }
+
+                        &b"get"[..]
+                    },
+                    &Query::Put { ref token, ref item, cas } => {
+                        args.insert("token".to_string(), bytes(token));
+                        insert_item(&mut args, item);
+
+                        if !item.salt.is_empty() {
+                            args.insert("salt".to_string(), bytes(&item.salt));
+                        }
+
+                        if let Some(cas) = cas {
+                            args.insert("cas".to_string(), int(cas));
+                        }
+
+                        &b"put"[..]
+                    },
+                    &Query::Unknown(ref method) => &method[..]
+                };
+
+                dict.insert("y".to_string(), bytes(b"q"));
+                dict.insert("q".to_string(), bytes(method));
+                dict.insert("a".to_string(), Entry::new(EntryData::Dictionary(args)));
+            },
+            Body::Response(ref response) => {
+                let mut values = HashMap::new();
+                values.insert("id".to_string(), bytes(&self.id));
+
+                if !response.nodes.is_empty() {
+                    values.insert("nodes".to_string(), bytes(&encode_nodes(&response.nodes)));
+                }
+
+                if !response.values.is_empty() {
+                    let peers = response.values.iter().map(|p| bytes(&p.to_compact())).collect();
+                    values.insert("values".to_string(), Entry::new(EntryData::List(peers)));
+                }
+
+                if let Some(ref token) = response.token {
+                    values.insert("token".to_string(), bytes(token));
+                }
+
+                if let Some(ref item) = response.item {
+                    insert_item(&mut values, item);
+                }
+
+                dict.insert("y".to_string(), bytes(b"r"));
+                dict.insert("r".to_string(), Entry::new(EntryData::Dictionary(values)));
+            },
+            Body::Error(code, ref message) => {
+                dict.insert("y".to_string(), bytes(b"e"));
+                dict.insert("e".to_string(), Entry::new(EntryData::List(vec![int(code), bytes(message.as_bytes())])));
+            }
+        }
+
+        EntryData::Dictionary(dict).bencode()
+    }
+
+    pub fn decode(mut data: &[u8]) -> Result<KrpcMessage, String> {
+        let root = decode(&mut data).map_err(|_| "Malformed KRPC message".to_string())?;
+
+        let tid = field_bytes(&root, "t").ok_or("Missing transaction id")?;
+        let kind = field_bytes(&root, "y").ok_or("Missing message type")?;
+
+        match &kind[..] {
+            b"q" => {
+                let method = field_bytes(&root, "q").ok_or("Missing query method")?;
+                let args = root.field("a").map_err(|e| e.to_string())?;
+                let id = field_id(&args, "id")?;
+
+                let query = match &method[..] {
+                    b"ping" => Query::Ping,
+                    b"find_node" => Query::FindNode(field_id(&args, "target")?),
+                    b"get_peers" => Query::GetPeers(field_id(&args, "info_hash")?),
+                    b"announce_peer" => {
+                        let port = field_int(&args, "port").unwrap_or(0);
+
+                        Query::AnnouncePeer {
+                            info_hash: field_id(&args, "info_hash")?,
+                            port: if port > 0 && port <= 65535 { port as u16 } else { 0 },
+                            implied_port: field_int(&args, "implied_port").unwrap_or(0) != 0,
+                            token: field_bytes(&args, "token").ok_or("Missing token")?
+                        }
+                    },
+                    b"get" => Query::Get {
+                        target: field_id(&args, "target")?,
+                        seq: field_int(&args, "seq")
+                    },
+                    b"put" => Query::Put {
+                        token: field_bytes(&args, "token").ok_or("Missing token")?,
+                        item: item_fields(&args, field_bytes(&args, "salt").unwrap_or(Vec::new())).ok_or("Missing value")?,
+                        cas: field_int(&args, "cas")
+                    },
+                    _ => Query::Unknown(method.clone())
+                };
+
+                Ok(KrpcMessage {
+                    tid: tid,
+                    id: id,
+                    body: Body::Query(query)
+                })
+            },
+            b"r" => {
+                let values = root.field("r").map_err(|e| e.to_string())?;
+
+                let peers = match values.field("values") {
+                    Ok(Entry { data: EntryData::List(list), .. }) => list.iter().flat_map(|p| match p.data {
+                        EntryData::Str(ref v) => PeerAddress::from_compact_list(v, false),
+                        _ => Vec::new()
+                    }).collect(),
+                    _ => Vec::new()
+                };
+
+                Ok(KrpcMessage {
+                    tid: tid,
+                    id: field_id(&values, "id")?,
+                    body: Body::Response(Response {
+                        nodes: field_bytes(&values, "nodes").map(|n| decode_nodes(&n)).unwrap_or(Vec::new()),
+                        values: peers,
+                        token: field_bytes(&values, "token"),
+                        item: item_fields(&values, Vec::new())
+                    })
+                })
+            },
+            b"e" => {
+                let (code, message) = match root.field("e") {
+                    Ok(Entry { data: EntryData::List(ref list), .. }) if list.len() >= 2 => {
+                        (list[0].as_usize().unwrap_or(ERROR_GENERIC as usize) as i64, list[1].to_string())
+                    },
+                    _ => (ERROR_GENERIC, String::new())
+                };
+
+                Ok(KrpcMessage {
+                    tid: tid,
+                    id: [0; ID_LEN],
+                    body: Body::Error(code, message)
+                })
+            },
+            _ => Err("Unknown message type".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dht::krpc::{KrpcMessage, Body, Query, Response};
+    use dht::item::Item;
+    use tracker::PeerAddress;
+    use std::net::SocketAddr;
+
+    fn round_trip(msg: KrpcMessage) {
+        assert_eq!(KrpcMessage::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        round_trip(KrpcMessage { tid: b"aa".to_vec(), id: [1; 20], body: Body::Query(Query::Ping) });
+        round_trip(KrpcMessage { tid: b"ab".to_vec(), id: [1; 20], body: Body::Query(Query::FindNode([2; 20])) });
+        round_trip(KrpcMessage { tid: b"ac".to_vec(), id: [1; 20], body: Body::Query(Query::GetPeers([3; 20])) });
+        round_trip(KrpcMessage {
+            tid: b"ad".to_vec(),
+            id: [1; 20],
+            body: Body::Query(Query::AnnouncePeer { info_hash: [3; 20], port: 6881, implied_port: true, token: b"tok".to_vec() })
+        });
+
+        round_trip(KrpcMessage {
+            tid: b"ae".to_vec(),
+            id: [4; 20],
+            body: Body::Response(Response {
+                nodes: vec![([5; 20], SocketAddr::from(([10, 0, 0, 1], 6881)))],
+                values: vec![PeerAddress { ip: "10.0.0.2".parse().unwrap(), port: 51413 }],
+                token: Some(b"secret".to_vec()),
+                item: None
+            })
+        });
+
+        let item = Item::mutable(&[2; 32], b"salt".to_vec(), 3, b"l4:spami7ee".to_vec()).unwrap();
+        round_trip(KrpcMessage { tid: b"ah".to_vec(), id: [1; 20], body: Body::Query(Query::Get { target: item.target(), seq: Some(2) }) });
+        round_trip(KrpcMessage { tid: b"ai".to_vec(), id: [1; 20], body: Body::Query(Query::Put { token: b"tok".to_vec(), item: item.clone(), cas: Some(2) }) });
+
+        let mut unsalted = item.clone();
+        unsalted.salt = Vec::new();
+        round_trip(KrpcMessage {
+            tid: b"aj".to_vec(),
+            id: [4; 20],
+            body: Body::Response(Response { item: Some(unsalted), ..Response::default() })
+        });
+
+        round_trip(KrpcMessage { tid: b"ag".to_vec(), id: [1; 20], body: Body::Query(Query::Unknown(b"vote".to_vec())) });
+        round_trip(KrpcMessage { tid: b"af".to_vec(), id: [0; 20], body: Body::Error(203, "Bad token".to_string()) });
+    }
+
+    #[test]
+    fn bep5_example() {
+        let ping = KrpcMessage::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe").unwrap();
+        assert_eq!(ping.tid, b"aa".to_vec());
+        assert_eq!(&ping.id, b"abcdefghij0123456789");
+        assert_eq!(ping.body, Body::Query(Query::Ping));
+
+        assert!(KrpcMessage::decode(b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe").is_err());
+        assert!(KrpcMessage::decode(b"d1:t2:aa1:y1:qe").is_err());
+    }
+}
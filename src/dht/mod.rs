@@ -0,0 +1,835 @@
+/**
+ * Mainline DHT (BEP 5). A node runs on the event loop with a UDP socket, answers queries from
+ * other nodes and runs iterative lookups to find and announce peers for info hashes, or to get
+ * and put arbitrary items (BEP 44).
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::net;
+use std::net::{SocketAddr, IpAddr, ToSocketAddrs};
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::io::ErrorKind::WouldBlock;
+use mio::Ready;
+use mio::net::UdpSocket;
+use mio_extras::channel;
+use event_loop::{EventLoop, Handler, Context, Event};
+use sha1;
+use bencoder::{Entry, EntryData, decode};
+use tracker::PeerAddress;
+
+mod routing;
+mod krpc;
+mod item;
+
+pub use dht::routing::{NodeId, id_from_slice};
+pub use dht::item::{Item, immutable_target, mutable_target, public_key};
+pub use dht::item::{ERROR_CAS_MISMATCH, ERROR_SEQ_TOO_LOW};
+use dht::routing::{RoutingTable, K, distance, random_id};
+use dht::krpc::{KrpcMessage, Body, Query, Response, encode_nodes, decode_nodes, ERROR_GENERIC, ERROR_PROTOCOL, ERROR_METHOD_UNKNOWN};
+
+/** Queries a lookup keeps in flight at once **/
+const ALPHA: usize = 3;
+
+const QUERY_TIMEOUT_SECS: u64 = 5;
+
+/** How often timed out queries are expired and the table maintained **/
+const TICK_MS: u64 = 500;
+
+/** Sources the node registers with the event loop **/
+const SOURCE_SOCKET: usize = 0;
+const SOURCE_REQUESTS: usize = 1;
+
+/** A small table is topped up from the bootstrap nodes this often, a healthy one is refreshed less **/
+const BOOTSTRAP_RETRY_SECS: u64 = 30;
+const REFRESH_SECS: u64 = 15 * 60;
+
+/** Token secrets rotate this often, tokens from the previous secret are still accepted **/
+const SECRET_ROTATE_SECS: u64 = 5 * 60;
+
+const SAVE_SECS: u64 = 5 * 60;
+
+/** Announced peers are forgotten after this long unless announced again **/
+const PEER_EXPIRY_SECS: u64 = 30 * 60;
+const MAX_PEERS_PER_HASH: usize = 100;
+
+/** Items others put are kept this long unless put again **/
+const ITEM_EXPIRY_SECS: u64 = 2 * 60 * 60;
+const MAX_ITEMS: usize = 1000;
+
+/** Outcome of a put at one node, an error carries the KRPC error code and message **/
+pub type PutResult = Result<(), (i64, String)>;
+
+pub enum DhtRequest {
+    GetPeers(NodeId, Sender<Vec<PeerAddress>>),
+    Announce(NodeId, u16, Sender<Vec<PeerAddress>>),
+    Get(NodeId, Vec<u8>, Sender<Item>), /* Target and the salt mutable items were published with */
+    Put(Item, Option<i64>, Sender<PutResult>),
+    Close
+}
+
+/**
+ * Handle to a running DHT node
+ */
+#[derive(Clone)]
+pub struct Dht {
+    send: channel::Sender<DhtRequest>,
+    port: u16
+}
+
+impl Dht {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /** Look up peers for an info hash, batches arrive as they are found and the channel closes when the lookup ends **/
+    pub fn get_peers(&self, info_hash: NodeId) -> Receiver<Vec<PeerAddress>> {
+        let (send, recv) = mpsc::channel();
+        self.send.send(DhtRequest::GetPeers(info_hash, send)).ok();
+        recv
+    }
+
+    /** As get_peers, then announce that we accept connections for info hash on port **/
+    pub fn announce(&self, info_hash: NodeId, port: u16) -> Receiver<Vec<PeerAddress>> {
+        let (send, recv) = mpsc::channel();
+        self.send.send(DhtRequest::Announce(info_hash, port, send)).ok();
+        recv
+    }
+
+    /** Fetch an immutable item by the hash of its value, it is sent when the lookup ends if any node had it **/
+    pub fn get(&self, target: NodeId) -> Receiver<Item> {
+        let (send, recv) = mpsc::channel();
+        self.send.send(DhtRequest::Get(target, Vec::new(), send)).ok();
+        recv
+    }
+
+    /** Fetch the newest mutable item published under a public key and salt **/
+    pub fn get_mutable(&self, key: &[u8], salt: &[u8]) -> Receiver<Item> {
+        let (send, recv) = mpsc::channel();
+        self.send.send(DhtRequest::Get(mutable_target(key, salt), salt.to_vec(), send)).ok();
+        recv
+    }
+
+    /**
+     * Store an item at the nodes closest to its target, each node's answer is sent back. With
+     * cas a mutable item only replaces the version with that sequence number.
+     */
+    pub fn put(&self, item: Item, cas: Option<i64>) -> Receiver<PutResult> {
+        let (send, recv) = mpsc::channel();
+        self.send.send(DhtRequest::Put(item, cas, send)).ok();
+        recv
+    }
+
+    pub fn close(&self) {
+        self.send.send(DhtRequest::Close).ok();
+    }
+}
+
+/** A handle whose requests arrive on the returned channel rather than at a node, for tests **/
+#[cfg(test)]
+pub fn detached_dht() -> (Dht, channel::Receiver<DhtRequest>) {
+    let (send, recv) = channel::channel();
+    (Dht { send: send, port: 0 }, recv)
+}
+
+enum LookupKind {
+    Bootstrap,
+    GetPeers(Sender<Vec<PeerAddress>>),
+    Announce(u16, Sender<Vec<PeerAddress>>),
+    Get(Vec<u8>, Sender<Item>),
+    Put(Item, Option<i64>, Sender<PutResult>)
+}
+
+struct Lookup {
+    target: NodeId,
+    kind: LookupKind,
+    candidates: Vec<(NodeId, SocketAddr)>, /* Closest first */
+    queried: HashSet<SocketAddr>,
+    tokens: HashMap<SocketAddr, Vec<u8>>,
+    found: HashSet<PeerAddress>,
+    item: Option<Item>, /* Newest valid item found by a get */
+    in_flight: usize
+}
+
+struct Transaction {
+    addr: SocketAddr,
+    sent: Instant,
+    lookup: Option<usize>,
+    reply: Option<Sender<PutResult>>
+}
+
+struct Node {
+    id: NodeId,
+    socket: UdpSocket,
+    requests: channel::Receiver<DhtRequest>,
+    table: RoutingTable,
+    bootstrap: Vec<SocketAddr>,
+    state_path: Option<String>,
+    next_tid: u16,
+    transactions: HashMap<Vec<u8>, Transaction>,
+    next_lookup: usize,
+    lookups: HashMap<usize, Lookup>,
+    secret: [u8; 20],
+    previous_secret: [u8; 20],
+    peers: HashMap<NodeId, Vec<(PeerAddress, Instant)>>,
+    items: HashMap<NodeId, (Item, Instant)>,
+    last_bootstrap: Option<Instant>,
+    last_rotate: Instant,
+    last_save: Instant
+}
+
+fn token(secret: &[u8], ip: &IpAddr) -> Vec<u8> {
+    let mut digest = sha1::Sha1::new();
+    digest.update(secret);
+
+    match ip {
+        &IpAddr::V4(ip) => digest.update(&ip.octets()),
+        &IpAddr::V6(ip) => digest.update(&ip.octets())
+    }
+
+    digest.digest().bytes().to_vec()
+}
+
+/** Load a saved node id and routing table **/
+fn load_state(path: &str) -> Option<(NodeId, Vec<(NodeId, SocketAddr)>)> {
+    let mut buffer = Vec::new();
+    File::open(path).and_then(|mut f| f.read_to_end(&mut buffer)).ok()?;
+
+    let root = decode(&mut &buffer[..]).ok()?;
+
+    let id = match root.field("id") {
+        Ok(Entry { data: EntryData::Str(id), .. }) => id_from_slice(&id)?,
+        _ => return None
+    };
+
+    let nodes = match root.field("nodes") {
+        Ok(Entry { data: EntryData::Str(nodes), .. }) => decode_nodes(&nodes),
+        _ => Vec::new()
+    };
+
+    Some((id, nodes))
+}
+
+impl Node {
+
+    fn save(&self) {
+        if let Some(ref path) = self.state_path {
+            let nodes: Vec<(NodeId, SocketAddr)> = self.table.nodes().iter().map(|n| (n.id, n.addr)).collect();
+
+            let mut dict = HashMap::new();
+            dict.insert("id".to_string(), Entry::new(EntryData::Str(self.id.to_vec())));
+            dict.insert("nodes".to_string(), Entry::new(EntryData::Str(encode_nodes(&nodes))));
+
+            if let Err(e) = File::create(path).and_then(|mut f| f.write_all(&EntryData::Dictionary(dict).bencode())) {
+                println!("DHT: Could not save state to {} because {}", path, e);
+            }
+        }
+    }
+
+    fn send(&self, addr: SocketAddr, msg: KrpcMessage) {
+        if let Err(e) = self.socket.send_to(&msg.encode(), &addr) {
+            println!("DHT: Send to {} failed because {}", addr, e);
+        }
+    }
+
+    fn query(&mut self, addr: SocketAddr, query: Query, lookup: Option<usize>, reply: Option<Sender<PutResult>>) {
+        self.next_tid = self.next_tid.wrapping_add(1);
+        let tid = vec![(self.next_tid >> 8) as u8, self.next_tid as u8];
+
+        self.transactions.insert(tid.clone(), Transaction {
+            addr: addr,
+            sent: Instant::now(),
+            lookup: lookup,
+            reply: reply
+        });
+
+        let msg = KrpcMessage {
+            tid: tid,
+            id: self.id,
+            body: Body::Query(query)
+        };
+
+        self.send(addr, msg);
+    }
+
+    fn start_lookup(&mut self, target: NodeId, kind: LookupKind) {
+        let id = self.next_lookup;
+        self.next_lookup += 1;
+
+        let candidates = self.table.closest(&target, K).iter().map(|n| (n.id, n.addr)).collect();
+
+        self.lookups.insert(id, Lookup {
+            target: target,
+            kind: kind,
+            candidates: candidates,
+            queried: HashSet::new(),
+            tokens: HashMap::new(),
+            found: HashSet::new(),
+            item: None,
+            in_flight: 0
+        });
+
+        //Until the table is healthy also ask the bootstrap nodes, whose ids we don't know yet
+        if self.table.len() < K {
+            for addr in self.bootstrap.clone() {
+                self.lookup_query(id, addr);
+            }
+        }
+
+        self.step(id);
+    }
+
+    fn lookup_query(&mut self, id: usize, addr: SocketAddr) {
+        let query = match self.lookups.get_mut(&id) {
+            Some(lookup) => {
+                lookup.queried.insert(addr);
+                lookup.in_flight += 1;
+
+                match lookup.kind {
+                    LookupKind::Bootstrap => Query::FindNode(lookup.target),
+                    LookupKind::GetPeers(_) | LookupKind::Announce(_, _) => Query::GetPeers(lookup.target),
+                    LookupKind::Get(_, _) | LookupKind::Put(_, _, _) => Query::Get { target: lookup.target, seq: None }
+                }
+            },
+            None => return
+        };
+
+        self.query(addr, query, Some(id), None);
+    }
+
+    /**
+     * Query the closest unqueried candidates, the lookup ends once the closest K known nodes
+     * have all answered or failed
+     */
+    fn step(&mut self, id: usize) {
+        let (next, done) = match self.lookups.get(&id) {
+            Some(lookup) => {
+                let next: Vec<SocketAddr> = lookup.candidates.iter().take(K)
+                    .map(|&(_, addr)| addr)
+                    .filter(|addr| !lookup.queried.contains(addr))
+                    .take(ALPHA.saturating_sub(lookup.in_flight))
+                    .collect();
+
+                (next.clone(), next.is_empty() && lookup.in_flight == 0)
+            },
+            None => return
+        };
+
+        for addr in next {
+            self.lookup_query(id, addr);
+        }
+
+        if done {
+            self.finish(id);
+        }
+    }
+
+    fn finish(&mut self, id: usize) {
+        let lookup = match self.lookups.remove(&id) {
+            Some(lookup) => lookup,
+            None => return
+        };
+
+        //Announces and puts go to the closest nodes that gave us a write token
+        let closest: Vec<(SocketAddr, Vec<u8>)> = lookup.candidates.iter().take(K)
+            .filter_map(|&(_, addr)| lookup.tokens.get(&addr).map(|token| (addr, token.clone())))
+            .collect();
+
+        match lookup.kind {
+            LookupKind::Announce(port, _) => {
+                for (addr, token) in closest {
+                    let query = Query::AnnouncePeer {
+                        info_hash: lookup.target,
+                        port: port,
+                        implied_port: false,
+                        token: token
+                    };
+
+                    self.query(addr, query, None, None);
+                }
+            },
+            LookupKind::Put(item, cas, send) => {
+                for (addr, token) in closest {
+                    let query = Query::Put {
+                        token: token,
+                        item: item.clone(),
+                        cas: cas
+                    };
+
+                    self.query(addr, query, None, Some(send.clone()));
+                }
+            },
+            LookupKind::Get(_, send) => {
+                if let Some(item) = lookup.item {
+                    send.send(item).ok();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    /** A query sent by a lookup was answered, failed or timed out **/
+    fn lookup_done(&mut self, id: usize, addr: &SocketAddr, response: Option<(NodeId, Response)>) {
+        let own = self.id;
+
+        if let Some(lookup) = self.lookups.get_mut(&id) {
+            lookup.in_flight -= 1;
+
+            match response {
+                Some((node, response)) => {
+                    if let Some(token) = response.token {
+                        lookup.tokens.insert(*addr, token);
+                    }
+
+                    //Bootstrap nodes are only known by address until they answer
+                    if !lookup.candidates.iter().any(|c| c.1 == *addr) {
+                        lookup.candidates.push((node, *addr));
+                    }
+
+                    for (node, node_addr) in response.nodes {
+                        if node != own && !lookup.candidates.iter().any(|c| c.1 == node_addr) {
+                            lookup.candidates.push((node, node_addr));
+                        }
+                    }
+
+                    let target = lookup.target;
+                    lookup.candidates.sort_by_key(|c| distance(&c.0, &target));
+
+                    let found = &mut lookup.found;
+                    let peers: Vec<PeerAddress> = response.values.into_iter().filter(|p| found.insert(p.clone())).collect();
+
+                    if !peers.is_empty() {
+                        match lookup.kind {
+                            LookupKind::GetPeers(ref send) | LookupKind::Announce(_, ref send) => { send.send(peers).ok(); },
+                            _ => {}
+                        }
+                    }
+
+                    if let (Some(mut item), &LookupKind::Get(ref salt, _)) = (response.item, &lookup.kind) {
+                        item.salt = salt.clone();
+
+                        let newer = lookup.item.as_ref().map(|best| item.seq > best.seq).unwrap_or(true);
+
+                        if newer && item.target() == target && item.verify().is_ok() {
+                            lookup.item = Some(item);
+                        }
+                    }
+                },
+                None => lookup.candidates.retain(|c| c.1 != *addr)
+            }
+        }
+
+        self.step(id);
+    }
+
+    fn valid_token(&self, ip: &IpAddr, given: &[u8]) -> bool {
+        token(&self.secret, ip) == given || token(&self.previous_secret, ip) == given
+    }
+
+    fn store_peer(&mut self, info_hash: NodeId, peer: PeerAddress) {
+        let peers = self.peers.entry(info_hash).or_insert(Vec::new());
+        peers.retain(|&(ref p, _)| *p != peer);
+
+        if peers.len() >= MAX_PEERS_PER_HASH {
+            peers.remove(0);
+        }
+
+        peers.push((peer, Instant::now()));
+    }
+
+    /** Store an item put by another node, checking it and the sequence number of any version we hold **/
+    fn store_item(&mut self, item: Item, cas: Option<i64>) -> PutResult {
+        item.verify()?;
+
+        let target = item.target();
+
+        if let Some(&(ref current, _)) = self.items.get(&target) {
+            if cas.map(|cas| cas != current.seq).unwrap_or(false) {
+                return Err((ERROR_CAS_MISMATCH, "CAS mismatch".to_string()));
+            }
+
+            //Republishing the current version is fine, changing it needs a higher sequence number
+            if item.seq < current.seq || (item.seq == current.seq && item.value != current.value) {
+                return Err((ERROR_SEQ_TOO_LOW, "Sequence number not greater than current".to_string()));
+            }
+        } else if self.items.len() >= MAX_ITEMS {
+            return Err((ERROR_GENERIC, "Storage full".to_string()));
+        }
+
+        self.items.insert(target, (item, Instant::now()));
+        Ok(())
+    }
+
+    fn handle_query(&mut self, from: SocketAddr, tid: Vec<u8>, node: NodeId, query: Query) {
+        let closest = |table: &RoutingTable, target: &NodeId| table.closest(target, K).iter().map(|n| (n.id, n.addr)).collect();
+
+        let body = match query {
+            Query::Ping => Body::Response(Response::default()),
+            Query::FindNode(target) => Body::Response(Response {
+                nodes: closest(&self.table, &target),
+                ..Response::default()
+            }),
+            Query::GetPeers(info_hash) => Body::Response(Response {
+                nodes: closest(&self.table, &info_hash),
+                values: self.peers.get(&info_hash).map(|p| p.iter().map(|&(ref p, _)| p.clone()).collect()).unwrap_or(Vec::new()),
+                token: Some(token(&self.secret, &from.ip())),
+                item: None
+            }),
+            Query::Get { target, seq } => Body::Response(Response {
+                nodes: closest(&self.table, &target),
+                token: Some(token(&self.secret, &from.ip())),
+                item: self.items.get(&target)
+                    .filter(|&&(ref item, _)| seq.map(|seq| item.seq > seq).unwrap_or(true))
+                    .map(|&(ref item, _)| item.clone()),
+                ..Response::default()
+            }),
+            Query::Put { token, item, cas } => {
+                if !self.valid_token(&from.ip(), &token) {
+                    Body::Error(ERROR_PROTOCOL, "Bad token".to_string())
+                } else {
+                    match self.store_item(item, cas) {
+                        Ok(()) => Body::Response(Response::default()),
+                        Err((code, message)) => Body::Error(code, message)
+                    }
+                }
+            },
+            Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if !self.valid_token(&from.ip(), &token) {
+                    Body::Error(ERROR_PROTOCOL, "Bad token".to_string())
+                } else if !implied_port && port == 0 {
+                    Body::Error(ERROR_PROTOCOL, "Bad port".to_string())
+                } else {
+                    let peer = PeerAddress {
+                        ip: from.ip(),
+                        port: if implied_port { from.port() } else { port }
+                    };
+
+                    self.store_peer(info_hash, peer);
+                    Body::Response(Response::default())
+                }
+            },
+            Query::Unknown(_) => Body::Error(ERROR_METHOD_UNKNOWN, "Method Unknown".to_string())
+        };
+
+        self.table.insert(node, from);
+
+        let msg = KrpcMessage {
+            tid: tid,
+            id: self.id,
+            body: body
+        };
+
+        self.send(from, msg);
+    }
+
+    /** Take the transaction a reply is for, replies from anywhere but the queried address are ignored **/
+    fn take_transaction(&mut self, tid: &[u8], from: &SocketAddr) -> Option<Transaction> {
+        if self.transactions.get(tid).map(|t| t.addr == *from).unwrap_or(false) {
+            self.transactions.remove(tid)
+        } else {
+            None
+        }
+    }
+
+    fn receive(&mut self, data: &[u8], from: SocketAddr) {
+        let msg = match KrpcMessage::decode(data) {
+            Ok(msg) => msg,
+            Err(e) => {
+                println!("DHT: Bad message from {} because {}", from, e);
+                return;
+            }
+        };
+
+        match msg.body {
+            Body::Query(query) => self.handle_query(from, msg.tid, msg.id, query),
+            Body::Response(response) => {
+                if let Some(transaction) = self.take_transaction(&msg.tid, &from) {
+                    self.table.insert(msg.id, from);
+
+                    if let Some(reply) = transaction.reply {
+                        reply.send(Ok(())).ok();
+                    }
+
+                    if let Some(lookup) = transaction.lookup {
+                        self.lookup_done(lookup, &from, Some((msg.id, response)));
+                    }
+                }
+            },
+            Body::Error(code, message) => {
+                if let Some(transaction) = self.take_transaction(&msg.tid, &from) {
+                    println!("DHT: {} replied with error {} {}", from, code, message);
+
+                    if let Some(reply) = transaction.reply {
+                        reply.send(Err((code, message))).ok();
+                    }
+
+                    if let Some(lookup) = transaction.lookup {
+                        self.lookup_done(lookup, &from, None);
+                    }
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = Duration::from_secs(QUERY_TIMEOUT_SECS);
+
+        let expired: Vec<Vec<u8>> = self.transactions.iter()
+            .filter(|&(_, t)| t.sent.elapsed() > timeout)
+            .map(|(tid, _)| tid.clone())
+            .collect();
+
+        for tid in expired {
+            let transaction = self.transactions.remove(&tid).unwrap();
+            self.table.failed(&transaction.addr);
+
+            if let Some(lookup) = transaction.lookup {
+                self.lookup_done(lookup, &transaction.addr, None);
+            }
+        }
+
+        let expiry = Duration::from_secs(PEER_EXPIRY_SECS);
+
+        for peers in self.peers.values_mut() {
+            peers.retain(|&(_, announced)| announced.elapsed() < expiry);
+        }
+
+        self.peers.retain(|_, peers| !peers.is_empty());
+
+        let expiry = Duration::from_secs(ITEM_EXPIRY_SECS);
+        self.items.retain(|_, &mut (_, stored)| stored.elapsed() < expiry);
+    }
+
+    fn maintain(&mut self) {
+        let bootstrapping = self.lookups.values().any(|l| match l.kind { LookupKind::Bootstrap => true, _ => false });
+
+        let due = match self.last_bootstrap {
+            Some(last) if self.table.len() < K => last.elapsed() > Duration::from_secs(BOOTSTRAP_RETRY_SECS),
+            Some(last) => last.elapsed() > Duration::from_secs(REFRESH_SECS),
+            None => true
+        };
+
+        if due && !bootstrapping {
+            //Looking ourselves up fills the buckets closest to us and announces us to our neighbours
+            self.last_bootstrap = Some(Instant::now());
+            let own = self.id;
+            self.start_lookup(own, LookupKind::Bootstrap);
+        }
+
+        if self.last_rotate.elapsed() > Duration::from_secs(SECRET_ROTATE_SECS) {
+            self.previous_secret = self.secret;
+            self.secret = random_id();
+            self.last_rotate = Instant::now();
+        }
+
+        if self.last_save.elapsed() > Duration::from_secs(SAVE_SECS) {
+            self.save();
+            self.last_save = Instant::now();
+        }
+    }
+
+    /** Start the lookups asked for, false once the node has been closed **/
+    fn requests(&mut self) -> bool {
+        while let Ok(request) = self.requests.try_recv() {
+            match request {
+                DhtRequest::GetPeers(info_hash, send) => self.start_lookup(info_hash, LookupKind::GetPeers(send)),
+                DhtRequest::Announce(info_hash, port, send) => self.start_lookup(info_hash, LookupKind::Announce(port, send)),
+                DhtRequest::Get(target, salt, send) => self.start_lookup(target, LookupKind::Get(salt, send)),
+                DhtRequest::Put(item, cas, send) => {
+                    let target = item.target();
+                    self.start_lookup(target, LookupKind::Put(item, cas, send));
+                },
+                DhtRequest::Close => {
+                    self.save();
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
+impl Handler for Node {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        let registered = ctx.register(SOURCE_SOCKET, &self.socket, Ready::readable())
+            .and_then(|_| ctx.register(SOURCE_REQUESTS, &self.requests, Ready::readable()));
+
+        if let Err(e) = registered {
+            println!("DHT: Stopped because {}", e);
+            return false;
+        }
+
+        //The first tick starts bootstrapping
+        ctx.set_timeout(Duration::from_millis(0));
+        true
+    }
+
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        match event {
+            Event::Ready(SOURCE_SOCKET, _) => {
+                let mut buffer = [0; 4096];
+
+                loop {
+                    match self.socket.recv_from(&mut buffer) {
+                        Ok((len, from)) => self.receive(&buffer[..len], from),
+                        Err(ref e) if e.kind() == WouldBlock => break,
+                        Err(e) => {
+                            //Errors such as an unreachable host are reported once, re-arming picks up any datagrams behind them
+                            println!("DHT socket error {}", e);
+                            ctx.reregister(SOURCE_SOCKET, &self.socket, Ready::readable()).ok();
+                            break;
+                        }
+                    }
+                }
+            },
+            Event::Ready(SOURCE_REQUESTS, _) => return self.requests(),
+            Event::Ready(..) => {},
+            Event::Timeout => {
+                self.expire();
+                self.maintain();
+                ctx.set_timeout(Duration::from_millis(TICK_MS));
+            }
+        }
+
+        true
+    }
+}
+
+/**
+ * Start a DHT node listening on port, bootstrapping from the given host:port addresses. With a
+ * state path the node id and routing table are kept across runs.
+ */
+pub fn dht(events: &EventLoop, port: u16, bootstrap: &[String], state_path: Option<String>) -> Result<Dht, String> {
+    let socket = net::UdpSocket::bind(("0.0.0.0", port)).map_err(|e| e.to_string())?;
+    let port = socket.local_addr().map_err(|e| e.to_string())?.port();
+    let socket = UdpSocket::from_socket(socket).map_err(|e| e.to_string())?;
+
+    let bootstrap = bootstrap.iter()
+        .filter_map(|host| host.to_socket_addrs().ok())
+        .flat_map(|addrs| addrs.filter(|a| a.is_ipv4()))
+        .collect();
+
+    let (id, nodes) = state_path.as_ref().and_then(|path| load_state(path)).unwrap_or((random_id(), Vec::new()));
+
+    let mut table = RoutingTable::new(id);
+
+    for (node, addr) in nodes {
+        table.insert(node, addr);
+    }
+
+    let (send, recv) = channel::channel();
+
+    events.spawn(Box::new(Node {
+        id: id,
+        socket: socket,
+        requests: recv,
+        table: table,
+        bootstrap: bootstrap,
+        state_path: state_path,
+        next_tid: 0,
+        transactions: HashMap::new(),
+        next_lookup: 0,
+        lookups: HashMap::new(),
+        secret: random_id(),
+        previous_secret: random_id(),
+        peers: HashMap::new(),
+        items: HashMap::new(),
+        last_bootstrap: None,
+        last_rotate: Instant::now(),
+        last_save: Instant::now()
+    }));
+
+    Ok(Dht {
+        send: send,
+        port: port
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use dht::{dht, Item, Dht, PutResult, ERROR_CAS_MISMATCH, ERROR_SEQ_TOO_LOW};
+    use event_loop::{event_loop, EventLoop};
+    use tracker::PeerAddress;
+    use std::thread;
+    use std::time::Duration;
+
+    fn first_node() -> (EventLoop, Dht) {
+        let events = event_loop(2).unwrap();
+        let first = dht(&events, 0, &[], None).unwrap();
+        (events, first)
+    }
+
+    /** A node bootstrapped from first **/
+    fn join(events: &EventLoop, first: &Dht) -> Dht {
+        dht(events, 0, &[format!("127.0.0.1:{}", first.port())], None).unwrap()
+    }
+
+    #[test]
+    fn announce_and_find_over_loopback() {
+        let (events, a) = first_node();
+        let b = join(&events, &a);
+        let c = join(&events, &a);
+        let info_hash = [7; 20];
+        let expected = PeerAddress { ip: "127.0.0.1".parse().unwrap(), port: 4000 };
+
+        //Lookups end when the receiver closes
+        for _ in b.announce(info_hash, 4000) {}
+
+        let mut found = false;
+
+        for _ in 0..20 {
+            found = c.get_peers(info_hash).iter().any(|peers| peers.contains(&expected));
+
+            if found {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        assert!(found);
+
+        a.close();
+        b.close();
+        c.close();
+    }
+
+    #[test]
+    fn put_and_get_items_over_loopback() {
+        //Until c joins, a is the only node b knows, so every put is checked against one store
+        let (events, a) = first_node();
+        let b = join(&events, &a);
+
+        let immutable = Item::immutable(b"12:Hello World!".to_vec()).unwrap();
+        assert_eq!(b.put(immutable.clone(), None).iter().collect::<Vec<_>>(), vec![Ok(())]);
+
+        let secret = [5; 32];
+        let key = ::dht::public_key(&secret);
+        let first = Item::mutable(&secret, b"dataset".to_vec(), 1, b"5:first".to_vec()).unwrap();
+        let second = Item::mutable(&secret, b"dataset".to_vec(), 2, b"6:second".to_vec()).unwrap();
+        let forked = Item::mutable(&secret, b"dataset".to_vec(), 2, b"6:forked".to_vec()).unwrap();
+
+        assert_eq!(b.put(first.clone(), None).iter().collect::<Vec<_>>(), vec![Ok(())]);
+
+        //A stale compare-and-swap, an older sequence number and a new value at the same one are all refused
+        let code = |results: Vec<PutResult>| results.into_iter().map(|r| r.err().map(|e| e.0)).collect::<Vec<_>>();
+        assert_eq!(code(b.put(second.clone(), Some(0)).iter().collect()), vec![Some(ERROR_CAS_MISMATCH)]);
+        assert_eq!(code(b.put(second.clone(), Some(1)).iter().collect()), vec![None]);
+        assert_eq!(code(b.put(first, None).iter().collect()), vec![Some(ERROR_SEQ_TOO_LOW)]);
+        assert_eq!(code(b.put(forked, None).iter().collect()), vec![Some(ERROR_SEQ_TOO_LOW)]);
+        assert_eq!(code(b.put(second.clone(), None).iter().collect()), vec![None]);
+
+        let c = join(&events, &a);
+        assert_eq!(c.get(immutable.target()).recv().ok(), Some(immutable));
+        assert_eq!(c.get_mutable(&key, b"dataset").recv().ok(), Some(second));
+        assert!(c.get_mutable(&key, b"other").recv().is_err());
+
+        a.close();
+        b.close();
+        c.close();
+    }
+}
@@ -0,0 +1,188 @@
+/**
+ * Kademlia routing table. Nodes are bucketed by how many leading bits their id shares with ours,
+ * so we know many nodes close to us and a few from each more distant part of the id space.
+ */
+
+use std::net::SocketAddr;
+use std::time::Instant;
+use rand::{thread_rng, Rng};
+
+pub const ID_LEN: usize = 20;
+
+/** Nodes kept per bucket **/
+pub const K: usize = 8;
+
+/** Unanswered queries before a node is dropped **/
+const MAX_FAILURES: usize = 2;
+
+pub type NodeId = [u8; ID_LEN];
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0; ID_LEN];
+    for i in 0..ID_LEN {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+pub fn random_id() -> NodeId {
+    let mut id = [0; ID_LEN];
+    thread_rng().fill_bytes(&mut id);
+    id
+}
+
+pub fn id_from_slice(data: &[u8]) -> Option<NodeId> {
+    if data.len() != ID_LEN {
+        return None;
+    }
+
+    let mut id = [0; ID_LEN];
+    id.copy_from_slice(data);
+    Some(id)
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    pub failures: usize
+}
+
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Node>>
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> RoutingTable {
+        RoutingTable {
+            own: own,
+            buckets: vec![Vec::new(); ID_LEN * 8]
+        }
+    }
+
+    /** Bucket for an id, the number of leading bits it shares with ours **/
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        let d = distance(&self.own, id);
+        let shared = d.iter().position(|&b| b != 0)
+            .map(|byte| byte * 8 + d[byte].leading_zeros() as usize)
+            .unwrap_or(ID_LEN * 8);
+
+        shared.min(ID_LEN * 8 - 1)
+    }
+
+    /**
+     * Record a node we have heard from. A full bucket only takes it in place of a node that
+     * has stopped answering, long lived nodes are the most likely to stay.
+     */
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddr) -> bool {
+        if id == self.own {
+            return false;
+        }
+
+        let idx = self.bucket_index(&id);
+        let bucket = &mut self.buckets[idx];
+
+        if let Some(node) = bucket.iter_mut().find(|n| n.id == id) {
+            node.addr = addr;
+            node.last_seen = Instant::now();
+            node.failures = 0;
+            return true;
+        }
+
+        let node = Node {
+            id: id,
+            addr: addr,
+            last_seen: Instant::now(),
+            failures: 0
+        };
+
+        if bucket.len() < K {
+            bucket.push(node);
+            return true;
+        }
+
+        let worst = (0..bucket.len()).max_by_key(|&i| bucket[i].failures).unwrap();
+
+        if bucket[worst].failures > 0 {
+            bucket[worst] = node;
+            true
+        } else {
+            false
+        }
+    }
+
+    /** A query to addr went unanswered **/
+    pub fn failed(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            for node in bucket.iter_mut().filter(|n| n.addr == *addr) {
+                node.failures += 1;
+            }
+
+            bucket.retain(|n| n.failures <= MAX_FAILURES);
+        }
+    }
+
+    /** Up to count known nodes closest to target **/
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flat_map(|b| b.iter().cloned()).collect();
+        nodes.sort_by_key(|n| distance(&n.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> Vec<Node> {
+        self.buckets.iter().flat_map(|b| b.iter().cloned()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dht::routing::{RoutingTable, NodeId, K};
+    use std::net::SocketAddr;
+
+    fn id(first: u8, last: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        id
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn buckets_fill_and_replace_failing_nodes() {
+        let mut table = RoutingTable::new(id(0, 0));
+
+        //Everything with the top bit set shares no prefix with us and lands in one bucket
+        for i in 0..K as u8 {
+            assert!(table.insert(id(0x80, i), addr(i as u16 + 1)));
+        }
+
+        assert!(!table.insert(id(0x80, 100), addr(100)));
+        assert!(table.insert(id(0x01, 0), addr(200)));
+        assert!(!table.insert(id(0, 0), addr(300)));
+
+        table.failed(&addr(1));
+        assert!(table.insert(id(0x80, 100), addr(100)));
+        assert_eq!(table.len(), K + 1);
+    }
+
+    #[test]
+    fn closest_by_xor_distance() {
+        let mut table = RoutingTable::new(id(0, 0));
+        table.insert(id(0x80, 1), addr(1));
+        table.insert(id(0x40, 1), addr(2));
+        table.insert(id(0x41, 0), addr(3));
+
+        let closest: Vec<SocketAddr> = table.closest(&id(0x41, 1), 2).iter().map(|n| n.addr).collect();
+        assert_eq!(closest, vec![addr(3), addr(2)]);
+    }
+}
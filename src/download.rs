@@ -1,85 +1,297 @@
 use torrent::{Info, from_file, prepare};
-use tracker::{TrackerState, PeerAddress, connect};
+use tracker::{PeerAddress, connect};
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
-use std::time::Duration;
-use std::thread;
-use peer_client::{peer_client, ClientState};
-use torrent_data::TorrentData;
+use std::time::{Duration, Instant};
+use std::ops::Range;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::io;
+use mio::Ready;
+use mio_extras::channel;
+use peer_client::{peer_client, peer_client_incoming, ClientState, Reporter};
+use peer_server::{PeerServer, Incoming};
+use event_loop::{EventLoop, Handler, Context, Event};
+use settings::Settings;
+use torrent_data::{TorrentData, DiskRequest, DiskResult, disk_thread};
+use picker::{Picker, PickStrategy, PeerKey, CompletedPiece};
 use bitfield::Bitfield;
+use rate::Throughput;
+use choker::{Choker, Candidate};
+use trust::Trust;
+use pex::{PexMessage, PEX_NAME, MAX_PEX_PEERS, MIN_PEX_INTERVAL_SECS, FLAG_SEED, FLAG_REACHABLE};
+use peer_source::{PeerSource, TrackerSource, DhtSource, LsdSource, pex_source};
+use dht::{Dht, id_from_slice};
+use lsd::Lsd;
 
 pub enum DownloadState {
-    Close
+    Close,
+    Strategy(PickStrategy),
+    Deadline(Range<usize>, Duration), /* Pieces needed within a duration from now */
+    ClearDeadlines
 }
 
 struct Peer {
+    key: PeerKey,
     id: PeerAddress,
-    locked: Option<usize>,
-    channel: (Sender<ClientState>, Receiver<ClientState>)
+    outgoing: bool, /* We connected to it, so id is the address it listens on */
+    listen_port: Option<u16>,
+    peer_id: Option<Vec<u8>>,
+    has: Bitfield,
+    interested: bool,
+    choked: bool,
+    snubbed: bool,
+    last_active: Instant, /* Last block sent either way */
+    uploaded: usize,
+    download_rate: Throughput,
+    upload_rate: Throughput,
+    pex_sent: HashSet<PeerAddress>, /* Peers we have told it about */
+    last_pex: Option<Instant>, /* When it last told us about peers */
+    suggested: Vec<usize>, /* Pieces it suggested we download */
+    send: channel::Sender<ClientState>
 }
 
-fn remaining(torrent_data: &TorrentData) -> usize {
-    (0..torrent_data.pieces.len()).filter(|&x| !torrent_data.have.get(x)).count()
+impl Peer {
+    fn new(key: PeerKey, id: PeerAddress, outgoing: bool, num_pieces: usize, send: channel::Sender<ClientState>) -> Peer {
+        Peer {
+            key: key,
+            id: id,
+            outgoing: outgoing,
+            listen_port: None,
+            peer_id: None,
+            has: Bitfield::new(num_pieces),
+            interested: false,
+            choked: true,
+            snubbed: false,
+            last_active: Instant::now(),
+            uploaded: 0,
+            download_rate: Throughput::new(),
+            upload_rate: Throughput::new(),
+            pex_sent: HashSet::new(),
+            last_pex: None,
+            suggested: Vec::new(),
+            send: send
+        }
+    }
+
+    /** Where other peers could reach it, if known **/
+    fn listen_address(&self) -> Option<PeerAddress> {
+        if self.outgoing {
+            Some(self.id.clone())
+        } else {
+            self.listen_port.map(|port| PeerAddress {
+                ip: self.id.ip,
+                port: port
+            })
+        }
+    }
 }
 
 const MAX_PEERS: usize = 50;
 
+/** Suggestions remembered from each peer **/
+const MAX_SUGGESTED: usize = 16;
+
+/** Peers queued for connection, further discoveries are dropped until the queue drains **/
+const MAX_CANDIDATES: usize = 500;
+
+/** How often sources, candidates, the picker and the choker are looked at **/
+const TICK_MS: u64 = 1000;
+
+/** Sources the download registers with the event loop **/
+const SOURCE_CONTROL: usize = 0;
+const SOURCE_PEERS: usize = 1;
+const SOURCE_INCOMING: usize = 2;
+const SOURCE_DISK: usize = 3;
+
 struct Download {
 
     send: Sender<DownloadState>,
-    recv: Receiver<DownloadState>,
+    recv: channel::Receiver<DownloadState>,
 
-    tracker: (Sender<TrackerState>, Receiver<TrackerState>),
+    events: EventLoop,
+    peer_events: channel::Receiver<(PeerKey, ClientState)>, /* Every peer reports here, tagged with its key */
+    report: channel::Sender<(PeerKey, ClientState)>,
 
-    info: Info,
-    data: TorrentData,
+    sources: Vec<Box<PeerSource>>,
+    pex: Sender<Vec<PeerAddress>>, /* Feeds peers learned through PEX to their source */
+
+    server: PeerServer,
+    incoming: channel::Receiver<Incoming>,
 
+    info: Info,
+    disk: Sender<DiskRequest>, /* Hashing and disk I/O happen off the event loop */
+    disk_results: channel::Receiver<DiskResult>,
+    have: Bitfield, /* Pieces written and verified */
+    claimed: Bitfield, /* Pieces we have or are verifying, so the picker leaves them alone */
+    verifying: HashMap<usize, Vec<IpAddr>>, /* Sender of each block of pieces the disk thread is checking */
+    settings: Settings,
+    picker: Picker,
+    choker: Choker,
+    trust: Trust,
+
+    uploaded: usize,
+    redundant: usize, /* Bytes downloaded more than once, mostly from endgame duplicates */
+    endgame: bool,
+
+    candidates: Vec<PeerAddress>, /* Peers we have heard of but not yet tried */
+    tried: HashMap<IpAddr, Instant>, /* Last connection attempt to each address */
+    next_connect: Instant,
+    next_pex: Instant,
+
+    next_key: PeerKey,
+    addresses: HashMap<PeerKey, IpAddr>, /* Connected peers, and departed ones whose blocks are still unverified */
     active_clients: Vec<Peer>
 }
 
 impl Download {
-    
+    fn new(info: Info, events: &EventLoop, server: &PeerServer, mut sources: Vec<Box<PeerSource>>, disk: (Sender<DiskRequest>, channel::Receiver<DiskResult>), settings: &Settings, control: (Sender<DownloadState>, channel::Receiver<DownloadState>)) -> Download {
+        let incoming = server.register(&info.info_hash);
+        let (report, peer_events) = channel::channel();
+        let (pex, pex_source) = pex_source();
+        sources.push(Box::new(pex_source));
+
+        Download {
+            send: control.0,
+            recv: control.1,
+            events: events.clone(),
+            peer_events: peer_events,
+            report: report,
+            sources: sources,
+            pex: pex,
+            server: server.clone(),
+            incoming: incoming,
+            disk: disk.0,
+            disk_results: disk.1,
+            have: Bitfield::new(info.pieces.len()),
+            claimed: Bitfield::new(info.pieces.len()),
+            verifying: HashMap::new(),
+            picker: Picker::new(info.piece_length, info.length(), info.pieces.len(), settings.pick_strategy),
+            trust: Trust::new(settings.ban_threshold, settings.smart_ban),
+            choker: Choker::new(settings.unchoke_slots, settings.optimistic_unchoke_slots, settings.choke_interval, settings.optimistic_unchoke_interval),
+            info: info,
+            settings: settings.clone(),
+            uploaded: 0,
+            redundant: 0,
+            endgame: false,
+            candidates: Vec::new(),
+            tried: HashMap::new(),
+            next_connect: Instant::now(),
+            next_pex: Instant::now(),
+            next_key: 0,
+            addresses: HashMap::new(),
+            active_clients: Vec::new()
+        }
+    }
+
     pub fn shutdown(&mut self, reason: &str) {
         println!("TODO: Shut Down because {}", reason);
+        self.server.unregister(&self.info.info_hash);
     }
 
     pub fn sync_ctrl(&mut self) {
         //Check if a control signal has been sent
-        let ctrl_data = self.recv.try_recv();
-            
-        if let Ok(DownloadState::Close) = ctrl_data {
-            self.shutdown("Requested");
+        while let Ok(ctrl_data) = self.recv.try_recv() {
+            match ctrl_data {
+                DownloadState::Close => self.shutdown("Requested"),
+                DownloadState::Strategy(strategy) => self.picker.set_strategy(strategy),
+                DownloadState::Deadline(pieces, due) => self.picker.set_deadline(pieces, Instant::now() + due),
+                DownloadState::ClearDeadlines => self.picker.clear_deadlines()
+            }
         }
     }
 
-    pub fn sync_tracker(&mut self) {
+    pub fn sync_sources(&mut self) {
+        //Gather newly found peers from the trackers, DHT and PEX, dropping any source that has stopped
+        let mut found = Vec::new();
+        let mut id = 0;
+
+        while id < self.sources.len() {
+            match self.sources[id].poll() {
+                Ok(peers) => {
+                    found.extend(peers);
+                    id += 1;
+                },
+                Err(reason) => {
+                    println!("Peer source {} stopped because {}", self.sources[id].name(), reason);
+                    self.sources.remove(id);
+                }
+            }
+        }
 
-        //Update tracker information
-        let tracker_data = self.tracker.1.try_recv();
+        if !found.is_empty() {
+            self.add_candidates(found);
+        }
+    }
 
-        match tracker_data {
-            Ok(TrackerState::Close(v)) => {
-                self.shutdown(&format!("Tracker Closed because {}", v));
-            },
-            Ok(TrackerState::Connected(cid)) => {
-                println!("Connected to the tracker with connection id {}", cid);
-            },
-            Ok(TrackerState::Announced(peers)) => {
-                //println!("Acquired peers {:?}", peers);
-                for peer in &peers {
-                    let mut active_peers = &mut self.active_clients;
-                    let can_add = active_peers.len() < MAX_PEERS;
-                    let already_have = active_peers.iter().any(|x| peer.ip == x.id.ip);
-                    if can_add && !already_have {
-                        active_peers.push(Peer {
-                            id: peer.clone(),
-                            locked: None,
-                            channel: peer_client(&self.info, peer)
-                        });
-                    }
+    /** Queue peers to connect to, skipping ones we are connected to, already queued or banned **/
+    fn add_candidates(&mut self, peers: Vec<PeerAddress>) {
+        for peer in peers {
+            let connected = self.active_clients.iter().any(|x| peer.ip == x.id.ip);
+            let queued = self.candidates.iter().any(|x| peer.ip == x.ip);
+
+            if !connected && !queued && !self.trust.is_banned(&peer.ip) && self.candidates.len() < MAX_CANDIDATES {
+                self.candidates.push(peer);
+            }
+        }
+    }
+
+    pub fn sync_candidates(&mut self) {
+        //Connect out to a limited number of queued peers a second
+        if Instant::now() < self.next_connect {
+            return;
+        }
+
+        self.next_connect = Instant::now() + Duration::from_secs(1);
+
+        let reconnect_interval = self.settings.reconnect_interval;
+        self.tried.retain(|_, t| t.elapsed() < reconnect_interval);
+
+        let mut attempts = 0;
+
+        while attempts < self.settings.connections_per_second && self.active_clients.len() < MAX_PEERS && !self.candidates.is_empty() {
+            let peer = self.candidates.remove(0);
+
+            let connected = self.active_clients.iter().any(|x| peer.ip == x.id.ip);
+            let recent = self.tried.contains_key(&peer.ip);
+
+            if connected || recent || self.trust.is_banned(&peer.ip) {
+                continue;
+            }
+
+            let slot = match self.server.acquire() {
+                Some(slot) => slot,
+                None => {
+                    self.candidates.insert(0, peer);
+                    break;
                 }
-            },
-            Err(_) => {}
+            };
+
+            attempts += 1;
+            self.tried.insert(peer.ip, Instant::now());
+
+            self.next_key += 1;
+            let send = peer_client(&self.events, &self.info, &self.settings, &self.server, &peer, slot, Reporter::new(self.next_key, self.report.clone()));
+            self.addresses.insert(self.next_key, peer.ip);
+            self.active_clients.push(Peer::new(self.next_key, peer, true, self.info.pieces.len(), send));
+        }
+    }
+
+    pub fn sync_incoming(&mut self) {
+        //Take over connections accepted by the peer server for this torrent
+        while let Ok(incoming) = self.incoming.try_recv() {
+            let can_add = self.active_clients.len() < MAX_PEERS;
+            let already_have = self.active_clients.iter().any(|x| incoming.addr.ip == x.id.ip);
+
+            if can_add && !already_have && !self.trust.is_banned(&incoming.addr.ip) {
+                let id = incoming.addr.clone();
+                self.next_key += 1;
+                let send = peer_client_incoming(&self.events, &self.info, &self.settings, &self.server, incoming, Reporter::new(self.next_key, self.report.clone()));
+                self.addresses.insert(self.next_key, id.ip);
+                self.active_clients.push(Peer::new(self.next_key, id, false, self.info.pieces.len(), send));
+            } else {
+                println!("Refusing incoming peer {:?}", incoming.addr);
+            }
         }
     }
 
@@ -90,46 +302,113 @@ impl Download {
     }
 
     fn s_client(&mut self, id: usize, msg: ClientState, to_remove: &mut Vec<usize>) {
-        if self.active_clients[id].channel.0.send(msg).is_err() {
+        if self.active_clients[id].send.send(msg).is_err() {
             Download::flag_remove(id, to_remove);
         }
     }
 
-    fn r_client(&mut self, id: usize) -> Result<ClientState, mpsc::TryRecvError> {
-        self.active_clients[id].channel.1.try_recv()
-    }
-
-    /** Find a piece we don't have and they do have **/
-    fn find_needed_piece(&self, field: &Bitfield) -> Option<usize> {
-        (0..self.data.pieces.len())
-        .find(|&x| {
-            //println!("Find {}", x);
-            let i_have = self.data.have.get(x);
-            let they_have = field.get(x);
-            let is_unlocked = remaining(&self.data) < MAX_PEERS || !self.active_clients.iter().any(|cl| cl.locked == Some(x));
-            !i_have && they_have && is_unlocked
-        })
-    }
-
     fn process_client_msg(&mut self, id: usize, msg: ClientState, to_remove: &mut Vec<usize>) {
         match msg {
             ClientState::Close(reason) => {
                 println!("Flagging {:?} for close due to {}", self.active_clients[id].id, reason);
                 Download::flag_remove(id, to_remove);
             },
-            ClientState::Need(field) => { 
-                let target = self.find_needed_piece(&field); 
+            ClientState::Connected(peer_id) => {
+                let duplicate = self.active_clients.iter().any(|cl| cl.peer_id.as_ref() == Some(&peer_id));
 
-                if let Some(i) = target {
-                    self.active_clients[id].locked = Some(i);
-                    self.s_client(id, ClientState::Want(i), to_remove);
+                if duplicate {
+                    self.s_client(id, ClientState::Close("Duplicate peer id".to_string()), to_remove);
                 } else {
-                    self.s_client(id, ClientState::Close("Nothing of interest".to_string()), to_remove);
+                    self.active_clients[id].peer_id = Some(peer_id);
+                    let have = self.have.clone();
+                    self.s_client(id, ClientState::Advertise(have), to_remove);
                 }
             },
-            ClientState::Commit(piece, data) => {
-                self.data.write(piece, &data).unwrap();
-                self.update_data_state();
+            ClientState::Interested(interested) => {
+                //Slots are handed out by the choker on its next round
+                self.active_clients[id].interested = interested;
+            },
+            ClientState::Read(piece, begin, length) => {
+                let key = self.active_clients[id].key;
+                self.disk.send(DiskRequest::Read(key, piece, begin, length)).ok();
+            },
+            ClientState::Uploaded(length) => {
+                self.active_clients[id].uploaded += length;
+                self.active_clients[id].upload_rate.add(length);
+                self.active_clients[id].last_active = Instant::now();
+                self.uploaded += length;
+            },
+            ClientState::Available(field) => {
+                self.picker.remove_availability(&self.active_clients[id].has);
+                self.picker.add_availability(&field);
+                self.active_clients[id].has = field;
+            },
+            ClientState::Acquired(piece) => {
+                //Repeated haves must not count the peer twice
+                let has = &mut self.active_clients[id].has;
+
+                if !has.get(piece) && has.set(piece).is_ok() {
+                    self.picker.peer_has(piece);
+                }
+            },
+            ClientState::Need(count) => { 
+                let has = self.active_clients[id].has.clone();
+                self.assign_blocks(id, &has, count, to_remove);
+            },
+            ClientState::NeedAllowed(count, allowed) => {
+                let has = self.active_clients[id].has.and(&allowed);
+                self.assign_blocks(id, &has, count, to_remove);
+            },
+            ClientState::Suggested(piece) => {
+                let suggested = &mut self.active_clients[id].suggested;
+
+                if !suggested.contains(&piece) && suggested.len() < MAX_SUGGESTED {
+                    suggested.push(piece);
+                }
+            },
+            ClientState::Received(block, data) => {
+                let key = self.active_clients[id].key;
+                self.active_clients[id].download_rate.add(data.len());
+                self.active_clients[id].last_active = Instant::now();
+
+                if !self.picker.is_needed(block) {
+                    self.redundant += data.len();
+                    return;
+                }
+
+                //Withdraw duplicate requests for the block from other peers
+                for other in self.picker.requesters(key, block) {
+                    if let Some(other_id) = self.active_clients.iter().position(|p| p.key == other) {
+                        self.s_client(other_id, ClientState::Cancel(block), to_remove);
+                    }
+                }
+
+                if let Some(complete) = self.picker.received(key, block, &data) {
+                    self.verify_piece(complete);
+                }
+            },
+            ClientState::Snubbed(snubbed) => {
+                self.active_clients[id].snubbed = snubbed;
+            },
+            ClientState::ListenPort(port) => {
+                self.active_clients[id].listen_port = Some(port);
+            },
+            ClientState::Discovered(peers) => {
+                let too_soon = self.active_clients[id].last_pex.map_or(false, |at| at.elapsed() < Duration::from_secs(MIN_PEX_INTERVAL_SECS));
+
+                if too_soon {
+                    println!("Dropping early peer exchange from {:?}", self.active_clients[id].id);
+                } else if !self.info.private {
+                    self.active_clients[id].last_pex = Some(Instant::now());
+                    self.pex.send(peers).ok();
+                }
+            },
+            ClientState::Redundant(length) => {
+                self.redundant += length;
+            },
+            ClientState::Abandon(blocks) => {
+                let key = self.active_clients[id].key;
+                blocks.into_iter().for_each(|block| self.picker.cancel(key, block));
             },
             _ => {
                 println!("Unexpected message from {:?}", self.active_clients[id].id);
@@ -138,78 +417,471 @@ impl Download {
         } 
     }
 
+    /** Pick up to count blocks from the pieces in has for a peer, trying its suggestions first **/
+    fn assign_blocks(&mut self, id: usize, has: &Bitfield, count: usize, to_remove: &mut Vec<usize>) {
+        let key = self.active_clients[id].key;
+
+        //Only our fastest peers may double up on pieces that are nearly due
+        let urgent_before = if self.is_fast(id) {
+            Some(Instant::now() + self.settings.deadline_window)
+        } else {
+            None
+        };
+
+        let mut suggested = Bitfield::new(has.len());
+
+        {
+            let have = &self.claimed;
+            let peer = &mut self.active_clients[id];
+            peer.suggested.retain(|&piece| !have.get(piece));
+            peer.suggested.iter().for_each(|&piece| { suggested.set(piece).ok(); });
+        }
+
+        let mut blocks = self.picker.pick(key, &has.and(&suggested), &self.claimed, count, urgent_before);
+
+        if blocks.len() < count {
+            let rest = self.picker.pick(key, has, &self.claimed, count - blocks.len(), urgent_before);
+            blocks.extend(rest);
+        }
+
+        self.s_client(id, ClientState::Want(blocks), to_remove);
+
+        let endgame = self.picker.in_endgame(&self.claimed);
+
+        if endgame != self.endgame {
+            println!("{} endgame", if endgame { "Entering" } else { "Leaving" });
+            self.endgame = endgame;
+        }
+    }
+
+    /** True if a peer is delivering and among the deadline_peers fastest **/
+    fn is_fast(&mut self, id: usize) -> bool {
+        let rate = self.active_clients[id].download_rate.rate();
+
+        if rate <= 0.0 {
+            return false;
+        }
+
+        let faster = self.active_clients.iter_mut().map(|p| p.download_rate.rate()).filter(|&r| r > rate).count();
+        faster < self.settings.deadline_peers
+    }
+
+    /** Hand a completed piece to the disk thread, remembering who sent it until the answer comes back **/
+    fn verify_piece(&mut self, complete: CompletedPiece) {
+        let piece = complete.piece;
+
+        let contributors: Vec<IpAddr> = complete.contributors.iter().map(|key| self.addresses[key]).collect();
+        self.verifying.insert(piece, contributors);
+        self.claimed.set(piece).ok();
+        self.disk.send(DiskRequest::Write(piece, complete.data)).ok();
+    }
+
+    /** Settle the trust of everyone who sent us part of a piece the disk thread has checked **/
+    fn piece_written(&mut self, piece: usize, data: Vec<u8>, written: io::Result<()>, to_remove: &mut Vec<usize>) {
+        let contributors = self.verifying.remove(&piece).unwrap_or(Vec::new());
+
+        let banned = match written {
+            Ok(()) => {
+                self.have.set(piece).ok();
+                self.update_data_state();
+                self.broadcast_have(piece, to_remove);
+                self.trust.piece_passed(piece, &data, &contributors)
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                println!("Piece {} failed hash check", piece);
+                self.claimed.clear(piece).ok();
+                self.trust.piece_failed(piece, &data, &contributors)
+            },
+            Err(e) => {
+                println!("Discarding piece {}: {}", piece, e);
+                self.claimed.clear(piece).ok();
+                Vec::new()
+            }
+        };
+
+        for ip in banned {
+            println!("Banning {} for sending corrupt data", ip);
+
+            for id in 0..self.active_clients.len() {
+                if self.active_clients[id].id.ip == ip {
+                    self.s_client(id, ClientState::Close("Banned".to_string()), to_remove);
+                }
+            }
+        }
+    }
+
+    /** Tell every connected peer about a piece we just verified **/
+    fn broadcast_have(&mut self, piece: usize, to_remove: &mut Vec<usize>) {
+        for id in 0..self.active_clients.len() {
+            //Peers that haven't finished their handshake will get the piece in their advertisement
+            if self.active_clients[id].peer_id.is_some() {
+                self.s_client(id, ClientState::Have(piece), to_remove);
+            }
+        }
+    }
+
+    pub fn sync_disk(&mut self) {
+        //Answers from the disk thread, blocks for peers that have since gone are dropped
+        let mut closed = Vec::new();
+
+        while let Ok(result) = self.disk_results.try_recv() {
+            match result {
+                DiskResult::Written(piece, data, written) => self.piece_written(piece, data, written, &mut closed),
+                DiskResult::Read(key, piece, begin, block) => {
+                    if let Some(id) = self.active_clients.iter().position(|p| p.key == key) {
+                        match block {
+                            Ok(block) => self.s_client(id, ClientState::Block(piece, begin, block), &mut closed),
+                            Err(e) => self.s_client(id, ClientState::Close(e.to_string()), &mut closed)
+                        }
+                    }
+                }
+            }
+        }
+
+        self.remove_closed(closed);
+    }
+
     pub fn sync_clients(&mut self) {
         //Update peer-wire client info
         let mut closed = Vec::new();
 
-        //Read all signals from clients & process
-        for client_num in 0..self.active_clients.len() {
-            while let Ok(signal) = self.r_client(client_num) {
+        //Read all signals from clients & process, peers already removed may still have some queued
+        while let Ok((key, signal)) = self.peer_events.try_recv() {
+            if let Some(client_num) = self.active_clients.iter().position(|p| p.key == key) {
                 self.process_client_msg(client_num, signal, &mut closed);
             }
         }
 
+        self.remove_closed(closed);
+    }
+
+    /** Drop peers flagged for removal, returning their blocks and availability to the picker **/
+    fn remove_closed(&mut self, mut closed: Vec<usize>) {
         //Back-to-front remove each index in vector (Preserves removal-index)
+        closed.sort();
         closed.iter().rev().for_each(|&i| {
-            self.active_clients.remove(i);
+            let peer = self.active_clients.remove(i);
+            self.picker.peer_gone(peer.key);
+            self.picker.remove_availability(&peer.has);
         });
+
+        //Keep the address of departed peers only while a piece they sent part of is unverified
+        let clients = &self.active_clients;
+        let picker = &self.picker;
+        self.addresses.retain(|&key, _| clients.iter().any(|p| p.key == key) || picker.has_blocks_from(key));
+    }
+
+    pub fn sync_idle(&mut self) {
+        //Peers we haven't traded with in a while give their connection to someone new
+        let mut closed = Vec::new();
+
+        for id in 0..self.active_clients.len() {
+            if self.active_clients[id].last_active.elapsed() >= self.settings.idle_timeout {
+                self.s_client(id, ClientState::Close("Idle".to_string()), &mut closed);
+            }
+        }
+
+        self.remove_closed(closed);
+    }
+
+    pub fn sync_pex(&mut self) {
+        //Tell each peer which of our peers it hasn't heard about from us, and which have gone
+        if self.info.private || Instant::now() < self.next_pex {
+            return;
+        }
+
+        self.next_pex = Instant::now() + self.settings.pex_interval;
+
+        let current: Vec<(PeerAddress, u8)> = self.active_clients.iter()
+            .filter(|p| p.peer_id.is_some())
+            .filter_map(|p| p.listen_address().map(|addr| {
+                let seed = if p.has.is_complete() { FLAG_SEED } else { 0 };
+                let reachable = if p.outgoing { FLAG_REACHABLE } else { 0 };
+                (addr, seed | reachable)
+            }))
+            .collect();
+
+        let mut closed = Vec::new();
+
+        for id in 0..self.active_clients.len() {
+            if self.active_clients[id].peer_id.is_none() {
+                continue;
+            }
+
+            let own = self.active_clients[id].listen_address();
+
+            let msg = {
+                let sent = &self.active_clients[id].pex_sent;
+
+                PexMessage {
+                    added: current.iter()
+                        .filter(|&&(ref addr, _)| !sent.contains(addr) && Some(addr) != own.as_ref())
+                        .take(MAX_PEX_PEERS)
+                        .cloned()
+                        .collect(),
+                    dropped: sent.iter()
+                        .filter(|addr| !current.iter().any(|&(ref c, _)| c == *addr))
+                        .take(MAX_PEX_PEERS)
+                        .cloned()
+                        .collect()
+                }
+            };
+
+            if msg.added.is_empty() && msg.dropped.is_empty() {
+                continue;
+            }
+
+            {
+                let sent = &mut self.active_clients[id].pex_sent;
+                msg.added.iter().for_each(|&(ref addr, _)| { sent.insert(addr.clone()); });
+                msg.dropped.iter().for_each(|addr| { sent.remove(addr); });
+            }
+
+            self.s_client(id, ClientState::Extended(PEX_NAME, msg.encode()), &mut closed);
+        }
+
+        self.remove_closed(closed);
+    }
+
+    pub fn sync_choker(&mut self) {
+        //Reward the peers that give us the most, or that take the most once we have nothing left to get
+        let seeding = self.have.is_complete();
+
+        let candidates: Vec<Candidate> = self.active_clients.iter_mut()
+            .filter(|p| p.peer_id.is_some())
+            .map(|p| Candidate {
+                key: p.key,
+                interested: p.interested,
+                snubbed: p.snubbed,
+                rate: if seeding { p.upload_rate.rate() } else { p.download_rate.rate() }
+            })
+            .collect();
+
+        let unchoked = match self.choker.run(Instant::now(), &candidates) {
+            Some(unchoked) => unchoked,
+            None => return
+        };
+
+        let mut closed = Vec::new();
+
+        for id in 0..self.active_clients.len() {
+            if self.active_clients[id].peer_id.is_none() {
+                continue;
+            }
+
+            let choke = !unchoked.contains(&self.active_clients[id].key);
+
+            if choke != self.active_clients[id].choked {
+                self.active_clients[id].choked = choke;
+                let decision = if choke { ClientState::Choke } else { ClientState::Unchoke };
+                self.s_client(id, decision, &mut closed);
+            }
+        }
+
+        self.remove_closed(closed);
     }
 
     fn update_data_state(&mut self) {
-        let total_pieces = self.data.pieces.len();
-        let remaining_pieces = remaining(&self.data);
-        let piece_length = self.data.piece_size;
+        let total_pieces = self.have.len();
+        let remaining_pieces = self.have.count_zeros();
+        let piece_length = self.info.piece_length;
+
+        println!("{}MB / {}MB ({}KB redundant)", ((total_pieces - remaining_pieces) * piece_length) / 1024 / 1024, (total_pieces * piece_length) / 1024 / 1024, self.redundant / 1024);
+    }
+}
+
+impl Handler for Download {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        let registered = ctx.register(SOURCE_CONTROL, &self.recv, Ready::readable())
+            .and_then(|_| ctx.register(SOURCE_PEERS, &self.peer_events, Ready::readable()))
+            .and_then(|_| ctx.register(SOURCE_INCOMING, &self.incoming, Ready::readable()))
+            .and_then(|_| ctx.register(SOURCE_DISK, &self.disk_results, Ready::readable()));
+
+        if let Err(e) = registered {
+            println!("Download stopped because {}", e);
+            self.send.send(DownloadState::Close).ok();
+            return false;
+        }
+
+        //The first tick looks for peers straight away
+        ctx.set_timeout(Duration::from_millis(0));
+        true
+    }
+
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        match event {
+            Event::Ready(SOURCE_CONTROL, _) => self.sync_ctrl(),
+            Event::Ready(SOURCE_PEERS, _) => self.sync_clients(),
+            Event::Ready(SOURCE_INCOMING, _) => self.sync_incoming(),
+            Event::Ready(SOURCE_DISK, _) => self.sync_disk(),
+            Event::Ready(..) => {},
+            Event::Timeout => {
+                self.sync_sources();
+                self.sync_candidates();
+                self.sync_clients();
+                self.sync_choker();
+                self.sync_idle();
+                self.sync_pex();
+
+                ctx.set_timeout(Duration::from_millis(TICK_MS));
+            }
+        }
 
-        println!("{}MB / {}MB", ((total_pieces - remaining_pieces) * piece_length) / 1024 / 1024, (total_pieces * piece_length) / 1024 / 1024);
+        true
     }
 }
 
-pub fn download(filename: &str) -> (Sender<DownloadState>, Receiver<DownloadState>) {
-    
-    let filename = filename.to_string();
+pub fn download(filename: &str, events: &EventLoop, server: &PeerServer, dht: Option<&Dht>, lsd: Option<&Lsd>, settings: &Settings) -> (channel::Sender<DownloadState>, Receiver<DownloadState>) {
 
     let (thread_send, main_recv): (Sender<DownloadState>, Receiver<DownloadState>) = mpsc::channel();
-    let (main_send, thread_recv): (Sender<DownloadState>, Receiver<DownloadState>) = mpsc::channel();
+    let (main_send, thread_recv) = channel::channel();
 
-    thread::spawn(move || {
+    let peer_port = server.port();
+    let tracker_port = 11993;
 
-        let peer_port = 6898;
-        let tracker_port = 11993;
+    let root = from_file(filename).unwrap();
+    let info = prepare(&root).unwrap();
 
-        let root = from_file(&filename).unwrap();
-        let info = prepare(&root).unwrap();
-        
-        println!("Loading {}", info.name);
+    println!("Loading {}", info.name);
 
-        let torrent_data = TorrentData::allocate(&("/home/blake/".to_string() + &info.name), info.pieces.clone(), info.piece_length); 
+    let torrent_data = TorrentData::allocate(&("/home/blake/".to_string() + &info.name), info.pieces.clone(), info.piece_length, info.length()); 
 
-        if let Err(v) = torrent_data { 
-            println!("Bad Allocate {}", v);
-            thread_send.send(DownloadState::Close);
-            return;
+    if let Err(v) = torrent_data { 
+        println!("Bad Allocate {}", v);
+        thread_send.send(DownloadState::Close).ok();
+        return (main_send, main_recv);
+    }
+
+    let (disk, disk_results) = disk_thread(torrent_data.unwrap());
+    let mut sources: Vec<Box<PeerSource>> = Vec::new();
+
+    if !info.announce.is_empty() {
+        sources.push(Box::new(TrackerSource::new(connect(events, &info, peer_port, tracker_port))));
+    }
+
+    //Private torrents only get peers from their tracker
+    if let (Some(dht), false) = (dht, info.private) {
+        if let Some(info_hash) = id_from_slice(&info.info_hash) {
+            sources.push(Box::new(DhtSource::new(dht, info_hash, peer_port, settings.dht_announce_interval)));
         }
+    }
 
-        let mut torrent_data = torrent_data.unwrap();
-        let mut tracker = connect(&info, peer_port, tracker_port);
+    if let (Some(lsd), true, false) = (lsd, settings.local_service_discovery, info.private) {
+        sources.push(Box::new(LsdSource::new(lsd, &info.info_hash)));
+    }
 
-        let mut state = Download {
-            send: thread_send, 
-            recv: thread_recv,
-            tracker: tracker,
-            data: torrent_data,
-            info: info,
-            active_clients: Vec::new()
+    events.spawn(Box::new(Download::new(info, events, server, sources, (disk, disk_results), settings, (thread_send, thread_recv))));
+
+    (main_send, main_recv)
+}
+
+#[cfg(test)]
+mod tests {
+    use download::{Download, Peer};
+    use peer_client::ClientState;
+    use peer_server::peer_server;
+    use picker::{PeerKey, CompletedPiece};
+    use event_loop::event_loop;
+    use torrent::{Info, FileInfo};
+    use tracker::PeerAddress;
+    use settings::Settings;
+    use mse::EncryptionPolicy;
+    use mio_extras::channel;
+    use std::net::IpAddr;
+    use std::sync::mpsc;
+    use std::io;
+    use std::time::Duration;
+
+    fn download() -> Download {
+        let events = event_loop(1).unwrap();
+        let server = peer_server(&events, 0, EncryptionPolicy::Disabled).unwrap();
+
+        let info = Info {
+            name: "test".to_string(),
+            announce: String::new(),
+            piece_length: 16384,
+            pieces: vec![vec![0; 20]; 4],
+            files: vec![FileInfo { path: "test".to_string(), length: 65536 }],
+            info_hash: vec![7; 20],
+            peer_id: vec![1; 20],
+            private: false
         };
 
-        loop {
+        let (_, disk_results) = channel::channel();
+        let (_, control) = channel::channel();
+        Download::new(info, &events, &server, Vec::new(), (mpsc::channel().0, disk_results), &Settings::default(), (mpsc::channel().0, control))
+    }
 
-            state.sync_ctrl();
-            state.sync_tracker();
-            state.sync_clients();
+    /** Add a peer, returning what the download sends it **/
+    fn connect(download: &mut Download, key: PeerKey, handshaken: bool) -> channel::Receiver<ClientState> {
+        let ip = IpAddr::from([10, 0, 0, key as u8]);
+        let (send, recv) = channel::channel();
+        let mut peer = Peer::new(key, PeerAddress { ip: ip, port: 6881 }, true, 4, send);
 
-            thread::sleep(Duration::from_millis(150));
-        } 
-    });
+        if handshaken {
+            peer.peer_id = Some(vec![key as u8; 20]);
+        }
 
-    (main_send, main_recv)
+        download.addresses.insert(key, ip);
+        download.active_clients.push(peer);
+        recv
+    }
+
+    #[test]
+    fn verified_pieces_announced_to_peers() {
+        let mut download = download();
+        let connected = connect(&mut download, 1, true);
+        let connecting = connect(&mut download, 2, false);
+        let gone = connect(&mut download, 3, true);
+        drop(gone);
+
+        let piece = |piece| CompletedPiece { piece: piece, data: vec![0; 16384], contributors: vec![1] };
+
+        let mut closed = Vec::new();
+        download.verify_piece(piece(2));
+        assert!(download.claimed.get(2) && !download.have.get(2));
+        download.piece_written(2, vec![0; 16384], Ok(()), &mut closed);
+
+        //Peers mid handshake hear about it in their advertisement, ones that can't be reached are dropped
+        assert!(download.have.get(2));
+        assert!(match connected.try_recv() { Ok(ClientState::Have(2)) => true, _ => false });
+        assert!(connecting.try_recv().is_err());
+        assert_eq!(closed, vec![2]);
+
+        //A piece that fails its hash check is announced to nobody and may be picked again
+        download.verify_piece(piece(3));
+        download.piece_written(3, vec![0; 16384], Err(io::Error::new(io::ErrorKind::InvalidData, "bad")), &mut closed);
+        assert!(!download.have.get(3) && !download.claimed.get(3));
+        assert!(connected.try_recv().is_err());
+    }
+
+    #[test]
+    fn idle_peers_closed() {
+        let mut download = download();
+        let idle = connect(&mut download, 1, true);
+
+        download.sync_idle();
+        assert!(idle.try_recv().is_err());
+
+        download.settings.idle_timeout = Duration::from_millis(0);
+        download.sync_idle();
+        assert!(match idle.try_recv() { Ok(ClientState::Close(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn duplicate_haves_count_once() {
+        let mut download = download();
+        let _peer = connect(&mut download, 1, true);
+        let mut closed = Vec::new();
+
+        for _ in 0..3 {
+            download.process_client_msg(0, ClientState::Acquired(1), &mut closed);
+        }
+
+        assert_eq!(download.picker.availability(1), 1);
+
+        //Leaving takes away everything the peer added
+        download.remove_closed(vec![0]);
+        assert_eq!(download.picker.availability(1), 0);
+    }
 }
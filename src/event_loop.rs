@@ -0,0 +1,364 @@
+/**
+ * Readiness driven event loop. A few reactor threads each wait on their sockets, channels and
+ * timers at once and call the handler that owns whatever became ready, so thousands of
+ * connections share a handful of threads instead of each blocking or polling on its own.
+ */
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, BinaryHeap};
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use mio::{Poll, Events, Token, Ready, PollOpt, Evented};
+use mio_extras::channel;
+
+/** Sockets and channels one handler may register, told apart by their source number **/
+pub const MAX_SOURCES: usize = 8;
+
+/** Token of the channel new handlers arrive on, handler ids start at 1 so none share it **/
+const SPAWN: Token = Token(0);
+
+const EVENTS_CAPACITY: usize = 1024;
+
+pub enum Event {
+    Ready(usize, Ready), /* A registered source (by number) became ready */
+    Timeout /* The handler's timeout passed */
+}
+
+/**
+ * Something driven by the event loop. Sources are registered edge triggered, so a handler must
+ * read or write until it would block before waiting for its next event.
+ */
+pub trait Handler: Send {
+    /** Register sources once on a reactor thread, false drops the handler **/
+    fn start(&mut self, ctx: &mut Context) -> bool;
+
+    /** React to an event, false when the handler is finished and should be dropped **/
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool;
+}
+
+/**
+ * One timeout per handler, replaced whenever it is set again
+ */
+struct Timers {
+    deadlines: HashMap<usize, Instant>,
+    queue: BinaryHeap<Reverse<(Instant, usize)>> /* May hold stale deadlines, checked against the map */
+}
+
+impl Timers {
+    fn set(&mut self, id: usize, at: Instant) {
+        self.deadlines.insert(id, at);
+        self.queue.push(Reverse((at, id)));
+    }
+
+    fn clear(&mut self, id: usize) {
+        self.deadlines.remove(&id);
+    }
+
+    fn is_current(&self, at: Instant, id: usize) -> bool {
+        self.deadlines.get(&id) == Some(&at)
+    }
+
+    /** When the next live timeout is due **/
+    fn next(&mut self) -> Option<Instant> {
+        while let Some(&Reverse((at, id))) = self.queue.peek() {
+            if self.is_current(at, id) {
+                return Some(at);
+            }
+
+            self.queue.pop();
+        }
+
+        None
+    }
+
+    /** Handlers whose timeouts have passed, each is cleared **/
+    fn expired(&mut self, now: Instant) -> Vec<usize> {
+        let mut expired = Vec::new();
+
+        while let Some(&Reverse((at, id))) = self.queue.peek() {
+            if at > now {
+                break;
+            }
+
+            self.queue.pop();
+
+            if self.is_current(at, id) {
+                self.deadlines.remove(&id);
+                expired.push(id);
+            }
+        }
+
+        expired
+    }
+}
+
+/**
+ * What a handler can do to the reactor it runs on while handling an event
+ */
+pub struct Context<'a> {
+    poll: &'a Poll,
+    id: usize,
+    timers: &'a mut Timers,
+    spawned: &'a mut Vec<Box<Handler>>
+}
+
+impl<'a> Context<'a> {
+    fn token(&self, source: usize) -> Token {
+        Token(self.id * MAX_SOURCES + source)
+    }
+
+    pub fn register(&self, source: usize, evented: &Evented, interest: Ready) -> io::Result<()> {
+        self.poll.register(evented, self.token(source), interest, PollOpt::edge())
+    }
+
+    pub fn reregister(&self, source: usize, evented: &Evented, interest: Ready) -> io::Result<()> {
+        self.poll.reregister(evented, self.token(source), interest, PollOpt::edge())
+    }
+
+    /** Must be called before a registered source is handed to another handler **/
+    pub fn deregister(&self, evented: &Evented) -> io::Result<()> {
+        self.poll.deregister(evented)
+    }
+
+    /** Raise a Timeout after delay, replacing any timeout already set **/
+    pub fn set_timeout(&mut self, delay: Duration) {
+        self.timers.set(self.id, Instant::now() + delay);
+    }
+
+    pub fn clear_timeout(&mut self) {
+        self.timers.clear(self.id);
+    }
+
+    /** Start another handler on this reactor once the current event has been handled **/
+    pub fn spawn(&mut self, handler: Box<Handler>) {
+        self.spawned.push(handler);
+    }
+}
+
+struct Reactor {
+    poll: Poll,
+    spawn: channel::Receiver<Box<Handler>>,
+    handlers: HashMap<usize, Box<Handler>>,
+    timers: Timers,
+    next_id: usize
+}
+
+impl Reactor {
+    /** Call a handler with a context for it, then start anything it spawned **/
+    fn call<F: FnOnce(&mut Handler, &mut Context) -> bool>(&mut self, id: usize, mut handler: Box<Handler>, f: F) {
+        let mut spawned = Vec::new();
+
+        let keep = {
+            let mut ctx = Context {
+                poll: &self.poll,
+                id: id,
+                timers: &mut self.timers,
+                spawned: &mut spawned
+            };
+
+            f(&mut *handler, &mut ctx)
+        };
+
+        //Dropping a handler closes its sockets, which takes them out of the poll
+        if keep {
+            self.handlers.insert(id, handler);
+        } else {
+            self.timers.clear(id);
+        }
+
+        for handler in spawned {
+            self.add(handler);
+        }
+    }
+
+    fn add(&mut self, handler: Box<Handler>) {
+        //Ids are never reused, so events still queued for a dropped handler are ignored
+        self.next_id += 1;
+        let id = self.next_id;
+        self.call(id, handler, |handler, ctx| handler.start(ctx));
+    }
+
+    fn dispatch(&mut self, id: usize, event: Event) {
+        if let Some(handler) = self.handlers.remove(&id) {
+            self.call(id, handler, |handler, ctx| handler.event(ctx, event));
+        }
+    }
+
+    fn run(mut self) {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+
+        loop {
+            let timeout = self.timers.next().map(|at| {
+                let now = Instant::now();
+                if at > now { at - now } else { Duration::from_millis(0) }
+            });
+
+            if let Err(e) = self.poll.poll(&mut events, timeout) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+
+                println!("Event loop stopped because {}", e);
+                return;
+            }
+
+            for event in events.iter() {
+                if event.token() == SPAWN {
+                    while let Ok(handler) = self.spawn.try_recv() {
+                        self.add(handler);
+                    }
+
+                    continue;
+                }
+
+                let Token(token) = event.token();
+                self.dispatch(token / MAX_SOURCES, Event::Ready(token % MAX_SOURCES, event.readiness()));
+            }
+
+            for id in self.timers.expired(Instant::now()) {
+                self.dispatch(id, Event::Timeout);
+            }
+        }
+    }
+}
+
+/**
+ * Handle to the reactor threads, cloned handles share them
+ */
+#[derive(Clone)]
+pub struct EventLoop {
+    reactors: Vec<channel::Sender<Box<Handler>>>,
+    next: Arc<AtomicUsize>
+}
+
+impl EventLoop {
+    /** Run a handler on one of the reactors, spreading handlers across them in turn **/
+    pub fn spawn(&self, handler: Box<Handler>) {
+        let reactor = self.next.fetch_add(1, Ordering::SeqCst) % self.reactors.len();
+        self.reactors[reactor].send(handler).ok();
+    }
+}
+
+/**
+ * Start an event loop of threads reactors
+ */
+pub fn event_loop(threads: usize) -> io::Result<EventLoop> {
+    let mut reactors = Vec::new();
+
+    for _ in 0..threads.max(1) {
+        let (send, recv) = channel::channel();
+        let poll = Poll::new()?;
+        poll.register(&recv, SPAWN, Ready::readable(), PollOpt::edge())?;
+
+        let reactor = Reactor {
+            poll: poll,
+            spawn: recv,
+            handlers: HashMap::new(),
+            timers: Timers {
+                deadlines: HashMap::new(),
+                queue: BinaryHeap::new()
+            },
+            next_id: 0
+        };
+
+        thread::spawn(move || {
+            reactor.run();
+        });
+
+        reactors.push(send);
+    }
+
+    Ok(EventLoop {
+        reactors: reactors,
+        next: Arc::new(AtomicUsize::new(0))
+    })
+}
+
+/** Wait for a message on an event loop channel from outside the loop, for tests **/
+#[cfg(test)]
+pub fn recv_timeout<T>(recv: &channel::Receiver<T>, timeout: Duration) -> Option<T> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        if let Ok(value) = recv.try_recv() {
+            return Some(value);
+        }
+
+        if Instant::now() >= deadline {
+            return None;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use event_loop::{event_loop, Handler, Context, Event};
+    use mio::Ready;
+    use mio_extras::channel;
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
+
+    /** Echoes channel messages and counts down its timeouts **/
+    struct Echo {
+        recv: channel::Receiver<u32>,
+        send: mpsc::Sender<u32>,
+        ticks: u32
+    }
+
+    impl Handler for Echo {
+        fn start(&mut self, ctx: &mut Context) -> bool {
+            ctx.set_timeout(Duration::from_millis(10));
+            ctx.register(0, &self.recv, Ready::readable()).is_ok()
+        }
+
+        fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+            match event {
+                Event::Ready(0, _) => {
+                    while let Ok(value) = self.recv.try_recv() {
+                        self.send.send(value).ok();
+                    }
+                },
+                Event::Timeout => {
+                    self.ticks -= 1;
+                    self.send.send(1000 + self.ticks).ok();
+
+                    if self.ticks > 0 {
+                        ctx.set_timeout(Duration::from_millis(10));
+                    }
+                },
+                _ => {}
+            }
+
+            true
+        }
+    }
+
+    #[test]
+    fn channels_and_timeouts() {
+        let events = event_loop(2).unwrap();
+        let (send, recv) = channel::channel();
+        let (echo_send, echoed) = mpsc::channel();
+
+        let started = Instant::now();
+
+        events.spawn(Box::new(Echo {
+            recv: recv,
+            send: echo_send,
+            ticks: 3
+        }));
+
+        send.send(7).unwrap();
+        send.send(8).unwrap();
+
+        let mut received: Vec<u32> = (0..5).map(|_| echoed.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+        received.sort();
+
+        assert_eq!(received, vec![7, 8, 1000, 1001, 1002]);
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+}
@@ -0,0 +1,166 @@
+/**
+ * Extension protocol (BEP 10). Extended messages carry a one byte id chosen by the receiver,
+ * each side announces the ids it wants in the m dictionary of its extended handshake (id 0).
+ */
+
+use std::collections::HashMap;
+use bencoder::{Entry, EntryData, decode};
+use peer_client::ClientState;
+use torrent::Info;
+use pex::PexHandler;
+
+/** Extended message id reserved for the handshake **/
+pub const HANDSHAKE_ID: u8 = 0;
+
+pub const CLIENT_VERSION: &'static str = "rustTorrent 0.1";
+
+/**
+ * A handler for one named extension, created for each connection
+ */
+pub trait ExtensionHandler: Send {
+    fn name(&self) -> &'static str;
+
+    /** Handle a message the peer sent for this extension, anything returned is passed to the download **/
+    fn receive(&mut self, payload: &[u8]) -> Result<Option<ClientState>, String>;
+}
+
+/** Handlers for every extension we support, ids are assigned in this order starting at 1 **/
+pub fn handlers(torrent: &Info) -> Vec<Box<ExtensionHandler>> {
+    let mut handlers: Vec<Box<ExtensionHandler>> = Vec::new();
+
+    //Private torrents only get peers from their tracker
+    if !torrent.private {
+        handlers.push(Box::new(PexHandler));
+    }
+
+    handlers
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtendedHandshake {
+    pub m: HashMap<String, u8>, /* Extension name to the id the sender wants it sent with, 0 disables */
+    pub v: Option<String>,
+    pub p: Option<u16>, /* Sender's listen port */
+    pub reqq: Option<usize> /* Outstanding requests the sender will queue */
+}
+
+impl ExtendedHandshake {
+    /** Our handshake, naming each handler with its local id **/
+    pub fn ours(handlers: &[Box<ExtensionHandler>], listen_port: u16, reqq: usize) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: handlers.iter().enumerate().map(|(i, h)| (h.name().to_string(), (i + 1) as u8)).collect(),
+            v: Some(CLIENT_VERSION.to_string()),
+            p: Some(listen_port),
+            reqq: Some(reqq)
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+
+        let m = self.m.iter().map(|(name, &id)| (name.clone(), Entry::new(EntryData::Int(id as i64)))).collect();
+        dict.insert("m".to_string(), Entry::new(EntryData::Dictionary(m)));
+
+        if let Some(ref v) = self.v {
+            dict.insert("v".to_string(), Entry::new(EntryData::Str(v.as_bytes().to_vec())));
+        }
+
+        if let Some(p) = self.p {
+            dict.insert("p".to_string(), Entry::new(EntryData::Int(p as i64)));
+        }
+
+        if let Some(reqq) = self.reqq {
+            dict.insert("reqq".to_string(), Entry::new(EntryData::Int(reqq as i64)));
+        }
+
+        EntryData::Dictionary(dict).bencode()
+    }
+
+    /** Parse a remote handshake, unknown keys are ignored and malformed optional keys dropped **/
+    pub fn decode(mut payload: &[u8]) -> Result<ExtendedHandshake, String> {
+        let root = decode(&mut payload).map_err(|_| "Malformed extended handshake".to_string())?;
+
+        if let EntryData::Dictionary(_) = root.data {} else {
+            return Err("Extended handshake is not a dictionary".to_string());
+        }
+
+        let mut m = HashMap::new();
+
+        if let Ok(Entry { data: EntryData::Dictionary(names), .. }) = root.field("m") {
+            for (name, id) in names {
+                if let EntryData::Int(id) = id.data {
+                    if id >= 0 && id <= 255 {
+                        m.insert(name, id as u8);
+                    }
+                }
+            }
+        }
+
+        let v = match root.field("v") {
+            Ok(Entry { data: EntryData::Str(v), .. }) => Some(String::from_utf8_lossy(&v).to_string()),
+            _ => None
+        };
+
+        let int = |key: &str| match root.field(key) {
+            Ok(Entry { data: EntryData::Int(n), .. }) if n >= 0 => Some(n as usize),
+            _ => None
+        };
+
+        Ok(ExtendedHandshake {
+            m: m,
+            v: v,
+            p: int("p").filter(|&p| p > 0 && p <= 65535).map(|p| p as u16),
+            reqq: int("reqq")
+        })
+    }
+
+    /** The id the sender wants a named extension sent with, if it supports it **/
+    pub fn id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).cloned().filter(|&id| id != HANDSHAKE_ID)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use extension::ExtendedHandshake;
+    use std::collections::HashMap;
+
+    #[test]
+    fn handshake_round_trip() {
+        let mut m = HashMap::new();
+        m.insert("ut_pex".to_string(), 1);
+        m.insert("ut_metadata".to_string(), 0);
+
+        let ours = ExtendedHandshake {
+            m: m,
+            v: Some("test".to_string()),
+            p: Some(6881),
+            reqq: Some(250)
+        };
+
+        let decoded = ExtendedHandshake::decode(&ours.encode()).unwrap();
+        assert_eq!(decoded, ours);
+        assert_eq!(decoded.id("ut_pex"), Some(1));
+        assert_eq!(decoded.id("ut_metadata"), None);
+    }
+
+    #[test]
+    fn keys_are_sorted() {
+        let ours = ExtendedHandshake {
+            m: HashMap::new(),
+            v: Some("x".to_string()),
+            p: Some(1),
+            reqq: Some(2)
+        };
+
+        assert_eq!(ours.encode(), b"d1:mde1:pi1e4:reqqi2e1:v1:xe".to_vec());
+    }
+
+    #[test]
+    fn tolerates_missing_keys() {
+        let decoded = ExtendedHandshake::decode(b"d1:pi99999ee").unwrap();
+        assert!(decoded.m.is_empty() && decoded.p.is_none() && decoded.v.is_none());
+        assert!(ExtendedHandshake::decode(b"i1e").is_err());
+        assert!(ExtendedHandshake::decode(b"d1:m").is_err());
+    }
+}
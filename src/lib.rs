@@ -5,8 +5,17 @@ extern crate sha1;
 extern crate url;
 extern crate byteorder;
 extern crate rand;
+extern crate ed25519_dalek;
+extern crate net2;
+extern crate num_bigint;
+extern crate mio;
+extern crate mio_extras;
 extern crate reqwest;
 
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
 mod bencoder;
 mod bencoder_recode;
 mod torrent;
@@ -15,6 +24,21 @@ mod tracker;
 mod download;
 mod peer_server;
 mod peer_client;
+mod peer_message;
+mod extension;
+mod pex;
+mod dht;
+mod peer_source;
+mod lsd;
+mod event_loop;
+mod utp;
+mod stream;
+mod mse;
 mod peer_id;
 mod urlencode;
 mod bitfield;
+mod picker;
+mod choker;
+mod trust;
+mod rate;
+mod settings;
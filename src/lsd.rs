@@ -0,0 +1,340 @@
+/**
+ * Local Service Discovery (BEP 14). Torrents are announced with BT-SEARCH messages multicast to
+ * the LAN, and peers heard announcing a torrent we have registered are passed to its download.
+ */
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4, Ipv4Addr};
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::io::ErrorKind::WouldBlock;
+use mio::Ready;
+use mio::net::UdpSocket;
+use mio_extras::channel;
+use net2::UdpBuilder;
+use rand::{thread_rng, Rng};
+use tracker::PeerAddress;
+use event_loop::{EventLoop, Handler, Context, Event};
+
+/** The multicast group and port BEP 14 announcements are sent to **/
+pub fn lsd_group() -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771)
+}
+
+/** Largest announcement we accept, they are a few short header lines **/
+const MAX_MESSAGE: usize = 1400;
+
+/** Sources the service registers with the event loop **/
+const SOURCE_SOCKET: usize = 0;
+const SOURCE_REQUESTS: usize = 1;
+
+pub enum LsdRequest {
+    Register(Vec<u8>, Sender<Vec<PeerAddress>>),
+    Unregister(Vec<u8>),
+    Close
+}
+
+/**
+ * Handle to the running discovery service
+ */
+#[derive(Clone)]
+pub struct Lsd {
+    send: channel::Sender<LsdRequest>
+}
+
+impl Lsd {
+    /** Start announcing an info hash, peers found on the LAN for it arrive on the returned channel **/
+    pub fn register(&self, info_hash: &[u8]) -> Receiver<Vec<PeerAddress>> {
+        let (send, recv) = mpsc::channel();
+        self.send.send(LsdRequest::Register(info_hash.to_vec(), send)).ok();
+        recv
+    }
+
+    pub fn unregister(&self, info_hash: &[u8]) {
+        self.send.send(LsdRequest::Unregister(info_hash.to_vec())).ok();
+    }
+
+    pub fn close(&self) {
+        self.send.send(LsdRequest::Close).ok();
+    }
+}
+
+/** A handle whose requests arrive on the returned channel rather than at a service, for tests **/
+#[cfg(test)]
+pub fn detached_lsd() -> (Lsd, channel::Receiver<LsdRequest>) {
+    let (send, recv) = channel::channel();
+    (Lsd { send: send }, recv)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len() / 2).map(|i| u8::from_str_radix(text.get(i * 2..i * 2 + 2)?, 16).ok()).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Announcement {
+    pub port: u16,
+    pub info_hashes: Vec<Vec<u8>>,
+    pub cookie: Option<String> /* Lets a sender recognise and ignore its own announcements */
+}
+
+impl Announcement {
+    pub fn encode(&self, group: &SocketAddrV4) -> Vec<u8> {
+        let mut msg = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n", group, self.port);
+
+        for info_hash in self.info_hashes.iter() {
+            msg += &format!("Infohash: {}\r\n", to_hex(info_hash));
+        }
+
+        if let Some(ref cookie) = self.cookie {
+            msg += &format!("cookie: {}\r\n", cookie);
+        }
+
+        msg += "\r\n\r\n";
+        msg.into_bytes()
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Announcement, String> {
+        let text = String::from_utf8_lossy(data);
+        let mut lines = text.split("\r\n");
+
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return Err("Not a BT-SEARCH message".to_string());
+        }
+
+        let mut announcement = Announcement {
+            port: 0,
+            info_hashes: Vec::new(),
+            cookie: None
+        };
+
+        for line in lines.take_while(|l| !l.is_empty()) {
+            let (name, value) = match line.find(':') {
+                Some(split) => (line[..split].trim().to_lowercase(), line[split + 1..].trim()),
+                None => continue
+            };
+
+            match name.as_ref() {
+                "port" => announcement.port = value.parse().map_err(|_| "Bad port".to_string())?,
+                "infohash" => announcement.info_hashes.push(from_hex(value).filter(|h| h.len() == 20).ok_or("Bad info hash")?),
+                "cookie" => announcement.cookie = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        if announcement.port == 0 || announcement.info_hashes.is_empty() {
+            return Err("Announcement missing port or info hash".to_string());
+        }
+
+        Ok(announcement)
+    }
+}
+
+struct Service {
+    socket: UdpSocket,
+    requests: channel::Receiver<LsdRequest>,
+    group: SocketAddrV4,
+    listen_port: u16,
+    interval: Duration,
+    cookie: String,
+    torrents: HashMap<Vec<u8>, (Sender<Vec<PeerAddress>>, Instant)> /* Where to send peers, next announcement */
+}
+
+impl Service {
+    fn announce(&self, info_hash: &[u8]) {
+        let msg = Announcement {
+            port: self.listen_port,
+            info_hashes: vec![info_hash.to_vec()],
+            cookie: Some(self.cookie.clone())
+        };
+
+        if let Err(e) = self.socket.send_to(&msg.encode(&self.group), &SocketAddr::V4(self.group)) {
+            println!("LSD: Announce failed because {}", e);
+        }
+    }
+
+    fn receive(&mut self, data: &[u8], from: SocketAddr) {
+        let msg = match Announcement::decode(data) {
+            Ok(msg) => msg,
+            Err(_) => return
+        };
+
+        if msg.cookie.as_ref() == Some(&self.cookie) {
+            return;
+        }
+
+        let peer = PeerAddress {
+            ip: from.ip(),
+            port: msg.port
+        };
+
+        for info_hash in msg.info_hashes.iter() {
+            let gone = match self.torrents.get(info_hash) {
+                Some(&(ref send, _)) => send.send(vec![peer.clone()]).is_err(),
+                None => false
+            };
+
+            //The download went away without unregistering
+            if gone {
+                self.torrents.remove(info_hash);
+            }
+        }
+    }
+
+    /** Announce every torrent that is due, then wait until the next one is **/
+    fn announce_due(&mut self, ctx: &mut Context) {
+        let due: Vec<Vec<u8>> = self.torrents.iter()
+            .filter(|&(_, &(_, next))| Instant::now() >= next)
+            .map(|(info_hash, _)| info_hash.clone())
+            .collect();
+
+        for info_hash in due {
+            self.announce(&info_hash);
+            self.torrents.get_mut(&info_hash).unwrap().1 = Instant::now() + self.interval;
+        }
+
+        match self.torrents.values().map(|&(_, next)| next).min() {
+            Some(next) => ctx.set_timeout(next.saturating_duration_since(Instant::now())),
+            None => ctx.clear_timeout()
+        }
+    }
+}
+
+impl Handler for Service {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        let registered = ctx.register(SOURCE_SOCKET, &self.socket, Ready::readable())
+            .and_then(|_| ctx.register(SOURCE_REQUESTS, &self.requests, Ready::readable()));
+
+        if let Err(e) = registered {
+            println!("LSD: Stopped because {}", e);
+            return false;
+        }
+
+        true
+    }
+
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        match event {
+            Event::Ready(SOURCE_SOCKET, _) => {
+                let mut buffer = [0; MAX_MESSAGE];
+
+                loop {
+                    match self.socket.recv_from(&mut buffer) {
+                        Ok((len, from)) => self.receive(&buffer[..len], from),
+                        Err(ref e) if e.kind() == WouldBlock => break,
+                        Err(e) => {
+                            //Errors such as an unreachable host are reported once, re-arming picks up any datagrams behind them
+                            println!("LSD socket error {}", e);
+                            ctx.reregister(SOURCE_SOCKET, &self.socket, Ready::readable()).ok();
+                            break;
+                        }
+                    }
+                }
+            },
+            Event::Ready(SOURCE_REQUESTS, _) => {
+                while let Ok(request) = self.requests.try_recv() {
+                    match request {
+                        LsdRequest::Register(info_hash, send) => {
+                            self.torrents.insert(info_hash, (send, Instant::now()));
+                        },
+                        LsdRequest::Unregister(info_hash) => {
+                            self.torrents.remove(&info_hash);
+                        },
+                        LsdRequest::Close => return false
+                    }
+                }
+
+                self.announce_due(ctx);
+            },
+            Event::Ready(..) => {},
+            Event::Timeout => self.announce_due(ctx)
+        }
+
+        true
+    }
+}
+
+/**
+ * Start discovery for peers listening on listen_port, announcing each registered torrent to the
+ * multicast group every interval. Several clients on one host can share the group port.
+ */
+pub fn lsd(events: &EventLoop, listen_port: u16, group: SocketAddrV4, interval: Duration) -> Result<Lsd, String> {
+    let builder = UdpBuilder::new_v4().map_err(|e| e.to_string())?;
+    builder.reuse_address(true).map_err(|e| e.to_string())?;
+    let socket = builder.bind(("0.0.0.0", group.port())).map_err(|e| e.to_string())?;
+
+    socket.join_multicast_v4(group.ip(), &Ipv4Addr::new(0, 0, 0, 0)).map_err(|e| e.to_string())?;
+    let socket = UdpSocket::from_socket(socket).map_err(|e| e.to_string())?;
+
+    let (send, recv) = channel::channel();
+
+    events.spawn(Box::new(Service {
+        socket: socket,
+        requests: recv,
+        group: group,
+        listen_port: listen_port,
+        interval: interval,
+        cookie: to_hex(&thread_rng().gen::<[u8; 8]>()),
+        torrents: HashMap::new()
+    }));
+
+    Ok(Lsd {
+        send: send
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use lsd::{lsd, lsd_group, Announcement};
+    use event_loop::event_loop;
+    use std::net::{SocketAddrV4, Ipv4Addr};
+    use std::time::Duration;
+
+    #[test]
+    fn announcement_round_trip() {
+        let msg = Announcement {
+            port: 6881,
+            info_hashes: vec![vec![0xab; 20], vec![1; 20]],
+            cookie: Some("c00k1e".to_string())
+        };
+
+        assert_eq!(Announcement::decode(&msg.encode(&lsd_group())).unwrap(), msg);
+
+        let theirs = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nport: 51413\r\nINFOHASH: ABABABABABABABABABABABABABABABABABABABAB\r\n\r\n\r\n";
+        assert_eq!(Announcement::decode(theirs).unwrap().info_hashes, vec![vec![0xab; 20]]);
+        assert!(Announcement::decode(b"M-SEARCH * HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn peers_found_over_loopback_multicast() {
+        //A group port of our own keeps the test away from real clients on the LAN
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 16771);
+        let events = event_loop(1).unwrap();
+        let a = lsd(&events, 4001, group, Duration::from_millis(200)).unwrap();
+        let b = lsd(&events, 4002, group, Duration::from_millis(200)).unwrap();
+
+        let info_hash = [3; 20];
+        let found_by_a = a.register(&info_hash);
+        let found_by_b = b.register(&info_hash);
+
+        let from_b = found_by_a.recv_timeout(Duration::from_secs(5)).unwrap();
+        let from_a = found_by_b.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(from_b[0].port, 4002);
+        assert_eq!(from_a[0].port, 4001);
+
+        //Torrents only one side has registered, and our own announcements, find nothing
+        let unshared = a.register(&[4; 20]);
+        assert!(unshared.recv_timeout(Duration::from_millis(500)).is_err());
+
+        a.close();
+        b.close();
+    }
+}
@@ -5,8 +5,17 @@ extern crate sha1;
 extern crate url;
 extern crate byteorder;
 extern crate rand;
+extern crate ed25519_dalek;
+extern crate net2;
+extern crate num_bigint;
+extern crate mio;
+extern crate mio_extras;
 extern crate reqwest;
 
+#[cfg(test)]
+#[macro_use]
+extern crate quickcheck;
+
 mod bencoder;
 mod bencoder_recode;
 mod torrent;
@@ -15,22 +24,55 @@ mod tracker;
 mod download;
 mod peer_server;
 mod peer_client;
+mod peer_message;
+mod extension;
+mod pex;
+mod dht;
+mod peer_source;
+mod lsd;
+mod event_loop;
+mod utp;
+mod stream;
+mod mse;
 mod peer_id;
 mod urlencode;
 mod bitfield;
+mod picker;
+mod choker;
+mod trust;
+mod rate;
+mod settings;
 
 use std::env;
-use std::thread;
-use std::time::Duration;
 
 pub fn main() {
-    let (master_send, master_recv) = download::download(&env::args().nth(1).unwrap());
+    let settings = settings::Settings::default();
+    let events = event_loop::event_loop(settings.event_threads).unwrap();
+    let server = peer_server::peer_server(&events, 6898, settings.encryption).unwrap();
+
+    let dht = match dht::dht(&events, 6881, &settings.dht_bootstrap, settings.dht_state_path.clone()) {
+        Ok(dht) => Some(dht),
+        Err(e) => {
+            println!("Running without the DHT because {}", e);
+            None
+        }
+    };
+
+    let lsd = match lsd::lsd(&events, server.port(), lsd::lsd_group(), settings.lsd_interval) {
+        Ok(lsd) => Some(lsd),
+        Err(e) => {
+            println!("Running without local service discovery because {}", e);
+            None
+        }
+    };
+
+    let (master_send, master_recv) = download::download(&env::args().nth(1).unwrap(), &events, &server, dht.as_ref(), lsd.as_ref(), &settings);
 
+    //Everything runs on the event loop, the main thread only waits for the download to finish
     loop {
-        let master_data = master_recv.try_recv();
-        if let Ok(download::DownloadState::Close) = master_data {
-            break;
+        match master_recv.recv() {
+            Ok(download::DownloadState::Close) | Err(_) => break,
+            Ok(_) => {}
         }
-        thread::sleep(Duration::from_millis(500));
-    }    
+    }
 }
@@ -0,0 +1,515 @@
+/**
+ * Message Stream Encryption / Protocol Encryption. A Diffie-Hellman exchange gives both sides a
+ * shared secret, the info hash proves they want the same torrent, and the rest of the connection
+ * is RC4 encrypted or left plaintext as negotiated.
+ */
+
+use num_bigint::BigUint;
+use rand::{thread_rng, Rng};
+use sha1;
+
+/** 768 bit safe prime from the specification, the generator is 2 **/
+const PRIME: &'static str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+const KEY_LEN: usize = 96;
+const MAX_PAD: usize = 512;
+
+/** Keystream bytes thrown away, the start of RC4's output is weak **/
+const RC4_DISCARD: usize = 1024;
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+const VC: [u8; 8] = [0; 8];
+
+const PROTOCOL_PREFIX: &'static [u8] = b"\x13BitTorrent protocol";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionPolicy {
+    Forced, /* Only RC4 encrypted connections */
+    Enabled, /* Prefer RC4, accept plaintext */
+    Disabled /* Plain BitTorrent handshakes only */
+}
+
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut state = [0; 256];
+
+        for i in 0..256 {
+            state[i] = i as u8;
+        }
+
+        let mut j: u8 = 0;
+
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 {
+            state: state,
+            i: 0,
+            j: 0
+        }
+    }
+
+    /** Encrypt or decrypt in place **/
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state[self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> [u8; 20] {
+    let mut digest = sha1::Sha1::new();
+    parts.iter().for_each(|p| digest.update(p));
+    digest.digest().bytes()
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+/** RC4 keyed from the shared secret and info hash, "keyA" for what the initiator sends and "keyB" for what it receives **/
+fn cipher(name: &[u8], secret: &[u8], info_hash: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(&sha1(&[name, secret, info_hash]));
+    rc4.apply(&mut [0; RC4_DISCARD]);
+    rc4
+}
+
+fn to_key(value: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let mut key = vec![0; KEY_LEN - bytes.len()];
+    key.extend(bytes);
+    key
+}
+
+/** A random private key and the public key we send for it **/
+fn key_pair() -> (BigUint, Vec<u8>) {
+    let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+    let private = BigUint::from_bytes_be(&thread_rng().gen::<[u8; 20]>());
+    let public = BigUint::from(2u32).modpow(&private, &prime);
+    (private, to_key(&public))
+}
+
+fn shared_secret(private: &BigUint, their_public: &[u8]) -> Vec<u8> {
+    let prime = BigUint::parse_bytes(PRIME.as_bytes(), 16).unwrap();
+    to_key(&BigUint::from_bytes_be(their_public).modpow(private, &prime))
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = thread_rng();
+    let len = rng.gen_range(0, MAX_PAD + 1);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+fn u32_bytes(value: u32) -> [u8; 4] {
+    [(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+fn get_u32(data: &[u8]) -> u32 {
+    ((data[0] as u32) << 24) | ((data[1] as u32) << 16) | ((data[2] as u32) << 8) | data[3] as u32
+}
+
+fn get_u16(data: &[u8]) -> usize {
+    ((data[0] as usize) << 8) | data[1] as usize
+}
+
+/** Find the other side's pattern past its random padding, which must come within max bytes **/
+fn sync(input: &[u8], pattern: &[u8], max: usize) -> Result<Option<usize>, String> {
+    match input.windows(pattern.len()).position(|w| w == pattern) {
+        Some(at) if at <= max => Ok(Some(at + pattern.len())),
+        _ if input.len() >= max + pattern.len() => Err("Encryption handshake out of sync".to_string()),
+        _ => Ok(None)
+    }
+}
+
+/** How a finished negotiation left the connection **/
+pub enum Negotiated {
+    Plaintext,
+    Rc4(Rc4, Rc4) /* Encrypts what we send and decrypts what we receive */
+}
+
+enum Step {
+    Start,
+    ReadPublic, /* Their public key */
+    SyncVc(Vec<u8>), /* The encrypted verification constant that follows their padding */
+    ReadSelect,
+    ReadStart, /* Either a plaintext BitTorrent handshake or the start of a public key */
+    SyncReq1(Vec<u8>), /* HASH('req1', S) after their padding */
+    ReadTorrent, /* The obscured info hash */
+    ReadOffer
+}
+
+enum Progress {
+    Wait,
+    Next,
+    Done(Negotiated)
+}
+
+/**
+ * Encryption negotiation for one connection, fed whatever has been received and queueing what
+ * to send so it never waits on the socket itself.
+ */
+pub struct Negotiation {
+    policy: EncryptionPolicy,
+    info_hashes: Vec<Vec<u8>>, /* The torrent we want, or those we accept connections for */
+    private: BigUint,
+    public: Vec<u8>,
+    secret: Vec<u8>,
+    ciphers: Option<(Rc4, Rc4)>,
+    step: Step
+}
+
+impl Negotiation {
+    fn new(info_hashes: Vec<Vec<u8>>, policy: EncryptionPolicy, step: Step) -> Negotiation {
+        let (private, public) = key_pair();
+
+        Negotiation {
+            policy: policy,
+            info_hashes: info_hashes,
+            private: private,
+            public: public,
+            secret: Vec::new(),
+            ciphers: None,
+            step: step
+        }
+    }
+
+    /** Negotiate an outgoing connection to a peer with info_hash, disabled negotiates nothing **/
+    pub fn outgoing(info_hash: &[u8], policy: EncryptionPolicy) -> Negotiation {
+        Negotiation::new(vec![info_hash.to_vec()], policy, Step::Start)
+    }
+
+    /** Negotiate an incoming connection for one of info_hashes, or let a plain handshake through if the policy allows **/
+    pub fn incoming(info_hashes: Vec<Vec<u8>>, policy: EncryptionPolicy) -> Negotiation {
+        Negotiation::new(info_hashes, policy, Step::ReadStart)
+    }
+
+    /**
+     * Take what has arrived in input and queue any reply on output. Once negotiated, input holds
+     * the plaintext that followed and the ciphers for the rest of the connection are returned.
+     */
+    pub fn advance(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<Option<Negotiated>, String> {
+        loop {
+            match self.step(input, output)? {
+                Progress::Wait => return Ok(None),
+                Progress::Next => {},
+                Progress::Done(negotiated) => return Ok(Some(negotiated))
+            }
+        }
+    }
+
+    fn send_public(&self, output: &mut Vec<u8>) {
+        output.extend_from_slice(&self.public);
+        output.extend(random_pad());
+    }
+
+    /** Finish with the method selected, anything received after the handshake is decrypted to match **/
+    fn finish(&mut self, select: u32, input: &mut Vec<u8>) -> Progress {
+        match self.ciphers.take() {
+            Some((encrypt, mut decrypt)) if select == CRYPTO_RC4 => {
+                decrypt.apply(input);
+                Progress::Done(Negotiated::Rc4(encrypt, decrypt))
+            },
+            _ => Progress::Done(Negotiated::Plaintext)
+        }
+    }
+
+    fn provide(&self) -> u32 {
+        match self.policy {
+            EncryptionPolicy::Forced => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT
+        }
+    }
+
+    fn step(&mut self, input: &mut Vec<u8>, output: &mut Vec<u8>) -> Result<Progress, String> {
+        match self.step {
+            Step::Start => {
+                if self.policy == EncryptionPolicy::Disabled {
+                    return Ok(Progress::Done(Negotiated::Plaintext));
+                }
+
+                self.send_public(output);
+                self.step = Step::ReadPublic;
+                Ok(Progress::Next)
+            },
+            Step::ReadPublic => {
+                if input.len() < KEY_LEN {
+                    return Ok(Progress::Wait);
+                }
+
+                let their_public: Vec<u8> = input.drain(0..KEY_LEN).collect();
+                self.secret = shared_secret(&self.private, &their_public);
+
+                let info_hash = self.info_hashes[0].clone();
+                let mut encrypt = cipher(b"keyA", &self.secret, &info_hash);
+                let mut decrypt = cipher(b"keyB", &self.secret, &info_hash);
+
+                //No padding and no initial payload, the BitTorrent handshake follows once negotiated
+                let mut offer = VC.to_vec();
+                offer.extend(&u32_bytes(self.provide()));
+                offer.extend(&[0, 0, 0, 0]);
+                encrypt.apply(&mut offer);
+
+                output.extend_from_slice(&sha1(&[b"req1", &self.secret]));
+                output.extend(xor(&sha1(&[b"req2", &info_hash]), &sha1(&[b"req3", &self.secret])));
+                output.extend(offer);
+
+                //The reply starts with the verification constant, found by what it encrypts to
+                let mut vc = VC.to_vec();
+                decrypt.apply(&mut vc);
+
+                self.ciphers = Some((encrypt, decrypt));
+                self.step = Step::SyncVc(vc);
+                Ok(Progress::Next)
+            },
+            Step::SyncVc(ref vc) => {
+                match sync(input, vc, MAX_PAD)? {
+                    Some(end) => {
+                        input.drain(0..end);
+                        self.step = Step::ReadSelect;
+                        Ok(Progress::Next)
+                    },
+                    None => Ok(Progress::Wait)
+                }
+            },
+            Step::ReadSelect => {
+                if input.len() < 6 {
+                    return Ok(Progress::Wait);
+                }
+
+                //Decrypt a copy until the whole padding is here, the keystream can't be rewound
+                let mut header = input[0..6].to_vec();
+                self.ciphers.as_ref().unwrap().1.clone().apply(&mut header);
+
+                let (select, pad) = (get_u32(&header), get_u16(&header[4..]));
+
+                if pad > MAX_PAD {
+                    return Err("Encryption handshake padding too long".to_string());
+                }
+
+                if input.len() < 6 + pad {
+                    return Ok(Progress::Wait);
+                }
+
+                let mut used: Vec<u8> = input.drain(0..6 + pad).collect();
+                self.ciphers.as_mut().unwrap().1.apply(&mut used);
+
+                if select & self.provide() == 0 || select.count_ones() != 1 {
+                    return Err("Peer selected a method we did not offer".to_string());
+                }
+
+                Ok(self.finish(select, input))
+            },
+            Step::ReadStart => {
+                if input.len() < PROTOCOL_PREFIX.len() {
+                    return Ok(Progress::Wait);
+                }
+
+                if input.starts_with(PROTOCOL_PREFIX) {
+                    if self.policy == EncryptionPolicy::Forced {
+                        return Err("Plaintext connection refused".to_string());
+                    }
+
+                    return Ok(Progress::Done(Negotiated::Plaintext));
+                }
+
+                if self.policy == EncryptionPolicy::Disabled {
+                    return Err("Encrypted connection refused".to_string());
+                }
+
+                if input.len() < KEY_LEN {
+                    return Ok(Progress::Wait);
+                }
+
+                let their_public: Vec<u8> = input.drain(0..KEY_LEN).collect();
+                self.secret = shared_secret(&self.private, &their_public);
+                self.send_public(output);
+
+                self.step = Step::SyncReq1(sha1(&[b"req1", &self.secret]).to_vec());
+                Ok(Progress::Next)
+            },
+            Step::SyncReq1(ref req1) => {
+                match sync(input, req1, MAX_PAD)? {
+                    Some(end) => {
+                        input.drain(0..end);
+                        self.step = Step::ReadTorrent;
+                        Ok(Progress::Next)
+                    },
+                    None => Ok(Progress::Wait)
+                }
+            },
+            Step::ReadTorrent => {
+                if input.len() < 20 {
+                    return Ok(Progress::Wait);
+                }
+
+                //Which of our torrents the peer wants, hidden so only someone with the info hash can tell
+                let obscured: Vec<u8> = input.drain(0..20).collect();
+                let req2 = xor(&obscured, &sha1(&[b"req3", &self.secret]));
+
+                let info_hash = self.info_hashes.iter()
+                    .find(|info_hash| sha1(&[b"req2", info_hash]).to_vec() == req2)
+                    .cloned()
+                    .ok_or("Encrypted connection for an unknown torrent")?;
+
+                let decrypt = cipher(b"keyA", &self.secret, &info_hash);
+                let encrypt = cipher(b"keyB", &self.secret, &info_hash);
+
+                self.ciphers = Some((encrypt, decrypt));
+                self.step = Step::ReadOffer;
+                Ok(Progress::Next)
+            },
+            Step::ReadOffer => {
+                //VC, crypto_provide and padding length, then the padding and the initial payload length
+                if input.len() < 14 {
+                    return Ok(Progress::Wait);
+                }
+
+                let mut peek = self.ciphers.as_ref().unwrap().1.clone();
+
+                let mut header = input[0..14].to_vec();
+                peek.apply(&mut header);
+
+                let pad = get_u16(&header[12..]);
+
+                if pad > MAX_PAD {
+                    return Err("Encryption handshake padding too long".to_string());
+                }
+
+                if input.len() < 16 + pad {
+                    return Ok(Progress::Wait);
+                }
+
+                let mut rest = input[14..16 + pad].to_vec();
+                peek.apply(&mut rest);
+
+                let initial_len = get_u16(&rest[pad..]);
+
+                if input.len() < 16 + pad + initial_len {
+                    return Ok(Progress::Wait);
+                }
+
+                let mut offer: Vec<u8> = input.drain(0..16 + pad + initial_len).collect();
+                self.ciphers.as_mut().unwrap().1.apply(&mut offer);
+
+                if offer[0..8] != VC {
+                    return Err("Bad verification constant".to_string());
+                }
+
+                let provide = get_u32(&offer[8..]);
+
+                let select = if provide & CRYPTO_RC4 != 0 {
+                    CRYPTO_RC4
+                } else if provide & CRYPTO_PLAINTEXT != 0 && self.policy != EncryptionPolicy::Forced {
+                    CRYPTO_PLAINTEXT
+                } else {
+                    return Err("No encryption method in common".to_string());
+                };
+
+                let mut reply = VC.to_vec();
+                reply.extend(&u32_bytes(select));
+                reply.extend(&[0, 0]);
+                self.ciphers.as_mut().unwrap().0.apply(&mut reply);
+                output.extend(reply);
+
+                //The initial payload goes ahead of whatever followed it
+                let mut plaintext = offer.split_off(16 + pad);
+                let progress = self.finish(select, input);
+                plaintext.extend(input.drain(..));
+                *input = plaintext;
+                Ok(progress)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mse::{Rc4, EncryptionPolicy, Negotiation, Negotiated};
+
+    #[test]
+    fn rc4_vectors() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, vec![0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+    }
+
+    /** Run an outgoing and incoming negotiation against each other, returning what the acceptor read **/
+    fn negotiate(ours: EncryptionPolicy, theirs: EncryptionPolicy, plain_handshake: bool) -> Result<Vec<u8>, String> {
+        let message = b"\x13BitTorrent protocol and the rest".to_vec();
+
+        let mut initiator = Negotiation::outgoing(&[5; 20], ours);
+        let mut acceptor = Negotiation::incoming(vec![vec![4; 20], vec![5; 20]], theirs);
+
+        let mut to_initiator = Vec::new();
+        let mut to_acceptor = if plain_handshake { message.clone() } else { Vec::new() };
+        let mut sent = plain_handshake;
+
+        let mut accepted: Option<Negotiated> = None;
+        let mut received = Vec::new();
+
+        //Each side answers what the other sent, bytes arriving after the acceptor finished are decrypted by it
+        for _ in 0..5 {
+            if !sent {
+                if let Some(negotiated) = initiator.advance(&mut to_initiator, &mut to_acceptor)? {
+                    let mut data = message.clone();
+
+                    if let Negotiated::Rc4(mut encrypt, _) = negotiated {
+                        encrypt.apply(&mut data);
+                    }
+
+                    to_acceptor.extend(data);
+                    sent = true;
+                }
+            }
+
+            match accepted {
+                None => {
+                    accepted = acceptor.advance(&mut to_acceptor, &mut to_initiator)?;
+
+                    if accepted.is_some() {
+                        received.extend(to_acceptor.drain(..));
+                    }
+                },
+                Some(ref mut negotiated) => {
+                    let mut data: Vec<u8> = to_acceptor.drain(..).collect();
+
+                    if let Negotiated::Rc4(_, ref mut decrypt) = *negotiated {
+                        decrypt.apply(&mut data);
+                    }
+
+                    received.extend(data);
+                }
+            }
+        }
+
+        Ok(received)
+    }
+
+    #[test]
+    fn policies() {
+        let expected = b"\x13BitTorrent protocol and the rest".to_vec();
+
+        assert_eq!(negotiate(EncryptionPolicy::Enabled, EncryptionPolicy::Forced, false).unwrap(), expected);
+        assert_eq!(negotiate(EncryptionPolicy::Forced, EncryptionPolicy::Enabled, false).unwrap(), expected);
+        assert_eq!(negotiate(EncryptionPolicy::Disabled, EncryptionPolicy::Enabled, false).unwrap(), expected);
+        assert_eq!(negotiate(EncryptionPolicy::Disabled, EncryptionPolicy::Enabled, true).unwrap(), expected);
+
+        assert!(negotiate(EncryptionPolicy::Disabled, EncryptionPolicy::Forced, true).is_err());
+        assert!(negotiate(EncryptionPolicy::Forced, EncryptionPolicy::Disabled, false).is_err());
+    }
+}
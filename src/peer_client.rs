@@ -1,264 +1,779 @@
 /**
- * TCP Peer-wire client implementation
+ * Peer-wire client implementation, each connection is a handler on the event loop
  */
 
-use torrent::Info;
+use torrent::{Info, piece_size};
 use tracker::PeerAddress;
 use std::io;
-use std::io::{copy, Read, Write};
-use std::io::ErrorKind::{WouldBlock, TimedOut};
-use std::net::TcpStream;
-use std::thread;
-use std::time;
-use std::sync::mpsc::{Sender, Receiver};
-use byteorder::{BE, WriteBytesExt, ReadBytesExt};
-use std::sync::mpsc;
+use std::io::{Read, Write};
+use std::io::ErrorKind::{WouldBlock, UnexpectedEof, InvalidData};
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use mio::Ready;
+use mio_extras::channel;
 use bitfield::Bitfield;
+use peer_message::{Message, MAX_BLOCK_LEN};
+use peer_server::{PeerServer, Incoming, ConnectionSlot};
+use stream::{Wire, Transport};
+use mse::{EncryptionPolicy, Negotiation, Negotiated};
+use event_loop::{EventLoop, Handler, Context, Event};
+use settings::Settings;
+use picker::{Block, BLOCK_SIZE, PeerKey};
+use rate::{Throughput, secs};
+use sha1;
+use extension::{ExtensionHandler, ExtendedHandshake, HANDSHAKE_ID, handlers};
 
 pub enum ClientState {
-    Commit(usize, Vec<u8>), /* Write cached piece to file */
-    Need(Bitfield),
-    Want(usize),
+    Need(usize), /* Ask for up to n blocks from the pieces the peer has */
+    NeedAllowed(usize, Bitfield), /* As Need, limited to pieces the peer lets us request while it chokes us */
+    Suggested(usize), /* The peer suggests we download a piece */
+    Available(Bitfield), /* The peer's full set of pieces */
+    Acquired(usize), /* The peer announced a new piece */
+    Want(Vec<Block>), /* Blocks to request, may be empty if nothing is available right now */
+    Received(Block, Vec<u8>), /* A requested block arrived */
+    Redundant(usize), /* Bytes of a block that arrived after it was cancelled or was never requested */
+    Snubbed(bool), /* The peer stopped (or resumed) sending the blocks we asked for */
+    Abandon(Vec<Block>), /* Requests the peer will no longer answer */
+    Cancel(Block), /* Block was reassigned, stop waiting for it */
+    Connected(Vec<u8>), /* Remote peer id from a valid handshake */
+    Advertise(Bitfield), /* Our pieces, sent to the peer before any other message */
+    Interested(bool), /* The peer's interest in us changed */
+    Choke,
+    Unchoke,
+    Read(usize, usize, usize), /* Peer requested a block (piece, begin, length) */
+    Block(usize, usize, Vec<u8>), /* Block read from storage to answer a request */
+    Uploaded(usize),
+    Have(usize), /* We completed a piece */
+    Extended(&'static str, Vec<u8>), /* Payload for a named extension, dropped if the peer doesn't support it */
+    ListenPort(u16), /* Port the peer accepts connections on, from its extended handshake */
+    Discovered(Vec<PeerAddress>), /* Peers we could connect to, learned from this peer */
     Close(String)
 }
 
+const PROTOCOL: &'static [u8] = b"BitTorrent protocol";
+
+/**
+ * Optional protocol features advertised in the handshake reserved bytes
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    pub extension_protocol: bool, /* BEP 10 */
+    pub fast: bool, /* BEP 6 */
+    pub dht: bool /* BEP 5 */
+}
+
+impl Capabilities {
+    /** What we advertise in our own handshakes **/
+    pub fn ours() -> Capabilities {
+        Capabilities {
+            extension_protocol: true,
+            fast: true,
+            ..Capabilities::default()
+        }
+    }
+
+    pub fn from_reserved(reserved: &[u8; 8]) -> Capabilities {
+        Capabilities {
+            extension_protocol: reserved[5] & 0x10 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            dht: reserved[7] & 0x01 != 0
+        }
+    }
+
+    pub fn to_reserved(&self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        if self.extension_protocol { reserved[5] |= 0x10; }
+        if self.fast { reserved[7] |= 0x04; }
+        if self.dht { reserved[7] |= 0x01; }
+        reserved
+    }
+}
+
 #[derive(Debug)]
 pub struct HandshakeMsg {
-    pub pstr: String,
+    pub pstr: Vec<u8>,
+    pub reserved: [u8; 8],
     pub info_hash: Vec<u8>,
     pub peer_id: Vec<u8>
 }
 
 impl HandshakeMsg {
+    pub fn new(info_hash: &[u8], peer_id: &[u8], capabilities: Capabilities) -> HandshakeMsg {
+        HandshakeMsg {
+            pstr: PROTOCOL.to_vec(),
+            reserved: capabilities.to_reserved(),
+            info_hash: info_hash.to_vec(),
+            peer_id: peer_id.to_vec()
+        }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
-        let resvd = [0u8; 8];
         let mut data = Vec::new();
-        data.write(&[self.pstr.len() as u8]).unwrap();
-        data.write(self.pstr.as_bytes()).unwrap();
-        data.write(&resvd).unwrap();
-        data.write(&self.info_hash).unwrap();
-        data.write(&self.peer_id).unwrap();
+        data.push(self.pstr.len() as u8);
+        data.extend_from_slice(&self.pstr);
+        data.extend_from_slice(&self.reserved);
+        data.extend_from_slice(&self.info_hash);
+        data.extend_from_slice(&self.peer_id);
         data
     }
 
-    pub fn recv(stream: &mut TcpStream) -> Result<HandshakeMsg, io::Error> {
-        let pstrlen = stream.read_u8()?;
+    /** Decode a handshake from the start of data and how many bytes it took, None until all of it has arrived **/
+    pub fn decode(data: &[u8]) -> Option<(HandshakeMsg, usize)> {
+        let pstrlen = *data.get(0)? as usize;
+        let len = 1 + pstrlen + 8 + 20 + 20;
 
-        let mut pstr = vec![0; pstrlen as usize];
-        stream.read_exact(&mut pstr)?;
+        if data.len() < len {
+            return None;
+        }
 
-        let mut resvd = vec![0; 8];
-        stream.read_exact(&mut resvd)?;
+        let mut reserved = [0; 8];
+        reserved.copy_from_slice(&data[1 + pstrlen..9 + pstrlen]);
 
-        let mut info_hash = vec![0; 20];
-        let mut peer_id = vec![0; 20];
+        Some((HandshakeMsg {
+            pstr: data[1..1 + pstrlen].to_vec(),
+            reserved: reserved,
+            info_hash: data[9 + pstrlen..29 + pstrlen].to_vec(),
+            peer_id: data[29 + pstrlen..len].to_vec()
+        }, len))
+    }
 
-        stream.read_exact(&mut info_hash)?;
-        stream.read_exact(&mut peer_id)?;
-        
-        Ok(HandshakeMsg {
-            pstr: String::from_utf8(pstr).unwrap() /* TODO: This might error */,
-            info_hash: info_hash,
-            peer_id: peer_id
-        })
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::from_reserved(&self.reserved)
+    }
+
+    /** Check a remote handshake is for our torrent and not from ourselves **/
+    pub fn validate(&self, info_hash: &[u8], our_peer_id: &[u8]) -> Result<(), String> {
+        if self.pstr != PROTOCOL {
+            Err(format!("Unknown protocol {}", String::from_utf8_lossy(&self.pstr)))
+        } else if self.info_hash != info_hash {
+            Err("Info hash mismatch".to_string())
+        } else if self.peer_id == our_peer_id {
+            Err("Connected to ourselves".to_string())
+        } else {
+            Ok(())
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct GeneralMsg {
-    pub action: u8,
-    pub payload: Vec<u8>
+fn send<W: Write + ?Sized>(stream: &mut W, msg: &Message) -> Result<(), io::Error> {
+    stream.write_all(&msg.serialize())
 }
 
-impl GeneralMsg {
-    
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut data = Vec::new();
-        data.write_u32::<BE>((self.payload.len() + 1) as u32).unwrap();
-        data.write(&[self.action]).unwrap();
-        data.write(&self.payload).unwrap();
-        data
-    }
+fn request<W: Write + ?Sized>(stream: &mut W, piece: usize, start: usize, length: usize) -> Result<(), io::Error> {
+    send(stream, &Message::Request {
+        index: piece as u32,
+        begin: start as u32,
+        length: length as u32
+    })
+}
 
-    pub fn recv(stream: &mut TcpStream) -> Result<GeneralMsg, io::Error> {
-        let msg_len = stream.read_u32::<BE>()?;
+const READ_CHUNK_SIZE: usize = 16384;
 
-        if msg_len == 0 {
-            //Keep alive message we call action 255 internally (TODO: This might break something?)
-            Ok(GeneralMsg {
-                action: 255,
-                payload: Vec::new()
-            })
-        } else {
-            let action = stream.read_u8()?;
-            let mut payload = vec![0; (msg_len -1) as usize];
+/** Pieces in the allowed fast set we give each peer **/
+const ALLOWED_FAST_COUNT: usize = 10;
+
+/** Allowed fast pieces we will remember from a peer **/
+const MAX_ALLOWED_FAST: usize = 64;
+
+/**
+ * The allowed fast set for a peer (BEP 6), pieces it may request while choked.
+ * Derived from the /24 of its IPv4 address and the info hash so reconnecting doesn't earn a new set.
+ */
+pub fn allowed_fast_set(ip: &IpAddr, info_hash: &[u8], num_pieces: usize, count: usize) -> Vec<usize> {
+    let ip = match ip {
+        &IpAddr::V4(ip) => ip.octets(),
+        &IpAddr::V6(_) => return Vec::new()
+    };
 
-            //Does this message have a payload?
-            if msg_len > 1 {
-                stream.read_exact(&mut payload)?;
+    let count = count.min(num_pieces);
+    let mut allowed = Vec::new();
+
+    let mut x = vec![ip[0], ip[1], ip[2], 0];
+    x.extend_from_slice(info_hash);
+
+    while allowed.len() < count {
+        let mut digest = sha1::Sha1::new();
+        digest.update(&x);
+        x = digest.digest().bytes().to_vec();
+
+        for word in x.chunks(4) {
+            if allowed.len() >= count {
+                break;
             }
 
-            Ok(GeneralMsg {
-                action: action,
-                payload: payload
-            })
+            let y = word.iter().fold(0u32, |y, &b| (y << 8) | b as u32);
+            let index = (y as usize) % num_pieces;
+
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
         }
     }
-}
 
-fn request(stream: &mut TcpStream, piece: usize, start: usize, length: usize, piece_size: usize) -> Result<(), io::Error> {
+    allowed
+}
 
-    let length = if start + length > piece_size {
-        piece_size - start
-    } else {
-        length
-    };
+/**
+ * A block request sent to the peer that has not been answered yet
+ */
+struct PendingRequest {
+    block: Block,
+    sent: Instant
+}
 
-    let mut request_data = Vec::with_capacity(12);
-    request_data.write_u32::<BE>(piece as u32);
-    request_data.write_u32::<BE>(start as u32);
-    request_data.write_u32::<BE>(length as u32);
+/** Requests from a peer we will queue before dropping new ones **/
+const MAX_PENDING_READS: usize = 250;
 
-    let msg = GeneralMsg {
-        action: 6,
-        payload: request_data
-    };
+/** Wait before asking again when the coordinator had no blocks for us **/
+const NEED_RETRY_MS: u64 = 1000;
 
-    stream.write(&msg.serialize())?;
-    Ok(())
+/**
+ * Reports a peer's state changes to its download, tagged with the peer they came from
+ */
+#[derive(Clone)]
+pub struct Reporter {
+    key: PeerKey,
+    events: channel::Sender<(PeerKey, ClientState)>
 }
 
-fn interested(stream: &mut TcpStream) -> Result<(), io::Error> {
-    stream.write(&GeneralMsg{ action: 2, payload: Vec::new() }.serialize())?;
-    Ok(())
-}
+impl Reporter {
+    pub fn new(key: PeerKey, events: channel::Sender<(PeerKey, ClientState)>) -> Reporter {
+        Reporter {
+            key: key,
+            events: events
+        }
+    }
 
-fn unchoked(stream: &mut TcpStream) -> Result<(), io::Error> {
-    stream.write(&GeneralMsg { action: 1, payload: Vec::new() }.serialize())?;
-    Ok(())
+    pub fn send(&self, state: ClientState) -> Result<(), channel::SendError<(PeerKey, ClientState)>> {
+        self.events.send((self.key, state))
+    }
 }
 
-const MAX_REQUEST_SIZE: usize = 16384;
-
 struct PeerClient { 
 
-    send: Sender<ClientState>,
-    recv: Receiver<ClientState>,
+    send: Reporter,
+    recv: channel::Receiver<ClientState>,
+
+    stream: Wire,
+
+    peer_id: Vec<u8>,
+    capabilities: Capabilities,
+    fast: bool, /* Both sides support the fast extension */
 
-    stream: TcpStream,
+    settings: Settings,
 
     piece_length: usize,
+    length: usize, /* Of the whole torrent, so the final piece's size is known */
 
     bitfield: Bitfield,
+    have: Bitfield,
 
     am_choked: bool,
     am_interested: bool,
 
+    peer_choked: bool,
+    peer_interested: bool,
+
+    pending_reads: Vec<(usize, usize, usize)>,
+    uploaded: usize,
+
+    allowed_fast: Vec<usize>, /* Pieces the peer lets us request while it chokes us */
+    our_allowed_fast: Vec<usize>, /* Pieces we serve to the peer while choking it */
+
     am_needing: bool,
+    next_need: Instant,
+
+    requests: Vec<PendingRequest>,
+    waiting_since: Option<Instant>, /* Requests have been outstanding without a block arriving since */
+    snubbed: bool,
+    download_rate: Throughput,
+    rtt: f64, /* Smoothed request round trip in seconds */
 
-    am_acquiring: bool,
-    acquiring_piece: usize,
-    acquire_step: usize,
-    waiting_piece: bool,
-    acquire_buffer: Vec<u8>
+    extensions: Vec<Box<ExtensionHandler>>, /* Our handlers, extended id n is extensions[n - 1] */
+    remote_extensions: ExtendedHandshake,
+
+    read_buffer: Vec<u8>
 }
 
 impl PeerClient {
 
     pub fn sync_ctrl(&mut self) -> bool {
-        if let Ok(msg) = self.recv.try_recv() {
-            match msg { 
-                ClientState::Want(piece) => {
-                    self.acquiring_piece = piece;
-                    self.am_acquiring = true;
-                    self.am_needing = false;
-                    self.acquire_step = 0;
-                    true
+        while let Ok(msg) = self.recv.try_recv() {
+            let ok = match msg { 
+                ClientState::Want(blocks) => self.request_blocks(blocks),
+                ClientState::Cancel(block) => {
+                    let pending = self.requests.iter().position(|r| r.block == block);
+
+                    match pending {
+                        Some(idx) => {
+                            self.requests.remove(idx);
+                            send(&mut self.stream, &Message::Cancel {
+                                index: block.piece as u32,
+                                begin: block.begin as u32,
+                                length: block.length as u32
+                            }).is_ok()
+                        },
+                        None => true
+                    }
+                },
+                ClientState::Have(piece) => self.announce_have(piece),
+                ClientState::Choke => {
+                    self.peer_choked = true;
+
+                    let (kept, dropped) = {
+                        let allowed = &self.our_allowed_fast;
+                        let fast = self.fast;
+                        self.pending_reads.drain(..).partition(|&(piece, _, _)| fast && allowed.contains(&piece))
+                    };
+
+                    self.pending_reads = kept;
+
+                    send(&mut self.stream, &Message::Choke).is_ok() && self.reject_all(dropped)
+                },
+                ClientState::Unchoke => {
+                    self.peer_choked = false;
+                    send(&mut self.stream, &Message::Unchoke).is_ok()
+                },
+                ClientState::Block(piece, begin, data) => self.serve_block(piece, begin, data),
+                ClientState::Extended(name, payload) => {
+                    match self.remote_extensions.id(name) {
+                        Some(id) => send(&mut self.stream, &Message::Extended { id: id, payload: payload }).is_ok(),
+                        None => true
+                    }
                 },
                 ClientState::Close(reason) => {
-                    self.send.send(ClientState::Close(reason));
+                    self.send.send(ClientState::Close(reason)).ok();
                     false
                 },
                 _ => {
-                    self.send.send(ClientState::Close("ctrl error".to_string()));
+                    self.send.send(ClientState::Close("ctrl error".to_string())).ok();
                     false
                 }
+            };
+
+            if !ok {
+                return false;
             }
-        } else {
-            true
         }
+
+        true
+    }
+
+    /** Tell the peer about a piece we completed and reconsider whether they still interest us **/
+    fn announce_have(&mut self, piece: usize) -> bool {
+        if self.have.set(piece).is_err() {
+            return true;
+        }
+
+        let redundant = self.settings.suppress_redundant_haves && self.bitfield.get(piece);
+
+        if !redundant {
+            if let Err(e) = send(&mut self.stream, &Message::Have(piece as u32)) {
+                self.send.send(ClientState::Close(e.to_string())).ok();
+                return false;
+            }
+        }
+
+        self.update_interest()
+    }
+
+    /** Send Interested or Not Interested if the peer's pieces we lack have changed **/
+    fn update_interest(&mut self) -> bool {
+        let interested = self.bitfield.and_not(&self.have).any();
+
+        if interested != self.am_interested {
+            let msg = if interested { Message::Interested } else { Message::NotInterested };
+
+            if let Err(e) = send(&mut self.stream, &msg) {
+                self.send.send(ClientState::Close(e.to_string())).ok();
+                return false;
+            }
+
+            self.am_interested = interested;
+        }
+
+        true
+    }
+
+    /** Send a block read from storage if the peer still wants it **/
+    fn serve_block(&mut self, piece: usize, begin: usize, data: Vec<u8>) -> bool {
+        let pending = self.pending_reads.iter().position(|&r| r == (piece, begin, data.len()));
+
+        if let Some(idx) = pending {
+            self.pending_reads.remove(idx);
+
+            let length = data.len();
+            let msg = Message::Piece {
+                index: piece as u32,
+                begin: begin as u32,
+                block: data
+            };
+
+            if let Err(e) = send(&mut self.stream, &msg) {
+                self.send.send(ClientState::Close(e.to_string())).ok();
+                return false;
+            }
+
+            self.uploaded += length;
+            self.send.send(ClientState::Uploaded(length)).ok();
+        }
+
+        true
+    }
+
+    /** Tell a fast extension peer we won't serve requests, other peers just never hear back **/
+    fn reject_all(&mut self, requests: Vec<(usize, usize, usize)>) -> bool {
+        if !self.fast {
+            return true;
+        }
+
+        for (piece, begin, length) in requests {
+            let reject = Message::Reject {
+                index: piece as u32,
+                begin: begin as u32,
+                length: length as u32
+            };
+
+            if let Err(e) = send(&mut self.stream, &reject) {
+                self.send.send(ClientState::Close(e.to_string())).ok();
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /** Queue a peer request to be read from storage, invalid or choked requests are rejected **/
+    fn queue_read(&mut self, piece: usize, begin: usize, length: usize) -> bool {
+        let valid = self.have.get(piece) && length > 0 && length <= MAX_BLOCK_LEN && begin + length <= piece_size(piece, self.piece_length, self.length);
+        let allowed = !self.peer_choked || (self.fast && self.our_allowed_fast.contains(&piece));
+
+        if !allowed || !valid || self.pending_reads.len() >= MAX_PENDING_READS {
+            println!("Rejecting request for {} {} {}", piece, begin, length);
+            return self.reject_all(vec![(piece, begin, length)]);
+        }
+
+        self.pending_reads.push((piece, begin, length));
+        self.send.send(ClientState::Read(piece, begin, length)).ok();
+        true
+    }
+
+    /**
+     * Number of requests to keep in flight, enough to cover the round trip plus
+     * request_queue_time at the measured download rate
+     */
+    fn queue_depth(&mut self) -> usize {
+        //A snubbed peer gets one request at a time until it proves itself again
+        if self.snubbed {
+            return 1;
+        }
+
+        let ahead = self.rtt + secs(self.settings.request_queue_time);
+        let depth = ((self.download_rate.rate() * ahead) / BLOCK_SIZE as f64).ceil() as usize;
+        let limit = self.remote_extensions.reqq.unwrap_or(self.settings.max_request_queue).min(self.settings.max_request_queue);
+        depth.max(self.settings.min_request_queue).min(limit.max(1))
+    }
+
+    /** Send requests for blocks the coordinator assigned us **/
+    fn request_blocks(&mut self, blocks: Vec<Block>) -> bool {
+        self.am_needing = false;
+
+        if blocks.is_empty() {
+            //Nothing to ask for isn't the peer's fault
+            if self.requests.is_empty() {
+                self.waiting_since = None;
+            }
+
+            self.next_need = Instant::now() + Duration::from_millis(NEED_RETRY_MS);
+            return true;
+        }
+
+        //While choked only allowed fast pieces can be requested
+        let (blocks, refused): (Vec<Block>, Vec<Block>) = {
+            let choked = self.am_choked;
+            let allowed = &self.allowed_fast;
+            blocks.into_iter().partition(|b| !choked || allowed.contains(&b.piece))
+        };
+
+        if !refused.is_empty() {
+            self.send.send(ClientState::Abandon(refused)).ok();
+        }
+
+        if blocks.is_empty() {
+            return true;
+        }
+
+        if self.waiting_since.is_none() {
+            self.waiting_since = Some(Instant::now());
+        }
+
+        for block in blocks {
+            if let Err(e) = request(&mut self.stream, block.piece, block.begin, block.length) {
+                self.send.send(ClientState::Close(e.to_string())).ok();
+                return false;
+            }
+
+            self.requests.push(PendingRequest {
+                block: block,
+                sent: Instant::now()
+            });
+        }
+
+        true
+    }
+
+    /** Give up on requests that have gone unanswered too long and flag a peer that has stopped sending **/
+    fn check_requests(&mut self) -> bool {
+        let timeout = self.settings.request_timeout;
+        let (expired, waiting): (Vec<PendingRequest>, Vec<PendingRequest>) = self.requests.drain(..).partition(|r| r.sent.elapsed() >= timeout);
+        self.requests = waiting;
+
+        if !expired.is_empty() {
+            for req in expired.iter() {
+                let cancel = Message::Cancel {
+                    index: req.block.piece as u32,
+                    begin: req.block.begin as u32,
+                    length: req.block.length as u32
+                };
+
+                if let Err(e) = send(&mut self.stream, &cancel) {
+                    self.send.send(ClientState::Close(e.to_string())).ok();
+                    return false;
+                }
+            }
+
+            self.send.send(ClientState::Abandon(expired.into_iter().map(|r| r.block).collect())).ok();
+        }
+
+        //Expired requests keep the clock running, only a block or a choke resets it
+        let stalled = self.waiting_since.map(|t| t.elapsed() >= self.settings.snub_timeout).unwrap_or(false);
+
+        if stalled && !self.snubbed {
+            println!("Peer snubbed us");
+            self.snubbed = true;
+            self.send.send(ClientState::Snubbed(true)).ok();
+        }
+
+        true
+    }
+
+    /** Pieces we could request while choked **/
+    fn allowed_field(&self) -> Bitfield {
+        let mut field = Bitfield::new(self.bitfield.len());
+        self.allowed_fast.iter().for_each(|&piece| { field.set(piece).ok(); });
+        field
     }
 
     pub fn update_state(&mut self) {
-        if !self.am_choked && self.am_acquiring && !self.waiting_piece {
-            if self.acquire_step < self.piece_length {
-                request(&mut self.stream, self.acquiring_piece, self.acquire_step, MAX_REQUEST_SIZE, self.piece_length);
-                self.waiting_piece = true;
-            } else {
-                self.send.send(ClientState::Commit(self.acquiring_piece, self.acquire_buffer.clone()));
-                self.am_acquiring = false;
+        let can_ask = self.am_interested && !self.am_needing && Instant::now() >= self.next_need;
+        let allowed = if self.am_choked { Some(self.allowed_field()) } else { None };
+
+        //Choked with no allowed fast pieces it has and we lack, there is nothing to ask for
+        let blocked = allowed.as_ref().map(|field| !field.and(&self.bitfield).and_not(&self.have).any()).unwrap_or(false);
+
+        if can_ask && !blocked {
+            let depth = self.queue_depth();
+
+            if self.requests.len() < depth {
+                let count = depth - self.requests.len();
+
+                self.send.send(match allowed {
+                    Some(field) => ClientState::NeedAllowed(count, field),
+                    None => ClientState::Need(count)
+                }).ok();
+
+                self.am_needing = true;
+            }
+        }
+    }
+
+    /** Pass on a block the peer sent if it answers one of our requests **/
+    fn receive_block(&mut self, piece: usize, begin: usize, data: Vec<u8>) {
+        let pending = self.requests.iter().position(|r| r.block.piece == piece && r.block.begin == begin && r.block.length == data.len());
+
+        if let Some(idx) = pending {
+            let req = self.requests.remove(idx);
+
+            self.rtt = (self.rtt * 0.875) + (secs(req.sent.elapsed()) * 0.125);
+            self.download_rate.add(data.len());
+            self.waiting_since = if self.requests.is_empty() { None } else { Some(Instant::now()) };
+
+            if self.snubbed {
+                self.snubbed = false;
+                self.send.send(ClientState::Snubbed(false)).ok();
+            }
+
+            self.send.send(ClientState::Received(req.block, data)).ok();
+        } else {
+            self.send.send(ClientState::Redundant(data.len())).ok();
+        }
+    }
+
+    /** Decode the next whole message from what has been received, if any **/
+    pub fn read_msg(&mut self) -> Result<Option<Message>, io::Error> {
+        if let Some((msg, used)) = self.decode_buffered()? {
+            self.read_buffer.drain(0..used);
+            return Ok(Some(msg));
+        }
+
+        let mut chunk = [0; READ_CHUNK_SIZE];
+
+        match self.stream.read(&mut chunk) {
+            Ok(0) => return Err(io::Error::new(UnexpectedEof, "Connection closed by peer")),
+            Ok(n) => self.read_buffer.extend_from_slice(&chunk[0..n]),
+            Err(e) => match e.kind() {
+                WouldBlock => return Ok(None),
+                _ => return Err(e)
             }
+        };
+
+        if let Some((msg, used)) = self.decode_buffered()? {
+            self.read_buffer.drain(0..used);
+            Ok(Some(msg))
+        } else {
+            Ok(None)
         }
+    }
 
-        if !self.am_choked && !self.am_needing && !self.am_acquiring {
-            self.send.send(ClientState::Need(self.bitfield.clone()));
-            self.am_needing = true;
+    fn decode_buffered(&self) -> Result<Option<(Message, usize)>, io::Error> {
+        Message::decode(&self.read_buffer).map_err(|e| io::Error::new(InvalidData, e))
+    }
+
+    /** Handle an extended message, id 0 is the handshake and others route to our handlers **/
+    fn process_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), String> {
+        if id == HANDSHAKE_ID {
+            self.remote_extensions = ExtendedHandshake::decode(payload)?;
+
+            if let Some(port) = self.remote_extensions.p {
+                self.send.send(ClientState::ListenPort(port)).ok();
+            }
+
+            println!("Extended handshake from {}", self.remote_extensions.v.as_ref().map(|v| v.as_str()).unwrap_or("unknown client"));
+            return Ok(());
         }
+
+        //Ids we never advertised are ignored rather than treated as an error
+        let handler = match self.extensions.get_mut(id as usize - 1) {
+            Some(handler) => handler,
+            None => {
+                println!("Ignoring unknown extended message id {}", id);
+                return Ok(());
+            }
+        };
+
+        if let Some(state) = handler.receive(payload)? {
+            self.send.send(state).ok();
+        }
+
+        Ok(())
     }
 
-    pub fn process_msg(&mut self, msg: GeneralMsg) -> bool {
-        match msg.action {
-            0 => /* Choke */ {
+    pub fn process_msg(&mut self, msg: Message) -> bool {
+        match msg {
+            Message::Choke => {
                 println!("Choked");
                 self.am_choked = true;
-                self.waiting_piece = false;
+                self.waiting_since = None;
+
+                //The peer discards our queue when it chokes us, hand the blocks back to the picker.
+                //Fast extension peers reject each request they drop instead
+                if !self.fast {
+                    let abandoned = self.requests.drain(..).map(|r| r.block).collect();
+                    self.send.send(ClientState::Abandon(abandoned)).ok();
+                }
             },
-            1 => /* Unchoked */ {
+            Message::Unchoke => {
                 println!("Unchoked");
                 self.am_choked = false;
             },
-            2 => /* Interested */ {
+            Message::Interested => {
                 println!("Interested");
-                self.am_interested = true;
+                self.peer_interested = true;
+                self.send.send(ClientState::Interested(true)).ok();
             },
-            3 => /* Not Interested */ {
+            Message::NotInterested => {
                 println!("Not Interested");
-                self.am_interested = false;
+                self.peer_interested = false;
+                self.send.send(ClientState::Interested(false)).ok();
             },
-            4 => /* Have */ {
-                let mut payload: &[u8] = &msg.payload;
+            Message::Have(piece) => {
+                if let Err(e) = self.bitfield.set(piece as usize) {
+                    self.send.send(ClientState::Close(format!("Bad have: {}", e))).ok();
+                    return false;
+                }
+
+                self.send.send(ClientState::Acquired(piece as usize)).ok();
+                return self.update_interest();
+            },
+            Message::Bitfield(payload) => {
+                match Bitfield::from_bytes(&payload, self.bitfield.len()) {
+                    Ok(field) => self.bitfield = field,
+                    Err(e) => {
+                        self.send.send(ClientState::Close(format!("Bad bitfield: {}", e))).ok();
+                        return false;
+                    }
+                }
 
-                if payload.len() == 4 {
-                    let piece = payload.read_u32::<BE>().unwrap();
-                    self.bitfield.set(piece as usize);
+                self.send.send(ClientState::Available(self.bitfield.clone())).ok();
+                return self.update_interest();
+            },
+            Message::HaveAll | Message::HaveNone if self.fast => {
+                if msg == Message::HaveAll {
+                    self.bitfield.set_all();
                 } else {
-                    println!("Have - Bad payload");
+                    self.bitfield.clear_all();
+                }
+
+                self.send.send(ClientState::Available(self.bitfield.clone())).ok();
+                return self.update_interest();
+            },
+            Message::Piece { index, begin, block } => {
+                self.receive_block(index as usize, begin as usize, block);
+            },
+            Message::Request { index, begin, length } => {
+                return self.queue_read(index as usize, begin as usize, length as usize);
+            },
+            Message::Reject { index, begin, length } if self.fast => {
+                //Hand the block straight back to the picker rather than waiting for it to time out
+                let rejected = self.requests.iter().position(|r| r.block.piece == index as usize && r.block.begin == begin as usize && r.block.length == length as usize);
+
+                if let Some(idx) = rejected {
+                    let req = self.requests.remove(idx);
+                    self.send.send(ClientState::Abandon(vec![req.block])).ok();
                 }
             },
-            5 => /* Bitfield */ {
-                self.bitfield = Bitfield::new(msg.payload);
-                interested(&mut self.stream);
-                unchoked(&mut self.stream);
+            Message::AllowedFast(piece) if self.fast => {
+                let piece = piece as usize;
+
+                if piece < self.bitfield.len() && !self.allowed_fast.contains(&piece) && self.allowed_fast.len() < MAX_ALLOWED_FAST {
+                    self.allowed_fast.push(piece);
+                }
             },
-            7 => /* Piece */ {
-                let mut payload: &[u8] = &msg.payload;
-                let index = payload.read_u32::<BE>().unwrap();
-                if index as usize == self.acquiring_piece {
-                    let begin = payload.read_u32::<BE>().unwrap() as usize;
-                    let length = payload.len();
-                    let mut buffer_lock = &mut self.acquire_buffer[begin..begin + length];
-                    copy(&mut payload, &mut buffer_lock).unwrap();
-                    self.acquire_step = begin + length;
-                    self.waiting_piece = false;
+            Message::Suggest(piece) if self.fast => {
+                if (piece as usize) < self.bitfield.len() {
+                    self.send.send(ClientState::Suggested(piece as usize)).ok();
                 }
             },
-            8 => {
-                println!("Cancel Received");
+            Message::Cancel { index, begin, length } => {
+                let cancelled = (index as usize, begin as usize, length as usize);
+                self.pending_reads.retain(|&r| r != cancelled);
+            },
+            Message::Extended { id, payload } => {
+                if let Err(e) = self.process_extended(id, &payload) {
+                    self.send.send(ClientState::Close(e)).ok();
+                    return false;
+                }
             },
-            255 => /* Keep Alive */ {},
-            _ => {
-                self.send.send(ClientState::Close(format!("Unhandled action {}", msg.action))).unwrap();
-                return false
+            Message::KeepAlive => {},
+            Message::Unknown(id) => {
+                println!("Ignoring unknown message id {}", id);
+            },
+            Message::Port(_) => {
+                //Our DHT node finds its own contacts
+            },
+            msg @ Message::Suggest(_) | msg @ Message::HaveAll | msg @ Message::HaveNone | msg @ Message::Reject { .. } | msg @ Message::AllowedFast(_) => {
+                println!("Ignoring {:?} from a peer without the fast extension", msg);
             }
         };
         
@@ -267,93 +782,779 @@ impl PeerClient {
 
 }
 
-pub fn peer_client(torrent: &Info, peer: &PeerAddress) -> (Sender<ClientState>, Receiver<ClientState>) {
-    let torrent = torrent.clone();
-    let peer = peer.clone();
+impl PeerClient {
+    fn new(torrent: &Info, settings: Settings, handshake: &HandshakeMsg, have: Bitfield, stream: Wire, send: Reporter, recv: channel::Receiver<ClientState>) -> PeerClient {
+        let fast = handshake.capabilities().fast;
+
+        //Let a choked fast extension peer start on a few pieces
+        let our_allowed_fast = match stream.stream().peer_addr() {
+            Ok(addr) if fast => allowed_fast_set(&addr.ip(), &torrent.info_hash, torrent.pieces.len(), ALLOWED_FAST_COUNT),
+            _ => Vec::new()
+        };
+
+        PeerClient {
+            send: send,
+            recv: recv,
 
-    let (thread_send, main_recv): (Sender<ClientState>, Receiver<ClientState>) = mpsc::channel();
-    let (main_send, thread_recv): (Sender<ClientState>, Receiver<ClientState>) = mpsc::channel();
+            stream: stream,
 
-    thread::spawn(move || {
-        let client = TcpStream::connect((peer.ip, peer.port));
+            peer_id: handshake.peer_id.clone(),
+            capabilities: handshake.capabilities(),
+            fast: fast,
 
-        if let Err(e) = client {
-            thread_send.send(ClientState::Close(e.to_string()));
-            return;
-        }
+            settings: settings,
+
+            piece_length: torrent.piece_length,
+            length: torrent.length(),
+
+            bitfield: Bitfield::new(torrent.pieces.len()),
+            have: have,
 
-        let mut client = client.unwrap();
+            am_choked: true,
+            am_interested: false,
+
+            peer_choked: true,
+            peer_interested: false,
+
+            pending_reads: Vec::new(),
+            uploaded: 0,
+
+            allowed_fast: Vec::new(),
+            our_allowed_fast: our_allowed_fast,
+
+            am_needing: false,
+            next_need: Instant::now(),
 
-        client.set_read_timeout(Some(time::Duration::from_millis(5000)));
-        client.set_write_timeout(Some(time::Duration::from_millis(5000)));
-       
-        let handshake = HandshakeMsg {
-            pstr: "BitTorrent protocol".to_string(),
-            info_hash: torrent.info_hash.clone(),
-            peer_id: torrent.peer_id.clone()
+            requests: Vec::new(),
+            waiting_since: None,
+            snubbed: false,
+            download_rate: Throughput::new(),
+            rtt: 0.0,
+
+            extensions: handlers(torrent),
+            remote_extensions: ExtendedHandshake::default(),
+
+            read_buffer: Vec::new()
+        }
+    }
+
+    /** Send our pieces, allowed fast set and extended handshake, which come before any other message **/
+    fn advertise(&mut self, listen_port: u16) -> bool {
+        //Fast extension peers can be told we have everything or nothing without a bitfield
+        let advertisement = if self.fast && self.have.is_complete() {
+            Some(Message::HaveAll)
+        } else if self.fast && !self.have.any() {
+            Some(Message::HaveNone)
+        } else if self.have.any() {
+            Some(Message::Bitfield(self.have.to_bytes()))
+        } else {
+            None
         };
 
-        if let Err(_) = client.write(&handshake.serialize()) {
-            thread_send.send(ClientState::Close("Error sending BT peer-wire handshake".to_string()));
-            return;
+        let mut intro: Vec<Message> = advertisement.into_iter().collect();
+        intro.extend(self.our_allowed_fast.iter().map(|&piece| Message::AllowedFast(piece as u32)));
+
+        if self.capabilities.extension_protocol {
+            let ours = ExtendedHandshake::ours(&self.extensions, listen_port, MAX_PENDING_READS);
+            intro.push(Message::Extended { id: HANDSHAKE_ID, payload: ours.encode() });
         }
 
-        let handshake_recv = HandshakeMsg::recv(&mut client);
-    
-        if let Err(e) = handshake_recv {
-            thread_send.send(ClientState::Close(e.to_string()));
-            return;
+        for msg in intro {
+            if let Err(e) = send(&mut self.stream, &msg) {
+                self.send.send(ClientState::Close(e.to_string())).ok();
+                return false;
+            }
         }
 
-        
+        true
+    }
 
-        client.set_read_timeout(Some(time::Duration::from_millis(500)));
-        client.set_write_timeout(Some(time::Duration::from_millis(500)));
+    /** Take in everything the socket has and act on each whole message **/
+    fn receive(&mut self) -> bool {
+        if let Err(e) = self.stream.receive() {
+            self.send.send(ClientState::Close(e.to_string())).ok();
+            return false;
+        }
 
-        let mut client = PeerClient {
-            send: thread_send,
-            recv: thread_recv,
+        loop {
+            match self.read_msg() {
+                Ok(Some(msg)) => {
+                    if !self.process_msg(msg) {
+                        return false;
+                    }
+                },
+                Ok(None) => return true,
+                Err(e) => {
+                    self.send.send(ClientState::Close(e.to_string())).ok();
+                    return false;
+                }
+            }
+        }
+    }
 
-            stream: client,
-            piece_length: torrent.piece_length,
+    /** Ask for more blocks if we can, then send everything queued as the socket allows **/
+    fn flush(&mut self) -> bool {
+        self.update_state();
 
-            bitfield: Bitfield::new((0..torrent.pieces.len() / 8).map(|_| 0).collect()),
+        if let Err(e) = self.stream.send() {
+            self.send.send(ClientState::Close(e.to_string())).ok();
+            return false;
+        }
 
-            am_choked: true,
-            am_interested: false,
+        true
+    }
+}
 
-            am_acquiring: false,
-            am_needing: false,
+/** Time allowed to negotiate encryption and exchange handshakes once connected **/
+const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
 
-            acquiring_piece: 0,
-            acquire_step: 0,
-            waiting_piece: false,
-            acquire_buffer: vec![0; torrent.piece_length]
-        };
+/** How often outstanding requests are checked and an idle peer asks again for blocks **/
+const TICK_MS: u64 = 1000;
+
+/** Sources a connection registers with the event loop **/
+const SOURCE_STREAM: usize = 0;
+const SOURCE_CONTROL: usize = 1;
+
+enum Phase {
+    Connecting(Vec<Transport>), /* Transports left to try if this attempt fails */
+    Negotiating(Negotiation),
+    Handshaking, /* Our handshake is sent, waiting for the peer's */
+    Accepted(HandshakeMsg), /* An incoming connection the peer server read the handshake of */
+    Advertising(HandshakeMsg), /* Waiting for the coordinator to take the peer on and send our pieces */
+    Running(PeerClient)
+}
+
+/**
+ * One peer connection on the event loop, from connecting through encryption and the handshakes
+ * to running the peer-wire state machine
+ */
+struct PeerConnection {
+    torrent: Info,
+    settings: Settings,
+    server: PeerServer,
+    peer: PeerAddress,
+    encryption: EncryptionPolicy, /* Lowered to disabled to retry in plaintext */
+    report: Reporter,
+    control: Option<channel::Receiver<ClientState>>, /* Both move to the client once running */
+    wire: Option<Wire>,
+    phase: Phase,
+    _slot: ConnectionSlot
+}
+
+impl PeerConnection {
+    fn our_handshake(&self) -> Vec<u8> {
+        HandshakeMsg::new(&self.torrent.info_hash, &self.torrent.peer_id, Capabilities::ours()).serialize()
+    }
+
+    /** Stop waiting on the current stream and close it **/
+    fn drop_wire(&mut self, ctx: &mut Context) {
+        if let Some(wire) = self.wire.take() {
+            ctx.deregister(wire.stream().evented()).ok();
+        }
+    }
+
+    /** Start the next connection attempt, giving up once every transport has failed **/
+    fn dial(&mut self, ctx: &mut Context) -> Result<bool, String> {
+        self.drop_wire(ctx);
+        let mut reason = "Connection failed".to_string();
 
         loop {
+            let transport = match self.phase {
+                Phase::Connecting(ref mut transports) if !transports.is_empty() => transports.remove(0),
+                _ => return Err(reason)
+            };
+
+            match self.server.connect(&self.peer, transport) {
+                Ok(stream) => {
+                    ctx.register(SOURCE_STREAM, stream.evented(), Ready::readable() | Ready::writable()).map_err(|e| e.to_string())?;
+                    ctx.set_timeout(self.settings.connect_timeout);
+                    self.wire = Some(Wire::new(stream));
+                    return Ok(true);
+                },
+                Err(e) => reason = e.to_string()
+            }
+        }
+    }
+
+    /** Take over an incoming connection and answer its handshake **/
+    fn accepted(&mut self, ctx: &mut Context, handshake: HandshakeMsg) -> Result<bool, String> {
+        let reply = self.our_handshake();
+        ctx.set_timeout(Duration::from_millis(HANDSHAKE_TIMEOUT_MS));
+
+        {
+            let wire = self.wire.as_mut().unwrap();
+            ctx.register(SOURCE_STREAM, wire.stream().evented(), Ready::readable() | Ready::writable()).map_err(|e| e.to_string())?;
+            wire.write_all(&reply).map_err(|e| e.to_string())?;
+        }
+
+        self.handshaken(handshake)
+    }
+
+    /** Check the peer's handshake and ask the coordinator to take the peer on **/
+    fn handshaken(&mut self, handshake: HandshakeMsg) -> Result<bool, String> {
+        handshake.validate(&self.torrent.info_hash, &self.torrent.peer_id)?;
+        self.report.send(ClientState::Connected(handshake.peer_id.clone())).ok();
+        self.phase = Phase::Advertising(handshake);
+        Ok(true)
+    }
+
+    /** Move encryption and the handshakes along with whatever has been received **/
+    fn handshake(&mut self) -> Result<bool, String> {
+        let ours = self.our_handshake();
+
+        let received = {
+            let wire = self.wire.as_mut().unwrap();
+            wire.receive().map_err(|e| e.to_string())?;
+
+            let negotiated = match self.phase {
+                Phase::Negotiating(ref mut negotiation) => {
+                    let mut output = Vec::new();
+                    let negotiated = negotiation.advance(wire.input(), &mut output)?;
+                    wire.write_all(&output).map_err(|e| e.to_string())?;
+                    negotiated
+                },
+                _ => None
+            };
 
-            if !client.sync_ctrl() {
-                return;
+            if let Some(negotiated) = negotiated {
+                if let Negotiated::Rc4(encrypt, decrypt) = negotiated {
+                    wire.set_ciphers(encrypt, decrypt);
+                }
+
+                wire.write_all(&ours).map_err(|e| e.to_string())?;
+                self.phase = Phase::Handshaking;
             }
 
-            client.update_state();            
+            let received = match self.phase {
+                Phase::Handshaking => HandshakeMsg::decode(wire.input()).map(|(handshake, used)| {
+                    wire.input().drain(0..used);
+                    handshake
+                }),
+                _ => None
+            };
+
+            wire.send().map_err(|e| e.to_string())?;
+
+            if received.is_none() && wire.is_eof() {
+                return Err("Connection closed during handshake".to_string());
+            }
 
-            let next = GeneralMsg::recv(&mut client.stream);
+            received
+        };
 
-            if let Ok(msg) = next {
-                 client.process_msg(msg);
-            } else if let Err(e) = next {
-                match e.kind() {
-                    WouldBlock | TimedOut => {},
-                    _ => {
-                        client.send.send(ClientState::Close(e.to_string())).unwrap();
-                        return;
+        match received {
+            Some(handshake) => self.handshaken(handshake),
+            None => Ok(true)
+        }
+    }
+
+    /** The coordinator took the peer on, start the peer-wire state machine **/
+    fn run(&mut self, ctx: &mut Context, have: Bitfield) -> Result<bool, String> {
+        let handshake = match mem::replace(&mut self.phase, Phase::Handshaking) {
+            Phase::Advertising(handshake) => handshake,
+            _ => return Err("Advertisement before handshake".to_string())
+        };
+
+        let mut client = PeerClient::new(&self.torrent, self.settings.clone(), &handshake, have, self.wire.take().unwrap(), self.report.clone(), self.control.take().unwrap());
+        ctx.set_timeout(Duration::from_millis(TICK_MS));
+
+        //Anything the peer sent while we waited on the coordinator is already buffered
+        let running = client.advertise(self.server.port()) && client.sync_ctrl() && client.receive() && client.flush();
+        self.phase = Phase::Running(client);
+        Ok(running)
+    }
+
+    fn ready(&mut self, ctx: &mut Context) -> Result<bool, String> {
+        let connecting = match self.phase {
+            Phase::Connecting(_) => true,
+            _ => false
+        };
+
+        if connecting {
+            match self.wire.as_ref().unwrap().stream().is_connected() {
+                Ok(false) => return Ok(true),
+                Ok(true) => {
+                    ctx.set_timeout(Duration::from_millis(HANDSHAKE_TIMEOUT_MS));
+                    self.phase = Phase::Negotiating(Negotiation::outgoing(&self.torrent.info_hash, self.encryption));
+                },
+                Err(_) => return self.dial(ctx)
+            }
+        }
+
+        if let Phase::Running(ref mut client) = self.phase {
+            return Ok(client.receive() && client.flush());
+        }
+
+        self.handshake()
+    }
+
+    fn control(&mut self, ctx: &mut Context) -> Result<bool, String> {
+        //The channel only signals again once emptied, so everything queued is taken now
+        loop {
+            if let Phase::Running(ref mut client) = self.phase {
+                return Ok(client.sync_ctrl() && client.flush());
+            }
+
+            let msg = match self.control.as_ref().unwrap().try_recv() {
+                Ok(msg) => msg,
+                Err(_) => return Ok(true)
+            };
+
+            match msg {
+                ClientState::Advertise(have) => {
+                    if !self.run(ctx, have)? {
+                        return Ok(false);
                     }
-                };
+                },
+                ClientState::Close(reason) => {
+                    self.report.send(ClientState::Close(reason)).ok();
+                    return Ok(false);
+                },
+                _ => return Err("No advertisement from coordinator".to_string())
             }
         }
-    });
+    }
+
+    fn timeout(&mut self, ctx: &mut Context) -> Result<bool, String> {
+        match self.phase {
+            Phase::Connecting(_) => self.dial(ctx),
+            Phase::Running(ref mut client) => {
+                ctx.set_timeout(Duration::from_millis(TICK_MS));
+                Ok(client.check_requests() && client.flush())
+            },
+            _ => Err("Handshake timed out".to_string())
+        }
+    }
+
+    /** Report a failure, unless it was encryption that failed and we can retry in plaintext **/
+    fn settle(&mut self, ctx: &mut Context, result: Result<bool, String>) -> bool {
+        let reason = match result {
+            Ok(running) => return running,
+            Err(reason) => reason
+        };
+
+        let negotiating = match self.phase {
+            Phase::Negotiating(_) => true,
+            _ => false
+        };
+
+        if negotiating && self.encryption == EncryptionPolicy::Enabled {
+            self.encryption = EncryptionPolicy::Disabled;
+            self.phase = Phase::Connecting(self.server.transports(self.settings.transport));
+            let retry = self.dial(ctx);
+            return self.settle(ctx, retry);
+        }
 
-    (main_send, main_recv)
+        self.report.send(ClientState::Close(reason)).ok();
+        false
+    }
+}
+
+impl Handler for PeerConnection {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        if ctx.register(SOURCE_CONTROL, self.control.as_ref().unwrap(), Ready::readable()).is_err() {
+            self.report.send(ClientState::Close("Could not register with the event loop".to_string())).ok();
+            return false;
+        }
+
+        let started = match mem::replace(&mut self.phase, Phase::Handshaking) {
+            Phase::Accepted(handshake) => self.accepted(ctx, handshake),
+            phase => {
+                self.phase = phase;
+                self.dial(ctx)
+            }
+        };
+
+        self.settle(ctx, started)
+    }
+
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        let result = match event {
+            Event::Ready(SOURCE_CONTROL, _) => self.control(ctx),
+            Event::Ready(..) => self.ready(ctx),
+            Event::Timeout => self.timeout(ctx)
+        };
+
+        self.settle(ctx, result)
+    }
+}
+
+/**
+ * Connect out to a peer, the slot is held for the lifetime of the connection. What happens on
+ * the connection is reported through report, and the returned sender controls it.
+ */
+pub fn peer_client(events: &EventLoop, torrent: &Info, settings: &Settings, server: &PeerServer, peer: &PeerAddress, slot: ConnectionSlot, report: Reporter) -> channel::Sender<ClientState> {
+    let (send, recv) = channel::channel();
+
+    events.spawn(Box::new(PeerConnection {
+        torrent: torrent.clone(),
+        settings: settings.clone(),
+        server: server.clone(),
+        peer: peer.clone(),
+        encryption: settings.encryption,
+        report: report,
+        control: Some(recv),
+        wire: None,
+        phase: Phase::Connecting(server.transports(settings.transport)),
+        _slot: slot
+    }));
+
+    send
+}
+
+/**
+ * Take over a connection accepted by the peer server, whose handshake has already been read
+ */
+pub fn peer_client_incoming(events: &EventLoop, torrent: &Info, settings: &Settings, server: &PeerServer, incoming: Incoming, report: Reporter) -> channel::Sender<ClientState> {
+    let Incoming { wire, addr, handshake, slot } = incoming;
+    let (send, recv) = channel::channel();
+
+    events.spawn(Box::new(PeerConnection {
+        torrent: torrent.clone(),
+        settings: settings.clone(),
+        server: server.clone(),
+        peer: addr,
+        encryption: settings.encryption,
+        report: report,
+        control: Some(recv),
+        wire: Some(wire),
+        phase: Phase::Accepted(handshake),
+        _slot: slot
+    }));
+
+    send
+}
+
+#[cfg(test)]
+mod tests {
+    use peer_client::{PeerClient, HandshakeMsg, Capabilities, ClientState, Reporter, allowed_fast_set, peer_client, peer_client_incoming};
+    use peer_server::peer_server;
+    use stream::Wire;
+    use picker::{Block, BLOCK_SIZE};
+    use peer_message::Message;
+    use mio::net::TcpStream;
+    use std::net;
+    use std::net::TcpListener;
+    use std::io::Read;
+    use event_loop::{event_loop, recv_timeout};
+    use torrent::{Info, FileInfo};
+    use tracker::PeerAddress;
+    use settings::Settings;
+    use bitfield::Bitfield;
+    use mse::EncryptionPolicy;
+    use mio_extras::channel;
+    use std::time::Duration;
+
+    fn info(peer_id: u8) -> Info {
+        Info {
+            name: "test".to_string(),
+            announce: String::new(),
+            piece_length: 16384,
+            pieces: vec![vec![0; 20]; 4],
+            files: vec![FileInfo { path: "test".to_string(), length: 65536 }],
+            info_hash: vec![7; 20],
+            peer_id: vec![peer_id; 20],
+            private: false
+        }
+    }
+
+    /** A client with every piece talking to a plain socket, along with its report and control channels **/
+    fn client(settings: Settings, fast: bool) -> (PeerClient, net::TcpStream, channel::Receiver<(usize, ClientState)>, channel::Sender<ClientState>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+        let (remote, _) = listener.accept().unwrap();
+        remote.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+        let mut have = Bitfield::new(4);
+        have.set_all();
+
+        let handshake = HandshakeMsg::new(&[7; 20], &[2; 20], Capabilities { fast: fast, ..Capabilities::default() });
+        let (report, events) = channel::channel();
+        let (control, recv) = channel::channel();
+        let client = PeerClient::new(&info(1), settings, &handshake, have, Wire::new(Box::new(stream)), Reporter::new(1, report), recv);
+        (client, remote, events, control)
+    }
+
+    /** Everything the client has written to the peer so far **/
+    fn sent(client: &mut PeerClient, remote: &mut net::TcpStream) -> Vec<Message> {
+        client.stream.send().unwrap();
+
+        let mut data = Vec::new();
+        let mut chunk = [0; 65536];
+
+        while let Ok(n) = remote.read(&mut chunk) {
+            if n == 0 {
+                break;
+            }
+
+            data.extend_from_slice(&chunk[0..n]);
+        }
+
+        let mut msgs = Vec::new();
+        let mut buf = &data[..];
+
+        while let Ok(Some((msg, used))) = Message::decode(buf) {
+            msgs.push(msg);
+            buf = &buf[used..];
+        }
+
+        msgs
+    }
+
+    #[test]
+    fn requests_capped_at_queue_depth() {
+        let mut settings = Settings::default();
+        settings.request_queue_time = Duration::from_millis(0);
+
+        let (mut client, _remote, events, control) = client(settings, false);
+
+        client.am_interested = true;
+        client.am_choked = false;
+
+        let block = |piece| Block { piece: piece, begin: 0, length: BLOCK_SIZE };
+
+        //With no rate measured yet the minimum queue is asked for
+        client.update_state();
+
+        match events.try_recv() {
+            Ok((1, ClientState::Need(2))) => {},
+            _ => panic!("Expected a request for two blocks")
+        }
+
+        control.send(ClientState::Want(vec![block(0), block(1)])).unwrap();
+        assert!(client.sync_ctrl());
+        assert_eq!(client.requests.len(), 2);
+
+        //A full queue asks for nothing more
+        client.update_state();
+        assert!(events.try_recv().is_err());
+
+        //Each block that arrives frees a slot to be refilled
+        client.receive_block(0, 0, vec![0; BLOCK_SIZE]);
+
+        match events.try_recv() {
+            Ok((1, ClientState::Received(received, _))) => assert_eq!(received, block(0)),
+            _ => panic!("Expected the block to be passed on")
+        }
+
+        client.update_state();
+
+        match events.try_recv() {
+            Ok((1, ClientState::Need(1))) => {},
+            _ => panic!("Expected the queue to be refilled")
+        }
+
+        assert_eq!(client.requests.len(), 1);
+    }
+
+    #[test]
+    fn slow_requests_abandoned_and_peer_snubbed() {
+        let mut settings = Settings::default();
+        settings.request_timeout = Duration::from_millis(0);
+        settings.snub_timeout = Duration::from_millis(0);
+
+        let (mut client, mut remote, events, control) = client(settings, false);
+        client.am_interested = true;
+        client.am_choked = false;
+
+        let block = |piece| Block { piece: piece, begin: 0, length: BLOCK_SIZE };
+
+        control.send(ClientState::Want(vec![block(0)])).unwrap();
+        assert!(client.sync_ctrl());
+        assert!(client.check_requests());
+
+        match events.try_recv() {
+            Ok((1, ClientState::Abandon(blocks))) => assert_eq!(blocks, vec![block(0)]),
+            _ => panic!("Expected the request to be abandoned")
+        }
+
+        match events.try_recv() {
+            Ok((1, ClientState::Snubbed(true))) => {},
+            _ => panic!("Expected the peer to be snubbed")
+        }
+
+        assert!(client.requests.is_empty());
+        assert_eq!(client.queue_depth(), 1);
+
+        //A block that does arrive lifts the snub
+        control.send(ClientState::Want(vec![block(1)])).unwrap();
+        assert!(client.sync_ctrl());
+        client.receive_block(1, 0, vec![0; BLOCK_SIZE]);
+
+        match events.try_recv() {
+            Ok((1, ClientState::Snubbed(false))) => {},
+            _ => panic!("Expected the snub to be lifted")
+        }
+
+        let cancel = Message::Cancel { index: 0, begin: 0, length: BLOCK_SIZE as u32 };
+        assert!(sent(&mut client, &mut remote).contains(&cancel));
+    }
+
+    #[test]
+    fn serves_requested_blocks() {
+        let (mut client, mut remote, events, control) = client(Settings::default(), false);
+        let block = vec![5; BLOCK_SIZE];
+
+        control.send(ClientState::Unchoke).unwrap();
+        assert!(client.sync_ctrl());
+
+        //Each request is read from storage, a cancelled one is never sent
+        for piece in 0..2 {
+            assert!(client.process_msg(Message::Request { index: piece, begin: 0, length: BLOCK_SIZE as u32 }));
+
+            match events.try_recv() {
+                Ok((1, ClientState::Read(read, 0, BLOCK_SIZE))) => assert_eq!(read, piece as usize),
+                _ => panic!("Expected a read")
+            }
+        }
+
+        assert!(client.process_msg(Message::Cancel { index: 1, begin: 0, length: BLOCK_SIZE as u32 }));
+
+        control.send(ClientState::Block(0, 0, block.clone())).unwrap();
+        control.send(ClientState::Block(1, 0, block.clone())).unwrap();
+        assert!(client.sync_ctrl());
+
+        match events.try_recv() {
+            Ok((1, ClientState::Uploaded(BLOCK_SIZE))) => {},
+            _ => panic!("Expected the upload to be counted")
+        }
+
+        assert!(events.try_recv().is_err());
+        assert_eq!(client.uploaded, BLOCK_SIZE);
+        assert_eq!(sent(&mut client, &mut remote), vec![Message::Unchoke, Message::Piece { index: 0, begin: 0, block: block }]);
+    }
+
+    #[test]
+    fn choked_requests_rejected_only_for_fast_peers() {
+        let request = Message::Request { index: 0, begin: 0, length: BLOCK_SIZE as u32 };
+        let reject = Message::Reject { index: 0, begin: 0, length: BLOCK_SIZE as u32 };
+
+        //Other peers are never answered
+        let (mut plain, mut remote, events, _control) = client(Settings::default(), false);
+        assert!(plain.process_msg(request.clone()));
+        assert!(events.try_recv().is_err());
+        assert!(sent(&mut plain, &mut remote).is_empty());
+
+        //With no allowed fast pieces a fast peer is rejected straight away
+        let (mut fast, mut fast_remote, fast_events, fast_control) = client(Settings::default(), true);
+        fast.our_allowed_fast.clear();
+        assert!(fast.process_msg(request.clone()));
+        assert!(fast_events.try_recv().is_err());
+        assert_eq!(sent(&mut fast, &mut fast_remote), vec![reject.clone()]);
+
+        //Reads queued before a choke are rejected by it
+        fast_control.send(ClientState::Unchoke).unwrap();
+        assert!(fast.sync_ctrl());
+        assert!(fast.process_msg(request));
+        assert!(fast_events.try_recv().is_ok());
+
+        fast_control.send(ClientState::Choke).unwrap();
+        assert!(fast.sync_ctrl());
+        assert!(fast.pending_reads.is_empty());
+        assert_eq!(sent(&mut fast, &mut fast_remote), vec![Message::Unchoke, Message::Choke, reject]);
+    }
+
+    #[test]
+    fn haves_skip_peers_with_the_piece() {
+        let (mut client, mut remote, _events, control) = client(Settings::default(), false);
+        client.have = Bitfield::new(4);
+
+        assert!(client.process_msg(Message::Have(0)));
+        assert!(client.process_msg(Message::Have(1)));
+
+        //Only a piece the peer lacks is announced, but every one we gain may end our interest
+        for piece in 0..3 {
+            control.send(ClientState::Have(piece)).unwrap();
+            assert!(client.sync_ctrl());
+        }
+
+        assert!(!client.am_interested);
+        assert_eq!(sent(&mut client, &mut remote), vec![Message::Interested, Message::NotInterested, Message::Have(2)]);
+    }
+
+    #[test]
+    fn encrypted_peers_meet_over_loopback() {
+        let events = event_loop(2).unwrap();
+        let settings = Settings::default();
+        let seed = peer_server(&events, 0, EncryptionPolicy::Forced).unwrap();
+        let leech = peer_server(&events, 0, EncryptionPolicy::Forced).unwrap();
+        let incoming = seed.register(&[7; 20]);
+
+        let seed_addr = PeerAddress { ip: "127.0.0.1".parse().unwrap(), port: seed.port() };
+        let (report, leech_events) = channel::channel();
+        let leech_control = peer_client(&events, &info(1), &settings, &leech, &seed_addr, leech.acquire().unwrap(), Reporter::new(1, report));
+
+        let accepted = recv_timeout(&incoming, Duration::from_secs(5)).unwrap();
+        let (report, seed_events) = channel::channel();
+        let seed_control = peer_client_incoming(&events, &info(2), &settings, &seed, accepted, Reporter::new(2, report));
+
+        match recv_timeout(&leech_events, Duration::from_secs(5)) {
+            Some((1, ClientState::Connected(peer_id))) => assert_eq!(peer_id, vec![2; 20]),
+            _ => panic!("Leech did not connect")
+        }
+
+        match recv_timeout(&seed_events, Duration::from_secs(5)) {
+            Some((2, ClientState::Connected(peer_id))) => assert_eq!(peer_id, vec![1; 20]),
+            _ => panic!("Seed did not connect")
+        }
+
+        let mut all = Bitfield::new(4);
+        all.set_all();
+        seed_control.send(ClientState::Advertise(all)).unwrap();
+        leech_control.send(ClientState::Advertise(Bitfield::new(4))).unwrap();
+
+        //The seed's advertisement reaches the leech, which becomes interested
+        loop {
+            match recv_timeout(&leech_events, Duration::from_secs(5)) {
+                Some((_, ClientState::Available(has))) => {
+                    assert!(has.is_complete());
+                    break;
+                },
+                Some((_, ClientState::Close(reason))) => panic!("Leech closed because {}", reason),
+                Some(_) => {},
+                None => panic!("Nothing advertised")
+            }
+        }
+
+        loop {
+            match recv_timeout(&seed_events, Duration::from_secs(5)) {
+                Some((_, ClientState::Interested(true))) => break,
+                Some((_, ClientState::Close(reason))) => panic!("Seed closed because {}", reason),
+                Some(_) => {},
+                None => panic!("Leech never became interested")
+            }
+        }
+    }
+
+    #[test]
+    fn capabilities_round_trip() {
+        let caps = Capabilities { extension_protocol: true, fast: true, dht: false };
+        let reserved = caps.to_reserved();
+        assert_eq!(reserved, [0, 0, 0, 0, 0, 0x10, 0, 0x04]);
+        assert_eq!(Capabilities::from_reserved(&reserved), caps);
+    }
+
+    #[test]
+    fn validate_handshake() {
+        let ours = HandshakeMsg::new(&[1; 20], &[2; 20], Capabilities::default());
+        let theirs = HandshakeMsg::new(&[1; 20], &[3; 20], Capabilities::default());
+        assert!(theirs.validate(&ours.info_hash, &ours.peer_id).is_ok());
+        assert!(ours.validate(&ours.info_hash, &ours.peer_id).is_err());
+        assert!(theirs.validate(&[9; 20], &ours.peer_id).is_err());
+
+        let mut other_protocol = HandshakeMsg::new(&[1; 20], &[3; 20], Capabilities::default());
+        other_protocol.pstr = vec![0xff; 19];
+        assert!(other_protocol.validate(&ours.info_hash, &ours.peer_id).is_err());
+    }
+
+    #[test]
+    fn allowed_fast_bep6_vectors() {
+        let ip = "80.4.4.200".parse().unwrap();
+        assert_eq!(allowed_fast_set(&ip, &[0xaa; 20], 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(&ip, &[0xaa; 20], 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+
+        //The last octet is ignored and there are never more than num_pieces
+        assert_eq!(allowed_fast_set(&"80.4.4.1".parse().unwrap(), &[0xaa; 20], 1313, 7), allowed_fast_set(&ip, &[0xaa; 20], 1313, 7));
+        assert_eq!(allowed_fast_set(&ip, &[0xaa; 20], 3, 10).len(), 3);
+    }
 }
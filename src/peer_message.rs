@@ -0,0 +1,282 @@
+/**
+ * Peer-wire message codec, frames are a 4 byte big-endian length followed by a message id and payload
+ */
+
+use byteorder::{BE, WriteBytesExt, ReadBytesExt};
+
+/** Largest frame (excluding the length prefix) we will accept, bounds bitfields and pieces **/
+pub const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/** Largest block we will request or serve **/
+pub const MAX_BLOCK_LEN: usize = 1 << 17;
+
+const LEN_SIZE: usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16),
+
+    /* Fast extension (BEP 6) */
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject { index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+
+    /* Extension protocol (BEP 10), id 0 is the extended handshake */
+    Extended { id: u8, payload: Vec<u8> },
+
+    /* An id we don't support, the payload is dropped */
+    Unknown(u8)
+}
+
+impl Message {
+
+    fn id(&self) -> Option<u8> {
+        match self {
+            &Message::KeepAlive => None,
+            &Message::Choke => Some(0),
+            &Message::Unchoke => Some(1),
+            &Message::Interested => Some(2),
+            &Message::NotInterested => Some(3),
+            &Message::Have(_) => Some(4),
+            &Message::Bitfield(_) => Some(5),
+            &Message::Request { .. } => Some(6),
+            &Message::Piece { .. } => Some(7),
+            &Message::Cancel { .. } => Some(8),
+            &Message::Port(_) => Some(9),
+            &Message::Suggest(_) => Some(13),
+            &Message::HaveAll => Some(14),
+            &Message::HaveNone => Some(15),
+            &Message::Reject { .. } => Some(16),
+            &Message::AllowedFast(_) => Some(17),
+            &Message::Extended { .. } => Some(20),
+            &Message::Unknown(id) => Some(id)
+        }
+    }
+
+    /** Append the framed message to buf **/
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        buf.write_u32::<BE>(0).unwrap();
+
+        if let Some(id) = self.id() {
+            buf.write_u8(id).unwrap();
+        }
+
+        match self {
+            &Message::Have(piece) | &Message::Suggest(piece) | &Message::AllowedFast(piece) => {
+                buf.write_u32::<BE>(piece).unwrap();
+            },
+            &Message::Bitfield(ref field) => {
+                buf.extend_from_slice(field);
+            },
+            &Message::Request { index, begin, length } |
+            &Message::Cancel { index, begin, length } |
+            &Message::Reject { index, begin, length } => {
+                buf.write_u32::<BE>(index).unwrap();
+                buf.write_u32::<BE>(begin).unwrap();
+                buf.write_u32::<BE>(length).unwrap();
+            },
+            &Message::Piece { index, begin, ref block } => {
+                buf.write_u32::<BE>(index).unwrap();
+                buf.write_u32::<BE>(begin).unwrap();
+                buf.extend_from_slice(block);
+            },
+            &Message::Port(port) => {
+                buf.write_u16::<BE>(port).unwrap();
+            },
+            &Message::Extended { id, ref payload } => {
+                buf.write_u8(id).unwrap();
+                buf.extend_from_slice(payload);
+            },
+            _ => {}
+        }
+
+        let len = (buf.len() - start - LEN_SIZE) as u32;
+        (&mut buf[start..start + LEN_SIZE]).write_u32::<BE>(len).unwrap();
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode(&mut buf);
+        buf
+    }
+
+    /**
+     * Decode one message from the front of buf, returning it and the number of bytes consumed.
+     * Ok(None) means buf does not yet hold a whole frame.
+     */
+    pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, String> {
+        if buf.len() < LEN_SIZE {
+            return Ok(None);
+        }
+
+        let len = (&buf[0..LEN_SIZE]).read_u32::<BE>().unwrap() as usize;
+
+        if len > MAX_MESSAGE_LEN {
+            return Err(format!("Message of {} bytes exceeds limit", len));
+        }
+
+        if buf.len() < LEN_SIZE + len {
+            return Ok(None);
+        }
+
+        let msg = if len == 0 {
+            Message::KeepAlive
+        } else {
+            Message::parse(buf[LEN_SIZE], &buf[LEN_SIZE + 1..LEN_SIZE + len])?
+        };
+
+        Ok(Some((msg, LEN_SIZE + len)))
+    }
+
+    fn parse(id: u8, mut payload: &[u8]) -> Result<Message, String> {
+        let expect = |size: usize| if payload.len() == size {
+            Ok(())
+        } else {
+            Err(format!("Message {} has payload of {} bytes, expected {}", id, payload.len(), size))
+        };
+
+        let msg = match id {
+            0 => { expect(0)?; Message::Choke },
+            1 => { expect(0)?; Message::Unchoke },
+            2 => { expect(0)?; Message::Interested },
+            3 => { expect(0)?; Message::NotInterested },
+            4 => { expect(4)?; Message::Have(payload.read_u32::<BE>().unwrap()) },
+            5 => Message::Bitfield(payload.to_vec()),
+            6 | 8 | 16 => {
+                expect(12)?;
+                let index = payload.read_u32::<BE>().unwrap();
+                let begin = payload.read_u32::<BE>().unwrap();
+                let length = payload.read_u32::<BE>().unwrap();
+
+                match id {
+                    6 => Message::Request { index: index, begin: begin, length: length },
+                    8 => Message::Cancel { index: index, begin: begin, length: length },
+                    _ => Message::Reject { index: index, begin: begin, length: length }
+                }
+            },
+            7 => {
+                if payload.len() < 8 || payload.len() - 8 > MAX_BLOCK_LEN {
+                    return Err(format!("Piece with bad length {}", payload.len()));
+                }
+
+                Message::Piece {
+                    index: payload.read_u32::<BE>().unwrap(),
+                    begin: payload.read_u32::<BE>().unwrap(),
+                    block: payload.to_vec()
+                }
+            },
+            9 => { expect(2)?; Message::Port(payload.read_u16::<BE>().unwrap()) },
+            13 => { expect(4)?; Message::Suggest(payload.read_u32::<BE>().unwrap()) },
+            14 => { expect(0)?; Message::HaveAll },
+            15 => { expect(0)?; Message::HaveNone },
+            17 => { expect(4)?; Message::AllowedFast(payload.read_u32::<BE>().unwrap()) },
+            20 => {
+                if payload.len() < 1 {
+                    return Err("Extended message with no id".to_string());
+                }
+
+                Message::Extended {
+                    id: payload.read_u8().unwrap(),
+                    payload: payload.to_vec()
+                }
+            },
+            _ => Message::Unknown(id)
+        };
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peer_message::{Message, MAX_MESSAGE_LEN, MAX_BLOCK_LEN};
+    use quickcheck::{Arbitrary, Gen};
+    use byteorder::{BE, WriteBytesExt};
+
+    impl Arbitrary for Message {
+        fn arbitrary<G: Gen>(g: &mut G) -> Message {
+            let mut block: Vec<u8> = Arbitrary::arbitrary(g);
+            block.truncate(MAX_BLOCK_LEN);
+
+            match g.gen_range(0, 17) {
+                0 => Message::KeepAlive,
+                1 => Message::Choke,
+                2 => Message::Unchoke,
+                3 => Message::Interested,
+                4 => Message::NotInterested,
+                5 => Message::Have(g.gen()),
+                6 => Message::Bitfield(block),
+                7 => Message::Request { index: g.gen(), begin: g.gen(), length: g.gen() },
+                8 => Message::Piece { index: g.gen(), begin: g.gen(), block: block },
+                9 => Message::Cancel { index: g.gen(), begin: g.gen(), length: g.gen() },
+                10 => Message::Port(g.gen()),
+                11 => Message::Suggest(g.gen()),
+                12 => Message::HaveAll,
+                13 => Message::HaveNone,
+                14 => Message::Reject { index: g.gen(), begin: g.gen(), length: g.gen() },
+                15 => Message::AllowedFast(g.gen()),
+                _ => Message::Extended { id: g.gen(), payload: block }
+            }
+        }
+    }
+
+    quickcheck! {
+        fn round_trip(msg: Message) -> bool {
+            let data = msg.serialize();
+            Message::decode(&data) == Ok(Some((msg, data.len())))
+        }
+
+        fn stream_of_messages(msgs: Vec<Message>) -> bool {
+            let mut data = Vec::new();
+            msgs.iter().for_each(|msg| msg.encode(&mut data));
+
+            let mut buf = &data[..];
+            let mut decoded = Vec::new();
+
+            while let Ok(Some((msg, used))) = Message::decode(buf) {
+                decoded.push(msg);
+                buf = &buf[used..];
+            }
+
+            buf.is_empty() && decoded == msgs
+        }
+
+        fn partial_frames_wait(msg: Message) -> bool {
+            let data = msg.serialize();
+            (0..data.len()).all(|i| Message::decode(&data[..i]) == Ok(None))
+        }
+    }
+
+    #[test]
+    fn rejects_oversize() {
+        let mut data = Vec::new();
+        data.write_u32::<BE>((MAX_MESSAGE_LEN + 1) as u32).unwrap();
+        data.push(7);
+        assert!(Message::decode(&data).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_payload() {
+        assert!(Message::decode(&[0, 0, 0, 2, 4, 0]).is_err());
+        assert!(Message::decode(&[0, 0, 0, 2, 1, 0]).is_err());
+        assert!(Message::decode(&[0, 0, 0, 1, 20]).is_err());
+    }
+
+    #[test]
+    fn skips_unknown_ids() {
+        assert_eq!(Message::decode(&[0, 0, 0, 3, 99, 1, 2]), Ok(Some((Message::Unknown(99), 7))));
+    }
+}
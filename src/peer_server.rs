@@ -1,14 +1,400 @@
-use std::net::TcpListener;
+/**
+ * Listener for incoming peer-wire connections over TCP and uTP, routed to downloads by info hash
+ */
 
-pub fn peer_server(port: u16) -> Result<(), String> {
-   let incoming_server = TcpListener::bind(("0.0.0.0", port));
+use std::io;
+use std::io::Write;
+use std::io::ErrorKind::WouldBlock;
+use std::net;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use mio::Ready;
+use mio::net::TcpListener;
+use mio_extras::channel;
+use peer_client::HandshakeMsg;
+use tracker::PeerAddress;
+use stream::{PeerStream, Wire, Transport, TransportPreference, connect};
+use mse::{EncryptionPolicy, Negotiation, Negotiated};
+use utp::{UtpSocket, UtpStream, utp_socket};
+use event_loop::{EventLoop, Handler, Context, Event};
+
+/** Connections (incoming and outgoing) allowed across all torrents **/
+pub const MAX_CONNECTIONS: usize = 200;
+
+/** Time a new connection has to negotiate encryption and send its handshake **/
+const ROUTE_TIMEOUT_MS: u64 = 5000;
+
+/**
+ * A counted connection, the slot is returned to the server when dropped
+ */
+pub struct ConnectionSlot {
+    connections: Arc<AtomicUsize>
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/**
+ * An accepted connection whose handshake named a registered torrent
+ */
+pub struct Incoming {
+    pub wire: Wire, /* Deregistered, with encryption set up and the handshake consumed */
+    pub addr: PeerAddress,
+    pub handshake: HandshakeMsg,
+    pub slot: ConnectionSlot
+}
+
+#[derive(Clone)]
+pub struct PeerServer {
+    port: u16,
+    utp: Option<UtpSocket>, /* Shares the TCP port */
+    encryption: EncryptionPolicy, /* What incoming connections must negotiate */
+    events: EventLoop,
+    torrents: Arc<Mutex<HashMap<Vec<u8>, channel::Sender<Incoming>>>>,
+    connections: Arc<AtomicUsize>,
+    max_connections: usize
+}
+
+impl PeerServer {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /** Start connecting out to a peer, using our uTP socket so replies reach the port we listen on **/
+    pub fn connect(&self, peer: &PeerAddress, transport: Transport) -> io::Result<Box<PeerStream>> {
+        connect(peer, transport, self.utp.as_ref())
+    }
+
+    /** Transports an outgoing connection tries in turn **/
+    pub fn transports(&self, preference: TransportPreference) -> Vec<Transport> {
+        preference.order(self.utp.is_some())
+    }
+
+    /** Start routing connections for info_hash to the returned channel **/
+    pub fn register(&self, info_hash: &[u8]) -> channel::Receiver<Incoming> {
+        let (send, recv) = channel::channel();
+        self.torrents.lock().unwrap().insert(info_hash.to_vec(), send);
+        recv
+    }
+
+    pub fn unregister(&self, info_hash: &[u8]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    /** Take a slot for an accepted connection and read its handshake on a handler of its own **/
+    fn accept(&self, stream: Box<PeerStream>) {
+        let slot = match self.acquire() {
+            Some(slot) => slot,
+            None => {
+                println!("Connection limit reached, dropping incoming peer");
+                return;
+            }
+        };
+
+        let info_hashes: Vec<Vec<u8>> = self.torrents.lock().unwrap().keys().cloned().collect();
+
+        self.events.spawn(Box::new(Router {
+            server: self.clone(),
+            negotiation: Some(Negotiation::incoming(info_hashes, self.encryption)),
+            wire: Some(Wire::new(stream)),
+            slot: Some(slot)
+        }));
+    }
+
+    /** Take a connection slot if we are under the global limit **/
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        let mut current = self.connections.load(Ordering::SeqCst);
+
+        loop {
+            if current >= self.max_connections {
+                return None;
+            }
+
+            match self.connections.compare_exchange(current, current + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Some(ConnectionSlot {
+                    connections: self.connections.clone()
+                }),
+                Err(prev) => current = prev
+            }
+        }
+    }
+
+    /** Pass a connection to the torrent its handshake names **/
+    fn route(&self, wire: Wire, handshake: HandshakeMsg, slot: ConnectionSlot) -> Result<(), String> {
+        let addr = wire.stream().peer_addr().map_err(|e| e.to_string())?;
+
+        let mut torrents = self.torrents.lock().unwrap();
+
+        let target = torrents.get(&handshake.info_hash).cloned();
+
+        match target {
+            Some(target) => {
+                let info_hash = handshake.info_hash.clone();
+
+                let incoming = Incoming {
+                    wire: wire,
+                    addr: PeerAddress {
+                        ip: addr.ip(),
+                        port: addr.port()
+                    },
+                    handshake: handshake,
+                    slot: slot
+                };
+
+                if target.send(incoming).is_err() {
+                    torrents.remove(&info_hash);
+                    Err("Torrent no longer active".to_string())
+                } else {
+                    Ok(())
+                }
+            },
+            None => Err("Unknown info hash".to_string())
+        }
+    }
+}
+
+/**
+ * Negotiates encryption with a new connection and reads its handshake, so a slow peer only
+ * holds up itself
+ */
+struct Router {
+    server: PeerServer,
+    negotiation: Option<Negotiation>, /* None once negotiated */
+    wire: Option<Wire>,
+    slot: Option<ConnectionSlot>
+}
+
+impl Router {
+    /** Ok(true) while waiting on the rest of the handshake **/
+    fn advance(&mut self, ctx: &mut Context) -> Result<bool, String> {
+        let handshake = {
+            let wire = self.wire.as_mut().unwrap();
+            wire.receive().map_err(|e| e.to_string())?;
+
+            let negotiated = match self.negotiation {
+                Some(ref mut negotiation) => {
+                    let mut output = Vec::new();
+                    let negotiated = negotiation.advance(wire.input(), &mut output)?;
+                    wire.write_all(&output).map_err(|e| e.to_string())?;
+                    negotiated
+                },
+                None => None
+            };
+
+            if let Some(negotiated) = negotiated {
+                if let Negotiated::Rc4(encrypt, decrypt) = negotiated {
+                    wire.set_ciphers(encrypt, decrypt);
+                }
+
+                self.negotiation = None;
+            }
+
+            wire.send().map_err(|e| e.to_string())?;
+
+            let decoded = match self.negotiation {
+                Some(_) => None,
+                None => HandshakeMsg::decode(wire.input())
+            };
+
+            match decoded {
+                Some((handshake, used)) => {
+                    wire.input().drain(0..used);
+                    handshake
+                },
+                None if wire.is_eof() => return Err("Connection closed during handshake".to_string()),
+                None => return Ok(true)
+            }
+        };
+
+        let wire = self.wire.take().unwrap();
+        ctx.deregister(wire.stream().evented()).map_err(|e| e.to_string())?;
+        self.server.route(wire, handshake, self.slot.take().unwrap())?;
+        Ok(false)
+    }
+}
+
+impl Handler for Router {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        ctx.set_timeout(Duration::from_millis(ROUTE_TIMEOUT_MS));
+
+        let wire = self.wire.as_ref().unwrap();
+
+        if let Err(e) = ctx.register(0, wire.stream().evented(), Ready::readable() | Ready::writable()) {
+            println!("Dropping incoming peer: {}", e);
+            return false;
+        }
+
+        true
+    }
+
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        let result = match event {
+            Event::Ready(..) => self.advance(ctx),
+            Event::Timeout => Err("Handshake timed out".to_string())
+        };
+
+        match result {
+            Ok(waiting) => waiting,
+            Err(e) => {
+                println!("Dropping incoming peer: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/** Sources the listener registers **/
+const SOURCE_TCP: usize = 0;
+const SOURCE_UTP: usize = 1;
+
+/**
+ * Accepts TCP connections and takes uTP connections from the uTP socket
+ */
+struct Listener {
+    server: PeerServer,
+    tcp: TcpListener,
+    utp: Option<channel::Receiver<UtpStream>>
+}
+
+impl Handler for Listener {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        if let Err(e) = ctx.register(SOURCE_TCP, &self.tcp, Ready::readable()) {
+            println!("Peer server stopped because {}", e);
+            return false;
+        }
+
+        if let Some(ref utp) = self.utp {
+            if let Err(e) = ctx.register(SOURCE_UTP, utp, Ready::readable()) {
+                println!("Accepting TCP peers only, uTP failed because {}", e);
+            }
+        }
+
+        true
+    }
+
+    fn event(&mut self, _ctx: &mut Context, event: Event) -> bool {
+        match event {
+            Event::Ready(SOURCE_TCP, _) => loop {
+                match self.tcp.accept() {
+                    Ok((stream, _)) => self.server.accept(Box::new(stream)),
+                    Err(ref e) if e.kind() == WouldBlock => break,
+                    Err(e) => {
+                        println!("Peer server accept error {}", e);
+                        break;
+                    }
+                }
+            },
+            Event::Ready(SOURCE_UTP, _) => {
+                if let Some(ref utp) = self.utp {
+                    while let Ok(stream) = utp.try_recv() {
+                        if stream.set_nonblocking(true).is_ok() {
+                            self.server.accept(Box::new(stream));
+                        }
+                    }
+                }
+            },
+            _ => {}
+        }
+
+        true
+    }
+}
+
+pub fn peer_server(events: &EventLoop, port: u16, encryption: EncryptionPolicy) -> Result<PeerServer, String> {
+    let incoming_server = net::TcpListener::bind(("0.0.0.0", port));
 
     if let Err(v) = incoming_server {
         return Err(v.to_string());
     }
 
     let incoming_server = incoming_server.unwrap();
+    let local_port = incoming_server.local_addr().map_err(|e| e.to_string())?.port();
+    let incoming_server = TcpListener::from_std(incoming_server).map_err(|e| e.to_string())?;
+
+    let (utp, utp_incoming) = match utp_socket(events, local_port) {
+        Ok((utp, incoming)) => (Some(utp), Some(incoming)),
+        Err(e) => {
+            println!("Accepting TCP peers only, uTP failed because {}", e);
+            (None, None)
+        }
+    };
+
+    let server = PeerServer {
+        port: local_port,
+        utp: utp,
+        encryption: encryption,
+        events: events.clone(),
+        torrents: Arc::new(Mutex::new(HashMap::new())),
+        connections: Arc::new(AtomicUsize::new(0)),
+        max_connections: MAX_CONNECTIONS
+    };
+
+    events.spawn(Box::new(Listener {
+        server: server.clone(),
+        tcp: incoming_server,
+        utp: utp_incoming
+    }));
+
+    Ok(server)
+}
+
+#[cfg(test)]
+mod tests {
+    use peer_server::peer_server;
+    use mse::EncryptionPolicy;
+    use peer_client::{HandshakeMsg, Capabilities};
+    use event_loop::{event_loop, recv_timeout};
+    use utp::utp_socket;
+    use std::net::{TcpStream, SocketAddr};
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    #[test]
+    fn routes_by_info_hash() {
+        let events = event_loop(1).unwrap();
+        let server = peer_server(&events, 0, EncryptionPolicy::Enabled).unwrap();
+        let incoming = server.register(&[7; 20]);
 
-    println!("TODO: Peer server threading");
-    Ok(())
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream.write(&HandshakeMsg::new(&[7; 20], &[1; 20], Capabilities::default()).serialize()).unwrap();
+
+        let accepted = recv_timeout(&incoming, Duration::from_secs(5)).unwrap();
+        assert_eq!(accepted.handshake.peer_id, vec![1; 20]);
+
+        //Unknown torrents are dropped without a reply
+        let mut stream = TcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream.write(&HandshakeMsg::new(&[8; 20], &[1; 20], Capabilities::default()).serialize()).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap_or(0), 0);
+    }
+
+    #[test]
+    fn routes_utp_connections() {
+        let events = event_loop(1).unwrap();
+        let server = peer_server(&events, 0, EncryptionPolicy::Enabled).unwrap();
+        let incoming = server.register(&[7; 20]);
+
+        let (utp, _) = utp_socket(&events, 0).unwrap();
+        let addr: SocketAddr = ([127, 0, 0, 1], server.port()).into();
+
+        let mut stream = utp.connect(addr).unwrap();
+        stream.write_all(&HandshakeMsg::new(&[7; 20], &[1; 20], Capabilities::default()).serialize()).unwrap();
+
+        let accepted = recv_timeout(&incoming, Duration::from_secs(5)).unwrap();
+        assert_eq!(accepted.handshake.peer_id, vec![1; 20]);
+    }
+
+    #[test]
+    fn connection_limit() {
+        let events = event_loop(1).unwrap();
+        let server = peer_server(&events, 0, EncryptionPolicy::Enabled).unwrap();
+        let slots: Vec<_> = (0..server.max_connections).map(|_| server.acquire().unwrap()).collect();
+        assert!(server.acquire().is_none());
+        drop(slots);
+        assert!(server.acquire().is_some());
+    }
 }
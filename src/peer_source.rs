@@ -0,0 +1,238 @@
+/**
+ * Places a download hears about peers from. Trackers, the DHT and peer exchange all look the
+ * same to the download, it polls each source and queues whatever they return for connection.
+ */
+
+use std::sync::mpsc::{Sender, Receiver, TryRecvError};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tracker::{TrackerState, PeerAddress};
+use dht::{Dht, NodeId};
+use lsd::Lsd;
+
+pub trait PeerSource: Send {
+    fn name(&self) -> &'static str;
+
+    /** Peers found since the last poll, an error means the source has stopped for good **/
+    fn poll(&mut self) -> Result<Vec<PeerAddress>, String>;
+}
+
+/**
+ * Peers announced by a tracker thread
+ */
+pub struct TrackerSource {
+    channel: (Sender<TrackerState>, Receiver<TrackerState>)
+}
+
+impl TrackerSource {
+    pub fn new(channel: (Sender<TrackerState>, Receiver<TrackerState>)) -> TrackerSource {
+        TrackerSource {
+            channel: channel
+        }
+    }
+}
+
+impl PeerSource for TrackerSource {
+    fn name(&self) -> &'static str {
+        "tracker"
+    }
+
+    fn poll(&mut self) -> Result<Vec<PeerAddress>, String> {
+        let mut found = Vec::new();
+
+        loop {
+            match self.channel.1.try_recv() {
+                Ok(TrackerState::Connected(cid)) => println!("Connected to the tracker with connection id {}", cid),
+                Ok(TrackerState::Announced(peers)) => found.extend(peers),
+                Ok(TrackerState::Close(reason)) => return Err(reason),
+                Err(TryRecvError::Disconnected) => return Err("Tracker thread exited".to_string()),
+                Err(TryRecvError::Empty) => return Ok(found)
+            }
+        }
+    }
+}
+
+/**
+ * Periodic DHT lookups for one info hash, announcing our listen port to the closest nodes
+ */
+pub struct DhtSource {
+    dht: Dht,
+    info_hash: NodeId,
+    port: u16,
+    interval: Duration,
+    next_lookup: Instant,
+    lookup: Option<Receiver<Vec<PeerAddress>>>
+}
+
+impl DhtSource {
+    pub fn new(dht: &Dht, info_hash: NodeId, port: u16, interval: Duration) -> DhtSource {
+        DhtSource {
+            dht: dht.clone(),
+            info_hash: info_hash,
+            port: port,
+            interval: interval,
+            next_lookup: Instant::now(),
+            lookup: None
+        }
+    }
+}
+
+impl PeerSource for DhtSource {
+    fn name(&self) -> &'static str {
+        "dht"
+    }
+
+    fn poll(&mut self) -> Result<Vec<PeerAddress>, String> {
+        let mut found = Vec::new();
+        let mut finished = false;
+
+        if let Some(ref lookup) = self.lookup {
+            loop {
+                match lookup.try_recv() {
+                    Ok(peers) => found.extend(peers),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => {
+                        finished = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        if finished {
+            self.lookup = None;
+        }
+
+        //Only one lookup at a time, the next starts an interval after the last began
+        if self.lookup.is_none() && Instant::now() >= self.next_lookup {
+            self.next_lookup = Instant::now() + self.interval;
+            self.lookup = Some(self.dht.announce(self.info_hash, self.port));
+        }
+
+        Ok(found)
+    }
+}
+
+/**
+ * Peers connected clients told us about with ut_pex, fed by the download as the messages arrive
+ */
+pub struct PexSource {
+    recv: Receiver<Vec<PeerAddress>>
+}
+
+pub fn pex_source() -> (Sender<Vec<PeerAddress>>, PexSource) {
+    let (send, recv) = mpsc::channel();
+
+    (send, PexSource {
+        recv: recv
+    })
+}
+
+impl PeerSource for PexSource {
+    fn name(&self) -> &'static str {
+        "pex"
+    }
+
+    fn poll(&mut self) -> Result<Vec<PeerAddress>, String> {
+        Ok(self.recv.try_iter().flat_map(|peers| peers).collect())
+    }
+}
+
+/**
+ * Peers on the local network announcing the same torrent, registered for as long as the source lives
+ */
+pub struct LsdSource {
+    lsd: Lsd,
+    info_hash: Vec<u8>,
+    recv: Receiver<Vec<PeerAddress>>
+}
+
+impl LsdSource {
+    pub fn new(lsd: &Lsd, info_hash: &[u8]) -> LsdSource {
+        LsdSource {
+            lsd: lsd.clone(),
+            info_hash: info_hash.to_vec(),
+            recv: lsd.register(info_hash)
+        }
+    }
+}
+
+impl PeerSource for LsdSource {
+    fn name(&self) -> &'static str {
+        "lsd"
+    }
+
+    fn poll(&mut self) -> Result<Vec<PeerAddress>, String> {
+        Ok(self.recv.try_iter().flat_map(|peers| peers).collect())
+    }
+}
+
+impl Drop for LsdSource {
+    fn drop(&mut self) {
+        self.lsd.unregister(&self.info_hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peer_source::{PeerSource, DhtSource, LsdSource};
+    use dht::{detached_dht, DhtRequest};
+    use lsd::{detached_lsd, LsdRequest};
+    use tracker::PeerAddress;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn dht_lookups_wait_for_the_interval() {
+        let (dht, requests) = detached_dht();
+        let mut source = DhtSource::new(&dht, [1; 20], 6881, Duration::from_secs(60));
+        let peer = PeerAddress { ip: "10.0.0.1".parse().unwrap(), port: 4000 };
+
+        assert!(source.poll().unwrap().is_empty());
+
+        match requests.try_recv() {
+            Ok(DhtRequest::Announce(info_hash, 6881, found)) => {
+                assert_eq!(info_hash, [1; 20]);
+                found.send(vec![peer.clone()]).unwrap();
+            },
+            _ => panic!("Expected an announce")
+        }
+
+        //The lookup has ended, but the next isn't due for a minute
+        assert_eq!(source.poll().unwrap(), vec![peer]);
+        assert!(requests.try_recv().is_err());
+
+        source.next_lookup = Instant::now();
+        source.poll().unwrap();
+
+        let _running = match requests.try_recv() {
+            Ok(DhtRequest::Announce(_, _, found)) => found,
+            _ => panic!("Expected a second announce")
+        };
+
+        //Never more than one lookup at a time, however overdue the next is
+        source.next_lookup = Instant::now();
+        source.poll().unwrap();
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn lsd_unregisters_on_drop() {
+        let (lsd, requests) = detached_lsd();
+
+        {
+            let _source = LsdSource::new(&lsd, &[2; 20]);
+
+            match requests.try_recv() {
+                Ok(LsdRequest::Register(ref info_hash, _)) if info_hash == &[2; 20] => {},
+                _ => panic!("Expected a register")
+            }
+
+            assert!(requests.try_recv().is_err());
+        }
+
+        match requests.try_recv() {
+            Ok(LsdRequest::Unregister(ref info_hash)) if info_hash == &[2; 20] => {},
+            _ => panic!("Expected an unregister")
+        }
+    }
+}
@@ -0,0 +1,147 @@
+/**
+ * Peer exchange (ut_pex, BEP 11). Connected peers periodically tell each other which peers they
+ * have connected to or dropped since the last message, as compact address lists with flags.
+ */
+
+use std::collections::HashMap;
+use bencoder::{Entry, EntryData, decode};
+use extension::ExtensionHandler;
+use peer_client::ClientState;
+use tracker::PeerAddress;
+
+pub const PEX_NAME: &'static str = "ut_pex";
+
+/** Most added or dropped peers we send in one message or take from one we receive **/
+pub const MAX_PEX_PEERS: usize = 50;
+
+/** Peers may send at most one message a minute, anything sooner is dropped **/
+pub const MIN_PEX_INTERVAL_SECS: u64 = 60;
+
+pub const FLAG_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_UTP: u8 = 0x04;
+pub const FLAG_REACHABLE: u8 = 0x10; /* We connected to it, so it accepts incoming connections */
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(PeerAddress, u8)>, /* Address and flags */
+    pub dropped: Vec<PeerAddress>
+}
+
+impl PexMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut dict = HashMap::new();
+
+        for &(v6, key) in [(false, "added"), (true, "added6")].iter() {
+            let added: Vec<&(PeerAddress, u8)> = self.added.iter().filter(|&&(ref addr, _)| addr.ip.is_ipv6() == v6).collect();
+
+            let addrs = added.iter().flat_map(|&&(ref addr, _)| addr.to_compact()).collect();
+            let flags = added.iter().map(|&&(_, flags)| flags).collect();
+
+            dict.insert(key.to_string(), Entry::new(EntryData::Str(addrs)));
+            dict.insert(key.to_string() + ".f", Entry::new(EntryData::Str(flags)));
+        }
+
+        for &(v6, key) in [(false, "dropped"), (true, "dropped6")].iter() {
+            let addrs = self.dropped.iter().filter(|addr| addr.ip.is_ipv6() == v6).flat_map(|addr| addr.to_compact()).collect();
+            dict.insert(key.to_string(), Entry::new(EntryData::Str(addrs)));
+        }
+
+        EntryData::Dictionary(dict).bencode()
+    }
+
+    pub fn decode(mut payload: &[u8]) -> Result<PexMessage, String> {
+        let root = decode(&mut payload).map_err(|_| "Malformed PEX message".to_string())?;
+
+        let bytes = |key: &str| match root.field(key) {
+            Ok(Entry { data: EntryData::Str(v), .. }) => v,
+            _ => Vec::new()
+        };
+
+        let mut msg = PexMessage::default();
+
+        for &(v6, key) in [(false, "added"), (true, "added6")].iter() {
+            let flags = bytes(&(key.to_string() + ".f"));
+            let addrs = PeerAddress::from_compact_list(&bytes(key), v6);
+
+            for (i, addr) in addrs.into_iter().enumerate() {
+                msg.added.push((addr, flags.get(i).cloned().unwrap_or(0)));
+            }
+        }
+
+        msg.dropped.extend(PeerAddress::from_compact_list(&bytes("dropped"), false));
+        msg.dropped.extend(PeerAddress::from_compact_list(&bytes("dropped6"), true));
+
+        //Oversized lists are cut short rather than costing us the peer
+        msg.added.truncate(MAX_PEX_PEERS);
+        msg.dropped.truncate(MAX_PEX_PEERS);
+
+        Ok(msg)
+    }
+}
+
+/**
+ * Passes the peers a remote has added on to the download as connection candidates
+ */
+pub struct PexHandler;
+
+impl ExtensionHandler for PexHandler {
+    fn name(&self) -> &'static str {
+        PEX_NAME
+    }
+
+    fn receive(&mut self, payload: &[u8]) -> Result<Option<ClientState>, String> {
+        let msg = PexMessage::decode(payload)?;
+
+        if msg.added.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(ClientState::Discovered(msg.added.into_iter().map(|(addr, _)| addr).collect())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pex::{PexMessage, FLAG_SEED, FLAG_REACHABLE, MAX_PEX_PEERS};
+    use tracker::PeerAddress;
+    use std::net::IpAddr;
+
+    fn addr(ip: &str, port: u16) -> PeerAddress {
+        PeerAddress {
+            ip: ip.parse::<IpAddr>().unwrap(),
+            port: port
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = PexMessage {
+            added: vec![(addr("10.0.0.1", 6881), FLAG_SEED), (addr("10.0.0.2", 80), 0), (addr("2001:db8::1", 6882), FLAG_REACHABLE)],
+            dropped: vec![addr("192.168.1.1", 1), addr("::1", 2)]
+        };
+
+        assert_eq!(PexMessage::decode(&msg.encode()).unwrap(), msg);
+    }
+
+    #[test]
+    fn missing_flags_and_lists() {
+        let msg = PexMessage::decode(b"d5:added6:\x0a\x00\x00\x01\x1a\xe1e").unwrap();
+        assert_eq!(msg.added, vec![(addr("10.0.0.1", 6881), 0)]);
+        assert!(msg.dropped.is_empty());
+    }
+
+    #[test]
+    fn long_lists_truncated() {
+        let many: Vec<PeerAddress> = (0..MAX_PEX_PEERS as u16 + 10).map(|port| addr("10.0.0.1", port)).collect();
+
+        let msg = PexMessage {
+            added: many.iter().map(|a| (a.clone(), 0)).collect(),
+            dropped: many.clone()
+        };
+
+        let decoded = PexMessage::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.added.len(), MAX_PEX_PEERS);
+        assert_eq!(decoded.dropped, many[0..MAX_PEX_PEERS].to_vec());
+    }
+}
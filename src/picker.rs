@@ -0,0 +1,503 @@
+/**
+ * Block-level piece picker shared by every peer of a download.
+ * Tracks each 16KiB block of the pieces in progress so several peers can work on one piece,
+ * and blocks held by slow or departed peers can be handed to someone else.
+ * New pieces are chosen rarest-first using availability counts from every connected peer, or in
+ * order when streaming. Pieces given a deadline are fetched first and, once the deadline is close,
+ * may be requested from several fast peers at once.
+ */
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Instant;
+use rand::{thread_rng, Rng};
+use bitfield::Bitfield;
+use torrent::piece_size;
+
+pub const BLOCK_SIZE: usize = 16384;
+
+/** Stable identifier for a peer connection within a download **/
+pub type PeerKey = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Block {
+    pub piece: usize,
+    pub begin: usize,
+    pub length: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PickStrategy {
+    RarestFirst,
+    Sequential
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BlockState {
+    Free,
+    Requested(Vec<PeerKey>), /* Usually one peer, several for urgent blocks */
+    Received(PeerKey)
+}
+
+impl BlockState {
+    /** Drop the requests matching f, freeing the block if none remain **/
+    fn release<F: Fn(PeerKey) -> bool>(&mut self, f: F) -> Vec<PeerKey> {
+        let mut released = Vec::new();
+
+        let now_free = match self {
+            &mut BlockState::Requested(ref mut holders) => {
+                holders.retain(|&peer| if f(peer) {
+                    released.push(peer);
+                    false
+                } else {
+                    true
+                });
+                holders.is_empty()
+            },
+            _ => false
+        };
+
+        if now_free {
+            *self = BlockState::Free;
+        }
+
+        released
+    }
+}
+
+/**
+ * A piece whose every block has arrived, ready to be verified
+ */
+pub struct CompletedPiece {
+    pub piece: usize,
+    pub data: Vec<u8>,
+    pub contributors: Vec<PeerKey> /* Sender of each block in order */
+}
+
+struct PieceProgress {
+    blocks: Vec<BlockState>,
+    data: Vec<u8>
+}
+
+impl PieceProgress {
+    fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|b| match b { &BlockState::Received(_) => true, _ => false })
+    }
+}
+
+pub struct Picker {
+    piece_length: usize,
+    total_length: usize,
+    availability: Vec<usize>, /* Number of connected peers with each piece */
+    strategy: PickStrategy,
+    deadlines: HashMap<usize, Instant>,
+    in_progress: HashMap<usize, PieceProgress>
+}
+
+impl Picker {
+    pub fn new(piece_length: usize, total_length: usize, num_pieces: usize, strategy: PickStrategy) -> Picker {
+        Picker {
+            piece_length: piece_length,
+            total_length: total_length,
+            availability: vec![0; num_pieces],
+            strategy: strategy,
+            deadlines: HashMap::new(),
+            in_progress: HashMap::new()
+        }
+    }
+
+    pub fn set_strategy(&mut self, strategy: PickStrategy) {
+        self.strategy = strategy;
+    }
+
+    /** Pieces that should be complete by a point in time, e.g. when a player reaches them **/
+    pub fn set_deadline(&mut self, pieces: Range<usize>, deadline: Instant) {
+        let num_pieces = self.availability.len();
+
+        for piece in pieces.filter(|&p| p < num_pieces) {
+            self.deadlines.insert(piece, deadline);
+        }
+    }
+
+    pub fn clear_deadlines(&mut self) {
+        self.deadlines.clear();
+    }
+
+    /** Count the pieces of a peer that connected or sent its bitfield **/
+    pub fn add_availability(&mut self, has: &Bitfield) {
+        for piece in has.ones() {
+            self.availability[piece] += 1;
+        }
+    }
+
+    /** Forget the pieces of a peer that disconnected or replaced its bitfield **/
+    pub fn remove_availability(&mut self, has: &Bitfield) {
+        for piece in has.ones() {
+            self.availability[piece] = self.availability[piece].saturating_sub(1);
+        }
+    }
+
+    /** A peer announced a new piece **/
+    pub fn peer_has(&mut self, piece: usize) {
+        if let Some(count) = self.availability.get_mut(piece) {
+            *count += 1;
+        }
+    }
+
+    pub fn availability(&self, piece: usize) -> usize {
+        self.availability.get(piece).cloned().unwrap_or(0)
+    }
+
+    fn block(&self, piece: usize, idx: usize) -> Block {
+        let begin = idx * BLOCK_SIZE;
+
+        Block {
+            piece: piece,
+            begin: begin,
+            length: BLOCK_SIZE.min(piece_size(piece, self.piece_length, self.total_length) - begin)
+        }
+    }
+
+    fn start_piece(&mut self, piece: usize) {
+        let size = piece_size(piece, self.piece_length, self.total_length);
+
+        self.in_progress.insert(piece, PieceProgress {
+            blocks: vec![BlockState::Free; (size + BLOCK_SIZE - 1) / BLOCK_SIZE],
+            data: vec![0; size]
+        });
+    }
+
+    /**
+     * Choose up to count blocks for a peer with the pieces in has.
+     * Deadline pieces come first, soonest first, and if urgent_before is given (the peer is
+     * one of our fastest) blocks of pieces due before then may duplicate other peers' requests.
+     * Then pieces already in progress are finished before new ones are started,
+     * new pieces are the rarest the peer has with ties broken at random, or the next in order
+     * when sequential. In endgame any outstanding block the peer has may be requested again.
+     */
+    pub fn pick(&mut self, peer: PeerKey, has: &Bitfield, have: &Bitfield, count: usize, urgent_before: Option<Instant>) -> Vec<Block> {
+        let mut picked = Vec::new();
+
+        self.deadlines.retain(|&p, _| !have.get(p));
+
+        let mut due: Vec<(Instant, usize)> = self.deadlines.iter()
+            .filter(|&(&p, _)| has.get(p))
+            .map(|(&p, &deadline)| (deadline, p))
+            .collect();
+        due.sort();
+
+        for (deadline, piece) in due {
+            let urgent = urgent_before.map(|t| deadline <= t).unwrap_or(false);
+            self.pick_from(piece, peer, urgent, count, &mut picked);
+        }
+
+        let mut started: Vec<usize> = self.in_progress.keys().cloned().filter(|&p| has.get(p)).collect();
+        started.sort();
+
+        let mut fresh: Vec<usize> = has.and_not(have).ones()
+            .filter(|p| !self.in_progress.contains_key(p))
+            .collect();
+
+        if self.strategy == PickStrategy::RarestFirst {
+            thread_rng().shuffle(&mut fresh);
+            fresh.sort_by_key(|&p| self.availability[p]);
+        }
+
+        for &piece in started.iter().chain(fresh.iter()) {
+            if picked.len() >= count {
+                break;
+            }
+
+            self.pick_from(piece, peer, false, count, &mut picked);
+        }
+
+        if picked.len() < count && self.in_endgame(have) {
+            for &piece in started.iter() {
+                self.pick_from(piece, peer, true, count, &mut picked);
+            }
+        }
+
+        picked
+    }
+
+    /**
+     * Endgame starts once every missing piece a connected peer can give us has been started
+     * and every block of those pieces is requested or received, so nothing is left to hand out
+     */
+    pub fn in_endgame(&self, have: &Bitfield) -> bool {
+        let all_started = have.zeros()
+            .filter(|&p| self.availability[p] > 0)
+            .all(|p| self.in_progress.contains_key(&p));
+
+        let none_free = self.in_progress.values()
+            .all(|progress| progress.blocks.iter().all(|b| *b != BlockState::Free));
+
+        !self.in_progress.is_empty() && all_started && none_free
+    }
+
+    /** False if a block is already received or belongs to a piece we aren't downloading **/
+    pub fn is_needed(&self, block: Block) -> bool {
+        match self.in_progress.get(&block.piece).and_then(|p| p.blocks.get(block.begin / BLOCK_SIZE)) {
+            Some(&BlockState::Received(_)) | None => false,
+            _ => true
+        }
+    }
+
+    /** Take blocks of one piece until picked holds count, duplicating other peers' requests if allowed **/
+    fn pick_from(&mut self, piece: usize, peer: PeerKey, duplicate: bool, count: usize, picked: &mut Vec<Block>) {
+        if picked.len() >= count {
+            return;
+        }
+
+        if !self.in_progress.contains_key(&piece) {
+            self.start_piece(piece);
+        }
+
+        let chosen: Vec<usize> = self.in_progress[&piece].blocks.iter().enumerate()
+            .filter(|&(_, b)| match b {
+                &BlockState::Free => true,
+                &BlockState::Requested(ref holders) => duplicate && !holders.contains(&peer),
+                &BlockState::Received(_) => false
+            })
+            .map(|(i, _)| i)
+            .take(count - picked.len())
+            .collect();
+
+        for idx in chosen {
+            let state = &mut self.in_progress.get_mut(&piece).unwrap().blocks[idx];
+
+            let duplicated = match state {
+                &mut BlockState::Requested(ref mut holders) => {
+                    holders.push(peer);
+                    true
+                },
+                _ => false
+            };
+
+            if !duplicated {
+                *state = BlockState::Requested(vec![peer]);
+            }
+
+            picked.push(self.block(piece, idx));
+        }
+    }
+
+    /** Peers other than peer with an outstanding request for block **/
+    pub fn requesters(&self, peer: PeerKey, block: Block) -> Vec<PeerKey> {
+        match self.in_progress.get(&block.piece).and_then(|p| p.blocks.get(block.begin / BLOCK_SIZE)) {
+            Some(&BlockState::Requested(ref holders)) => holders.iter().cloned().filter(|&k| k != peer).collect(),
+            _ => Vec::new()
+        }
+    }
+
+    /**
+     * Store a block sent by a peer. Returns the piece data and who sent each block once every
+     * block has arrived, the piece is then no longer tracked.
+     */
+    pub fn received(&mut self, peer: PeerKey, block: Block, data: &[u8]) -> Option<CompletedPiece> {
+        let complete = match self.in_progress.get_mut(&block.piece) {
+            Some(progress) => {
+                let idx = block.begin / BLOCK_SIZE;
+                let valid = block.begin % BLOCK_SIZE == 0 && idx < progress.blocks.len() && data.len() == BLOCK_SIZE.min(progress.data.len() - block.begin);
+
+                if !valid {
+                    return None;
+                }
+
+                if let BlockState::Received(_) = progress.blocks[idx] {
+                    return None;
+                }
+
+                progress.data[block.begin..block.begin + data.len()].copy_from_slice(data);
+                progress.blocks[idx] = BlockState::Received(peer);
+                progress.is_complete()
+            },
+            None => false
+        };
+
+        if complete {
+            self.in_progress.remove(&block.piece).map(|progress| CompletedPiece {
+                piece: block.piece,
+                data: progress.data,
+                contributors: progress.blocks.iter().map(|b| match b {
+                    &BlockState::Received(owner) => owner,
+                    _ => unreachable!()
+                }).collect()
+            })
+        } else {
+            None
+        }
+    }
+
+    /** True if a piece still being downloaded holds a block a peer sent **/
+    pub fn has_blocks_from(&self, peer: PeerKey) -> bool {
+        self.in_progress.values().any(|progress| progress.blocks.iter().any(|b| b == &BlockState::Received(peer)))
+    }
+
+    /** Free a block a peer will no longer deliver **/
+    pub fn cancel(&mut self, peer: PeerKey, block: Block) {
+        if let Some(progress) = self.in_progress.get_mut(&block.piece) {
+            if let Some(state) = progress.blocks.get_mut(block.begin / BLOCK_SIZE) {
+                state.release(|owner| owner == peer);
+            }
+        }
+    }
+
+    /** Free every block requested from a peer that disconnected **/
+    pub fn peer_gone(&mut self, peer: PeerKey) {
+        for progress in self.in_progress.values_mut() {
+            for state in progress.blocks.iter_mut() {
+                state.release(|owner| owner == peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use picker::{Picker, PickStrategy, BLOCK_SIZE};
+    use bitfield::Bitfield;
+    use std::time::{Duration, Instant};
+
+    fn all(len: usize) -> Bitfield {
+        let mut field = Bitfield::new(len);
+        field.set_all();
+        field
+    }
+
+    #[test]
+    fn peers_share_a_piece() {
+        let mut picker = Picker::new(BLOCK_SIZE * 4, BLOCK_SIZE * 7 + 10, 2, PickStrategy::RarestFirst);
+        let mut have = Bitfield::new(2);
+
+        //Make piece 0 the rarest so the order is fixed
+        let mut second_piece = Bitfield::new(2);
+        second_piece.set(1).unwrap();
+        picker.add_availability(&second_piece);
+
+        let first = picker.pick(1, &all(2), &have, 2, None);
+        let second = picker.pick(2, &all(2), &have, 3, None);
+
+        assert_eq!(first.iter().map(|b| (b.piece, b.begin)).collect::<Vec<_>>(), vec![(0, 0), (0, BLOCK_SIZE)]);
+        assert_eq!(second.iter().map(|b| (b.piece, b.begin)).collect::<Vec<_>>(), vec![(0, BLOCK_SIZE * 2), (0, BLOCK_SIZE * 3), (1, 0)]);
+
+        assert!(picker.received(1, first[0], &vec![1; BLOCK_SIZE]).is_none());
+        assert!(picker.received(2, second[0], &vec![3; BLOCK_SIZE]).is_none());
+        assert!(picker.received(2, second[1], &vec![4; BLOCK_SIZE]).is_none());
+
+        let complete = picker.received(1, first[1], &vec![2; BLOCK_SIZE]).unwrap();
+        let data = complete.data;
+        assert_eq!(complete.piece, 0);
+        assert_eq!(complete.contributors, vec![1, 1, 2, 2]);
+        assert_eq!(data.len(), BLOCK_SIZE * 4);
+        assert_eq!((data[0], data[BLOCK_SIZE], data[BLOCK_SIZE * 2], data[BLOCK_SIZE * 3]), (1, 2, 3, 4));
+        have.set(0).unwrap();
+
+        //The final piece is short, then endgame repeats peer 2's outstanding request
+        let last = picker.pick(1, &all(2), &have, 10, None);
+        assert_eq!(last.iter().map(|b| b.length).collect::<Vec<_>>(), vec![BLOCK_SIZE, BLOCK_SIZE, 10, BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn reassigns_from_departed_peers() {
+        let mut picker = Picker::new(BLOCK_SIZE * 2, BLOCK_SIZE * 4, 2, PickStrategy::Sequential);
+        let have = Bitfield::new(2);
+        let mut first = Bitfield::new(2);
+        first.set(0).unwrap();
+
+        picker.add_availability(&all(2));
+        assert_eq!(picker.pick(1, &all(2), &have, 2, None).len(), 2);
+        assert!(picker.pick(2, &first, &have, 2, None).is_empty());
+
+        picker.peer_gone(1);
+        assert_eq!(picker.pick(2, &first, &have, 2, None).len(), 2);
+    }
+
+    #[test]
+    fn reassigns_cancelled_blocks() {
+        let mut picker = Picker::new(BLOCK_SIZE, BLOCK_SIZE, 1, PickStrategy::RarestFirst);
+        let have = Bitfield::new(1);
+
+        let block = picker.pick(1, &all(1), &have, 1, None)[0];
+        picker.cancel(1, block);
+        assert_eq!(picker.pick(2, &all(1), &have, 1, None), vec![block]);
+
+        //A late block from the first peer still counts
+        assert!(picker.received(1, block, &vec![0; BLOCK_SIZE]).is_some());
+    }
+
+    #[test]
+    fn rarest_first() {
+        let mut picker = Picker::new(BLOCK_SIZE, BLOCK_SIZE * 4, 4, PickStrategy::RarestFirst);
+        let have = Bitfield::new(4);
+
+        let mut common = Bitfield::new(4);
+        common.set(0).unwrap();
+        common.set(1).unwrap();
+        common.set(3).unwrap();
+
+        picker.add_availability(&all(4));
+        picker.add_availability(&common);
+        picker.add_availability(&common);
+        picker.peer_has(1);
+
+        //Piece 2 is held by one peer, 0 and 3 by three and 1 by four
+        assert_eq!(picker.pick(1, &all(4), &have, 1, None)[0].piece, 2);
+        let tied = picker.pick(1, &all(4), &have, 2, None);
+        let mut tied: Vec<usize> = tied.iter().map(|b| b.piece).collect();
+        tied.sort();
+        assert_eq!(tied, vec![0, 3]);
+        assert_eq!(picker.pick(1, &all(4), &have, 1, None)[0].piece, 1);
+
+        picker.remove_availability(&all(4));
+        assert_eq!(picker.availability(2), 0);
+        assert_eq!(picker.availability(1), 3);
+    }
+
+    #[test]
+    fn sequential_with_deadlines() {
+        let mut picker = Picker::new(BLOCK_SIZE, BLOCK_SIZE * 6, 6, PickStrategy::Sequential);
+        let have = Bitfield::new(6);
+
+        picker.add_availability(&all(6));
+        assert_eq!(picker.pick(1, &all(6), &have, 2, None).iter().map(|b| b.piece).collect::<Vec<_>>(), vec![0, 1]);
+
+        //Pieces with a deadline jump the queue, soonest first
+        let now = Instant::now();
+        picker.set_deadline(4..5, now + Duration::from_secs(10));
+        picker.set_deadline(5..6, now + Duration::from_secs(1));
+        assert_eq!(picker.pick(1, &all(6), &have, 3, None).iter().map(|b| b.piece).collect::<Vec<_>>(), vec![5, 4, 2]);
+
+        //A fast peer may duplicate urgent requests, and the other holders are reported
+        let urgent = picker.pick(2, &all(6), &have, 1, Some(now + Duration::from_secs(5)));
+        assert_eq!(urgent[0].piece, 5);
+        assert_eq!(picker.requesters(2, urgent[0]), vec![1]);
+
+        //Once one copy is cancelled the block stays requested by the other peer
+        picker.cancel(1, urgent[0]);
+        assert!(picker.requesters(1, urgent[0]) == vec![2]);
+    }
+
+    #[test]
+    fn endgame() {
+        let mut picker = Picker::new(BLOCK_SIZE * 2, BLOCK_SIZE * 4, 2, PickStrategy::RarestFirst);
+        let have = Bitfield::new(2);
+
+        picker.add_availability(&all(2));
+        assert_eq!(picker.pick(1, &all(2), &have, 3, None).len(), 3);
+        assert!(!picker.in_endgame(&have));
+
+        //The last free block goes out normally, then peer 2 duplicates peer 1's requests
+        assert_eq!(picker.pick(2, &all(2), &have, 1, None).len(), 1);
+        assert!(picker.in_endgame(&have));
+
+        let duplicates = picker.pick(2, &all(2), &have, 10, None);
+        assert_eq!(duplicates.len(), 3);
+        assert_eq!(picker.requesters(2, duplicates[0]), vec![1]);
+
+        //The first copy to arrive wins, the second is redundant
+        assert!(picker.received(2, duplicates[0], &vec![0; BLOCK_SIZE]).is_none());
+        assert!(!picker.is_needed(duplicates[0]));
+        assert!(picker.is_needed(duplicates[1]));
+    }
+}
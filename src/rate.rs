@@ -0,0 +1,46 @@
+/**
+ * Transfer rate measurement
+ */
+
+use std::time::{Duration, Instant};
+
+pub fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+/**
+ * Smoothed transfer rate, sampled once a second
+ */
+pub struct Throughput {
+    window_start: Instant,
+    window_bytes: usize,
+    rate: f64
+}
+
+impl Throughput {
+    pub fn new() -> Throughput {
+        Throughput {
+            window_start: Instant::now(),
+            window_bytes: 0,
+            rate: 0.0
+        }
+    }
+
+    pub fn add(&mut self, bytes: usize) {
+        self.window_bytes += bytes;
+    }
+
+    /** Bytes per second **/
+    pub fn rate(&mut self) -> f64 {
+        let elapsed = secs(self.window_start.elapsed());
+
+        if elapsed >= 1.0 {
+            let sample = self.window_bytes as f64 / elapsed;
+            self.rate = (self.rate * 0.5) + (sample * 0.5);
+            self.window_bytes = 0;
+            self.window_start = Instant::now();
+        }
+
+        self.rate
+    }
+}
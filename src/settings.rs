@@ -0,0 +1,112 @@
+/**
+ * Tunables shared by a download and its peer connections
+ */
+
+use std::time::Duration;
+use picker::PickStrategy;
+use stream::TransportPreference;
+use mse::EncryptionPolicy;
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /** Skip Have messages for pieces the peer already has **/
+    pub suppress_redundant_haves: bool,
+
+    /** Bounds on the block requests kept outstanding to one peer **/
+    pub min_request_queue: usize,
+    pub max_request_queue: usize,
+
+    /** Transfer time at a peer's measured rate to keep requested ahead, on top of the round trip **/
+    pub request_queue_time: Duration,
+
+    /** Time a block may be outstanding before it is offered to another peer **/
+    pub request_timeout: Duration,
+
+    /** A peer that sends no blocks for this long while we wait is snubbed and loses its regular unchoke slot **/
+    pub snub_timeout: Duration,
+
+    /** Peers we have exchanged no data with for this long are dropped to make room for others **/
+    pub idle_timeout: Duration,
+
+    /** New outgoing connections started each second, and how long before an address is tried again **/
+    pub connections_per_second: usize,
+    pub reconnect_interval: Duration,
+
+    /** How often connected peers are sent peer exchange updates **/
+    pub pex_interval: Duration,
+
+    /** Trust an address may lose to failed pieces before it is banned **/
+    pub ban_threshold: i32,
+
+    /** Compare failed pieces block by block with the good copy to find who sent the bad data **/
+    pub smart_ban: bool,
+
+    /** Order new pieces are started in, sequential suits streaming playback **/
+    pub pick_strategy: PickStrategy,
+
+    /** Pieces due within this window may be requested from several of the fastest peers **/
+    pub deadline_window: Duration,
+    pub deadline_peers: usize,
+
+    /** Peers unchoked for their rate each choking round, plus randomly rotated optimistic slots **/
+    pub unchoke_slots: usize,
+    pub optimistic_unchoke_slots: usize,
+    pub choke_interval: Duration,
+    pub optimistic_unchoke_interval: Duration,
+
+    /** How often each download looks its info hash up in the DHT and announces itself **/
+    pub dht_announce_interval: Duration,
+
+    /** host:port of DHT nodes to join through, and where the node keeps its id and routing table **/
+    pub dht_bootstrap: Vec<String>,
+    pub dht_state_path: Option<String>,
+
+    /** Announce the torrent to and take peers from the local network, and how often to announce **/
+    pub local_service_discovery: bool,
+    pub lsd_interval: Duration,
+
+    /** Transport outgoing peer connections try first, and how long each attempt may take **/
+    pub transport: TransportPreference,
+    pub connect_timeout: Duration,
+
+    /** Whether peer connections are encrypted, outgoing enabled connections fall back to plaintext **/
+    pub encryption: EncryptionPolicy,
+
+    /** Threads the event loop spreads connections, trackers and timers across **/
+    pub event_threads: usize
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            suppress_redundant_haves: true,
+            min_request_queue: 2,
+            max_request_queue: 250,
+            request_queue_time: Duration::from_secs(3),
+            request_timeout: Duration::from_secs(20),
+            snub_timeout: Duration::from_secs(60),
+            idle_timeout: Duration::from_secs(300),
+            connections_per_second: 10,
+            reconnect_interval: Duration::from_secs(60),
+            pex_interval: Duration::from_secs(60),
+            ban_threshold: 7,
+            smart_ban: true,
+            pick_strategy: PickStrategy::RarestFirst,
+            deadline_window: Duration::from_secs(5),
+            deadline_peers: 4,
+            unchoke_slots: 4,
+            optimistic_unchoke_slots: 1,
+            choke_interval: Duration::from_secs(10),
+            optimistic_unchoke_interval: Duration::from_secs(30),
+            dht_announce_interval: Duration::from_secs(15 * 60),
+            dht_bootstrap: vec!["router.bittorrent.com:6881".to_string(), "dht.transmissionbt.com:6881".to_string()],
+            dht_state_path: Some("dht.state".to_string()),
+            local_service_discovery: true,
+            lsd_interval: Duration::from_secs(5 * 60),
+            transport: TransportPreference::UtpFirst,
+            connect_timeout: Duration::from_secs(5),
+            encryption: EncryptionPolicy::Enabled,
+            event_threads: 2
+        }
+    }
+}
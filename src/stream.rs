@@ -0,0 +1,284 @@
+/**
+ * A peer-wire connection over either TCP or uTP, so the peer state machine doesn't care which
+ */
+
+use std::io;
+use std::io::{Read, Write};
+use std::io::ErrorKind::WouldBlock;
+use std::net::SocketAddr;
+use mio::Evented;
+use mio::net::TcpStream;
+use tracker::PeerAddress;
+use utp::{UtpSocket, UtpStream};
+use mse::Rc4;
+
+/**
+ * A non-blocking stream the event loop can wait on
+ */
+pub trait PeerStream: Read + Write + Send {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /** Ok(false) while an outgoing connection is still being made, an error if it failed **/
+    fn is_connected(&self) -> io::Result<bool>;
+
+    fn evented(&self) -> &Evented;
+}
+
+impl PeerStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        if let Some(e) = self.take_error()? {
+            return Err(e);
+        }
+
+        match TcpStream::peer_addr(self) {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    fn evented(&self) -> &Evented {
+        self
+    }
+}
+
+impl PeerStream for UtpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        UtpStream::peer_addr(self)
+    }
+
+    fn is_connected(&self) -> io::Result<bool> {
+        UtpStream::is_connected(self)
+    }
+
+    fn evented(&self) -> &Evented {
+        self
+    }
+}
+
+/** Which transport outgoing connections try first, falling back to the other **/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransportPreference {
+    UtpFirst,
+    TcpFirst
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Utp,
+    Tcp
+}
+
+impl TransportPreference {
+    /** Transports to try in turn, without a uTP socket only TCP is tried **/
+    pub fn order(&self, utp: bool) -> Vec<Transport> {
+        match (*self, utp) {
+            (_, false) => vec![Transport::Tcp],
+            (TransportPreference::UtpFirst, true) => vec![Transport::Utp, Transport::Tcp],
+            (TransportPreference::TcpFirst, true) => vec![Transport::Tcp, Transport::Utp]
+        }
+    }
+}
+
+/**
+ * Start connecting to a peer over transport without waiting for it to answer. The stream becomes
+ * writable once connected, is_connected tells success from failure.
+ */
+pub fn connect(peer: &PeerAddress, transport: Transport, utp: Option<&UtpSocket>) -> io::Result<Box<PeerStream>> {
+    let addr = SocketAddr::new(peer.ip, peer.port);
+
+    match (transport, utp) {
+        (Transport::Tcp, _) => Ok(Box::new(TcpStream::connect(&addr)?)),
+        (Transport::Utp, Some(utp)) => {
+            let stream = utp.connect(addr)?;
+            stream.set_nonblocking(true)?;
+            Ok(Box::new(stream))
+        },
+        (Transport::Utp, None) => Err(io::Error::new(io::ErrorKind::NotConnected, "No uTP socket"))
+    }
+}
+
+const READ_CHUNK_SIZE: usize = 16384;
+
+/**
+ * A peer stream with the buffering the event loop needs. Whatever the socket has is read into
+ * input and whatever is written queues in output until the socket takes it, encrypted on the way
+ * through once RC4 has been negotiated.
+ */
+pub struct Wire {
+    stream: Box<PeerStream>,
+    input: Vec<u8>, /* Received and decrypted, not yet consumed */
+    output: Vec<u8>, /* Encrypted, waiting for the socket */
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+    eof: bool
+}
+
+impl Wire {
+    pub fn new(stream: Box<PeerStream>) -> Wire {
+        Wire {
+            stream: stream,
+            input: Vec::new(),
+            output: Vec::new(),
+            encrypt: None,
+            decrypt: None,
+            eof: false
+        }
+    }
+
+    pub fn stream(&self) -> &PeerStream {
+        &*self.stream
+    }
+
+    /** Bytes received and not yet read, which a handshake parser may consume directly **/
+    pub fn input(&mut self) -> &mut Vec<u8> {
+        &mut self.input
+    }
+
+    /** Encrypt everything written from now on, and decrypt everything received after input **/
+    pub fn set_ciphers(&mut self, encrypt: Rc4, decrypt: Rc4) {
+        self.encrypt = Some(encrypt);
+        self.decrypt = Some(decrypt);
+    }
+
+    /** True once the peer has closed its side **/
+    pub fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /** Read everything the socket has until it would block **/
+    pub fn receive(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+
+        while !self.eof {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    if let Some(ref mut decrypt) = self.decrypt {
+                        decrypt.apply(&mut chunk[0..n]);
+                    }
+
+                    self.input.extend_from_slice(&chunk[0..n]);
+                },
+                Err(ref e) if e.kind() == WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(())
+    }
+
+    /** Write queued output until the socket would block **/
+    pub fn send(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "Peer stopped accepting data")),
+                Ok(n) => {
+                    self.output.drain(0..n);
+                },
+                Err(ref e) if e.kind() == WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for Wire {
+    /** Buffered input only, WouldBlock until more is received **/
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.input.is_empty() {
+            return if self.eof { Ok(0) } else { Err(io::Error::new(WouldBlock, "Nothing received")) };
+        }
+
+        let count = buf.len().min(self.input.len());
+        buf[0..count].copy_from_slice(&self.input[0..count]);
+        self.input.drain(0..count);
+        Ok(count)
+    }
+}
+
+impl Write for Wire {
+    /** Always takes everything, it is sent as the socket allows **/
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = self.output.len();
+        self.output.extend_from_slice(buf);
+
+        if let Some(ref mut encrypt) = self.encrypt {
+            encrypt.apply(&mut self.output[start..]);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use stream::{Wire, PeerStream};
+    use mse::Rc4;
+    use mio::net::{TcpListener, TcpStream};
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn encrypted_wire_over_loopback() {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let ours = TcpStream::connect(&listener.local_addr().unwrap()).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut accepted = None;
+
+        while accepted.is_none() && Instant::now() < deadline {
+            accepted = listener.accept().ok().map(|(stream, _)| stream);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut a = Wire::new(Box::new(ours));
+        let mut b = Wire::new(Box::new(accepted.unwrap()));
+
+        a.write_all(b"plain").unwrap();
+        a.set_ciphers(Rc4::new(b"key"), Rc4::new(b"unused"));
+        a.write_all(b" and secret").unwrap();
+        assert!(a.stream().is_connected().unwrap());
+
+        let mut received = Vec::new();
+
+        while received.len() < 16 && Instant::now() < deadline {
+            a.send().unwrap();
+            b.receive().unwrap();
+            received.extend(b.input().drain(..));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(&received[0..5], b"plain");
+        Rc4::new(b"key").apply(&mut received[5..]);
+        assert_eq!(&received[5..], b" and secret");
+
+        //Nothing more has arrived, and a closed peer reads as the end of the stream
+        assert_eq!(b.read(&mut [0; 1]).unwrap_err().kind(), ::std::io::ErrorKind::WouldBlock);
+        drop(a);
+
+        while !b.is_eof() && Instant::now() < deadline {
+            b.receive().unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(b.read(&mut [0; 1]).unwrap(), 0);
+    }
+}
@@ -8,12 +8,31 @@ use peer_id::gen_peer_id;
 #[derive(Clone)]
 pub struct Info {
     pub name: String,
-    pub announce: String,
+    pub announce: String, /* Empty for trackerless torrents, which find peers through the DHT */
     pub piece_length: usize,
     pub pieces: Vec<Vec<u8>>,
     pub files: Vec<FileInfo>,
     pub info_hash: Vec<u8>,
-    pub peer_id: Vec<u8>
+    pub peer_id: Vec<u8>,
+    pub private: bool /* BEP 27, peers may only come from the tracker */
+}
+
+impl Info {
+    /** Total size of the torrent's content **/
+    pub fn length(&self) -> usize {
+        self.files.iter().map(|f| f.length).sum()
+    }
+}
+
+/** Size of a piece, the final piece may be short **/
+pub fn piece_size(piece: usize, piece_length: usize, total_length: usize) -> usize {
+    let start = piece * piece_length;
+
+    if start + piece_length > total_length {
+        total_length.saturating_sub(start)
+    } else {
+        piece_length
+    }
 }
 
 #[derive(Debug)]
@@ -70,7 +89,6 @@ fn extract_pieces(pieces: &Entry) -> Result<Vec<Vec<u8>>, &'static str> {
 
 pub fn prepare(torrent: &Entry) -> Result<Info, &'static str> {
     let info = torrent.field("info")?;
-    let announce = torrent.field("announce")?;
     let name = info.field("name")?;
     let piece_length = info.field("piece length")?;
     let files = info.field("files");
@@ -82,12 +100,13 @@ pub fn prepare(torrent: &Entry) -> Result<Info, &'static str> {
 
     let mut extracted = Info {
         name: name.to_string(),
-        announce: announce.to_string(),
+        announce: torrent.field("announce").map(|a| a.to_string()).unwrap_or(String::new()),
         piece_length: piece_length.as_usize()?,
         pieces: pieces,
         files: Vec::new(),
         info_hash: info_digest.digest().bytes().to_vec(),
         peer_id: gen_peer_id(),
+        private: info.field("private").and_then(|p| p.as_usize()).map(|p| p == 1).unwrap_or(false)
     }; 
 
     if files.is_ok() {
@@ -5,17 +5,24 @@
 use std::path::Path;
 use std::fs::File;
 use std::io;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::fs::OpenOptions;
 use std::io::{Seek, SeekFrom};
+use std::sync::mpsc::Sender;
+use std::sync::mpsc;
+use std::thread;
+use mio_extras::channel;
 use bitfield::Bitfield;
+use torrent::piece_size;
+use sha1;
 
 pub struct TorrentData {
     pub data_path: String,
     pub handle: File,
     pub have: Bitfield,
     pub pieces: Vec<Vec<u8>>, //Sha1 hashes of each piece of the torrent
-    pub piece_size: usize
+    pub piece_size: usize,
+    pub length: usize
 }
 
 fn zeros(size: usize) -> Vec<u8> {
@@ -23,7 +30,7 @@ fn zeros(size: usize) -> Vec<u8> {
 }
 
 impl TorrentData {
-    pub fn allocate(name: &str, pieces: Vec<Vec<u8>>, piece_size: usize) -> Result<TorrentData, io::Error> {
+    pub fn allocate(name: &str, pieces: Vec<Vec<u8>>, piece_size: usize, length: usize) -> Result<TorrentData, io::Error> {
 
         if !Path::new(name).exists() {
             println!("Pre-allocating space for the torrent");
@@ -44,19 +51,82 @@ impl TorrentData {
         Ok(TorrentData {
             data_path: name.to_string(),
             handle: OpenOptions::new().read(true).write(true).open(name)?,
-            have: Bitfield::new((0..(pieces.len() / 8) + 1).map(|_| 0).collect()),
+            have: Bitfield::new(pieces.len()),
             pieces: pieces,
-            piece_size: piece_size
+            piece_size: piece_size,
+            length: length
         })
     }
 
+    /** Write a whole piece, which must match its hash **/
     pub fn write(&mut self, piece: usize, data: &[u8]) -> io::Result<()> {
-        //println!("TODO: TorrentData preserve handle");
-        //println!("TODO: TorrentData check piece hash");
- 
+        if piece >= self.pieces.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Write to piece {} out of range", piece)));
+        }
+
+        let data = &data[0..piece_size(piece, self.piece_size, self.length).min(data.len())];
+
+        let mut digest = sha1::Sha1::new();
+        digest.update(data);
+
+        if digest.digest().bytes().to_vec() != self.pieces[piece] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Piece {} failed hash check", piece)));
+        }
+
         self.handle.seek(SeekFrom::Start((piece * self.piece_size) as u64))?;
         self.handle.write(data)?;
-        self.have.set(piece); 
+        self.have.set(piece).unwrap(); 
         Ok(())
     }
+
+    /** Read part of a piece we have, used to serve peer requests **/
+    pub fn read(&mut self, piece: usize, begin: usize, length: usize) -> io::Result<Vec<u8>> {
+        if !self.have.get(piece) || begin + length > piece_size(piece, self.piece_size, self.length) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Read of {} {} {} unavailable", piece, begin, length)));
+        }
+
+        let mut data = vec![0; length];
+        self.handle.seek(SeekFrom::Start((piece * self.piece_size + begin) as u64))?;
+        self.handle.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+/** Work for the disk thread **/
+pub enum DiskRequest {
+    Write(usize, Vec<u8>), /* A completed piece, hashed before it is written */
+    Read(usize, usize, usize, usize) /* Tag returned with the block, then piece, begin and length */
+}
+
+pub enum DiskResult {
+    Written(usize, Vec<u8>, io::Result<()>), /* The piece's data comes back for trust to look at */
+    Read(usize, usize, usize, io::Result<Vec<u8>>)
+}
+
+/**
+ * Hash and write pieces, and read blocks, on a thread of their own so a slow disk never holds up
+ * the event loop. Results arrive on a channel that can be registered with the loop, the thread
+ * exits once the request sender is dropped.
+ */
+pub fn disk_thread(mut data: TorrentData) -> (Sender<DiskRequest>, channel::Receiver<DiskResult>) {
+    let (send, requests) = mpsc::channel();
+    let (results, recv) = channel::channel();
+
+    thread::spawn(move || {
+        for request in requests {
+            let result = match request {
+                DiskRequest::Write(piece, block) => {
+                    let written = data.write(piece, &block);
+                    DiskResult::Written(piece, block, written)
+                },
+                DiskRequest::Read(tag, piece, begin, length) => DiskResult::Read(tag, piece, begin, data.read(piece, begin, length))
+            };
+
+            if results.send(result).is_err() {
+                break;
+            }
+        }
+    });
+
+    (send, recv)
 }
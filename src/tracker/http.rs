@@ -74,9 +74,13 @@ pub fn http_tracker_extract_peers(peers: &bencoder::Entry, send: &Sender<Tracker
         //Dictionary model, each entry has an ip and a port, ip might be IPv6 or IPv4
     }
 
-    send.send(TrackerState::Announced(extracted));
+    send.send(TrackerState::Announced(extracted)).ok();
 }
 
+/**
+ * Announce on a loop from a thread of its own. reqwest only offers blocking requests, so HTTP
+ * trackers are left off the event loop, moving them would mean our own HTTP and TLS client.
+ */
 pub fn http_tracker(info: &Info, peer_port: u16, _: u16, send: Sender<TrackerState>, recv: Receiver<TrackerState>) {
 
     loop {
@@ -84,7 +88,7 @@ pub fn http_tracker(info: &Info, peer_port: u16, _: u16, send: Sender<TrackerSta
         let announce_resp = http_tracker_do_announce(info, peer_port);
 
         if announce_resp.is_err() {
-            send.send(TrackerState::Close("Announce resp error".to_string()));
+            send.send(TrackerState::Close("Announce resp error".to_string())).ok();
             return;
         }
 
@@ -99,13 +103,14 @@ pub fn http_tracker(info: &Info, peer_port: u16, _: u16, send: Sender<TrackerSta
         let interval = announce_resp.field("interval");
 
         if interval.is_err() {
-            send.send(TrackerState::Close("No interval error".to_string()));
+            send.send(TrackerState::Close("No interval error".to_string())).ok();
             return;
         }
 
         let interval = interval.unwrap();
 
-        thread::sleep(time::Duration::from_millis(interval.as_usize().unwrap() as u64));
+        //The interval is in seconds
+        thread::sleep(time::Duration::from_secs(interval.as_usize().unwrap() as u64));
     } 
 }
 
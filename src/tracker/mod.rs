@@ -2,6 +2,7 @@ use torrent::Info;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
 use std::thread;
+use event_loop::EventLoop;
 
 mod state;
 mod http;
@@ -10,26 +11,29 @@ mod udp;
 pub use tracker::state::{TrackerState, PeerAddress};
 
 pub fn tracker_thread(info: &Info, peer_port: u16, tracker_port: u16, send: Sender<TrackerState>, recv: Receiver<TrackerState>) {
-    if info.announce.starts_with("udp://") {
-        udp::udp_tracker(info, peer_port, tracker_port, send, recv); 
-    } else if info.announce.starts_with("http://") || info.announce.starts_with("https://") {
+    if info.announce.starts_with("http://") || info.announce.starts_with("https://") {
         http::http_tracker(info, peer_port, tracker_port, send, recv);
     } else {
         send.send(
             TrackerState::Close("Unknown tracker protocol".to_string())
-        );
+        ).ok();
     }
 }
 
-pub fn connect(info: &Info, peer_port: u16, tracker_port: u16) -> (Sender<TrackerState>, Receiver<TrackerState>) {
-     let info = info.clone();
-
+pub fn connect(events: &EventLoop, info: &Info, peer_port: u16, tracker_port: u16) -> (Sender<TrackerState>, Receiver<TrackerState>) {
     let (thread_send, main_recv): (Sender<TrackerState>, Receiver<TrackerState>) = mpsc::channel();
     let (main_send, thread_recv): (Sender<TrackerState>, Receiver<TrackerState>) = mpsc::channel();
 
-    thread::spawn(move || {
-        tracker_thread(&info, peer_port, tracker_port, thread_send, thread_recv);
-    });
-   
+    //UDP trackers run on the event loop. HTTP requests block so they keep a thread of their own, moving them is out of scope
+    if info.announce.starts_with("udp://") {
+        udp::udp_tracker(events, info, peer_port, tracker_port, thread_send);
+    } else {
+        let info = info.clone();
+
+        thread::spawn(move || {
+            tracker_thread(&info, peer_port, tracker_port, thread_send, thread_recv);
+        });
+    }
+
     (main_send, main_recv)
 }
@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 pub enum TrackerState {
     Connected(u64),
@@ -7,8 +7,42 @@ pub enum TrackerState {
 }
 
 #[derive(Debug)]
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct PeerAddress {
     pub ip: IpAddr,
     pub port: u16
 }
+
+impl PeerAddress {
+    /** Compact form, 4 or 16 address bytes followed by the port, all big-endian **/
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut data = match self.ip {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec()
+        };
+
+        data.push((self.port >> 8) as u8);
+        data.push(self.port as u8);
+        data
+    }
+
+    /** Parse a list of compact addresses, 6 bytes each for IPv4 or 18 for IPv6 **/
+    pub fn from_compact_list(data: &[u8], v6: bool) -> Vec<PeerAddress> {
+        let size = if v6 { 18 } else { 6 };
+
+        data.chunks(size).filter(|c| c.len() == size).map(|c| {
+            let ip = if v6 {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&c[0..16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            } else {
+                IpAddr::V4(Ipv4Addr::new(c[0], c[1], c[2], c[3]))
+            };
+
+            PeerAddress {
+                ip: ip,
+                port: ((c[size - 2] as u16) << 8) | c[size - 1] as u16
+            }
+        }).collect()
+    }
+}
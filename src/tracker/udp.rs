@@ -1,11 +1,17 @@
 use torrent::Info;
 use std::io::Write;
+use std::io::ErrorKind::WouldBlock;
 use url::Url;
 use byteorder::{BE, ReadBytesExt, WriteBytesExt};
-use std::net::{IpAddr, Ipv4Addr, UdpSocket};
-use std::sync::mpsc::{Sender, Receiver};
-use std::{thread, time};
+use std::net;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use mio::Ready;
+use mio::net::UdpSocket;
+use rand::{thread_rng, Rng};
 use tracker::{TrackerState, PeerAddress};
+use event_loop::{EventLoop, Handler, Context, Event};
 
 /**
  * Error Handlers
@@ -161,85 +167,179 @@ impl Response for AnnounceResp {
     }
 }
 
-const CONNECT_RESP_SIZE: usize = 16;
 const ANNOUNCE_RESP_SIZE: usize = 20;
 const IP_SIZE: usize = 6;
 const NUM_WANT: usize = 50;
 
+/** An unanswered request is sent again after 15 * 2^n seconds, the tracker is given up on after a few **/
+const RETRY_SECS: u64 = 15;
+const MAX_RETRIES: u32 = 4;
+
 /**
  * Tracker Logic
  */
 
-fn udp_do_connect(url: &Url, socket: &mut UdpSocket) -> Result<ConnectResp, MsgError> {
-    let connect = ConnectCmd { action: 0, transaction_id: 23131 };
-    socket.send_to(&connect.serialize(), url).expect("couldn't send data");
-    let mut resp = [0; CONNECT_RESP_SIZE];
-    if let Ok(v) = socket.recv(&mut resp) {
-        ConnectResp::deserialize(23131, &resp[0..v])
-    } else {
-        Err("Could not receive".to_string())
-    }
+enum Step {
+    Connecting,
+    Announcing(u64), /* Connection id */
+    Waiting /* For the announce interval, connection ids expire long before it passes */
 }
 
-fn udp_do_announce(url: &Url, connection: u64, peer_port: u16, info_hash: &[u8], peer_id: &[u8], socket: &mut UdpSocket) -> Result<AnnounceResp, MsgError> {
-
-    let announce = AnnounceCmd {
-        connection_id: connection, 
-        transaction_id: 23131,
-        info_hash: info_hash.to_vec(),
-        peer_id: peer_id.to_vec(),
-        downloaded: 0,
-        left: 0,
-        uploaded: 0,
-        event: 0,
-        ip: 0,
-        key: 0,
-        num_want: NUM_WANT as u32,
-        port: peer_port as u16
-    };
+struct UdpTracker {
+    socket: UdpSocket,
+    tracker: SocketAddr,
+    info: Info,
+    peer_port: u16,
+    sender: Sender<TrackerState>,
+    step: Step,
+    transaction_id: u32,
+    retries: u32
+}
 
-    socket.send_to(&announce.serialize(), url).expect("couldn't send data");
-    let mut resp = [0; ANNOUNCE_RESP_SIZE + IP_SIZE + (IP_SIZE * NUM_WANT)];
-    
-    if let Ok(v) = socket.recv(&mut resp) {
-        Ok(AnnounceResp::deserialize(23131, &resp[0..v])?)
-    } else {
-        Err("Could not receive announce resp".to_string())
+impl UdpTracker {
+    /** Send the request for the current step under a new transaction id **/
+    fn request(&mut self, ctx: &mut Context) {
+        self.transaction_id = thread_rng().gen();
+
+        let msg = match self.step {
+            Step::Announcing(connection) => AnnounceCmd {
+                connection_id: connection,
+                transaction_id: self.transaction_id,
+                info_hash: self.info.info_hash.clone(),
+                peer_id: self.info.peer_id.clone(),
+                downloaded: 0,
+                left: 0,
+                uploaded: 0,
+                event: 0,
+                ip: 0,
+                key: 0,
+                num_want: NUM_WANT as u32,
+                port: self.peer_port
+            }.serialize(),
+            _ => ConnectCmd { action: 0, transaction_id: self.transaction_id }.serialize()
+        };
+
+        if let Err(e) = self.socket.send_to(&msg, &self.tracker) {
+            println!("UDP Tracker send failed because {}", e);
+        }
+
+        ctx.set_timeout(Duration::from_secs(RETRY_SECS << self.retries));
     }
-}
 
-pub fn udp_tracker(info: &Info, peer_port: u16, tracker_port: u16, sender: Sender<TrackerState>, recv: Receiver<TrackerState>) {
-    let announce = Url::parse(&info.announce.to_string());
-    let udp_addr = "0.0.0.0:".to_string() + &tracker_port.to_string();
+    fn receive(&mut self, ctx: &mut Context, data: &[u8]) -> Result<(), MsgError> {
+        match self.step {
+            Step::Connecting => {
+                let connection = ConnectResp::deserialize(self.transaction_id, data)?.connection_id;
+                self.sender.send(TrackerState::Connected(connection)).ok();
+                self.step = Step::Announcing(connection);
+                self.retries = 0;
+                self.request(ctx);
+            },
+            Step::Announcing(_) => {
+                let announced = AnnounceResp::deserialize(self.transaction_id, data)?;
+                self.sender.send(TrackerState::Announced(announced.peers)).ok();
+                self.step = Step::Waiting;
+                ctx.set_timeout(Duration::from_secs(announced.interval as u64));
+            },
+            Step::Waiting => {}
+        }
 
-    let announce = announce.unwrap();
+        Ok(())
+    }
+}
 
-    println!("UDP Tracker {} to {}", udp_addr, announce);
+impl Handler for UdpTracker {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        if let Err(e) = ctx.register(0, &self.socket, Ready::readable()) {
+            self.sender.send(TrackerState::Close(e.to_string())).ok();
+            return false;
+        }
 
-    let mut socket = UdpSocket::bind(udp_addr).expect("couldn't bind to address");
+        self.request(ctx);
+        true
+    }
 
-    let connection = udp_do_connect(&announce, &mut socket);
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        match event {
+            Event::Ready(..) => {
+                let mut resp = [0; ANNOUNCE_RESP_SIZE + IP_SIZE + (IP_SIZE * NUM_WANT)];
+
+                loop {
+                    match self.socket.recv_from(&mut resp) {
+                        Ok((len, from)) if from == self.tracker => {
+                            //Answers to requests we have since sent again are ignored
+                            if let Err(v) = self.receive(ctx, &resp[0..len]) {
+                                println!("UDP Tracker ignored a response because {}", v);
+                            }
+                        },
+                        Ok(_) => {},
+                        Err(ref e) if e.kind() == WouldBlock => break,
+                        Err(e) => {
+                            self.sender.send(TrackerState::Close(e.to_string())).ok();
+                            return false;
+                        }
+                    }
+                }
+            },
+            Event::Timeout => {
+                match self.step {
+                    Step::Waiting => {
+                        self.step = Step::Connecting;
+                        self.retries = 0;
+                    },
+                    _ if self.retries >= MAX_RETRIES => {
+                        self.sender.send(TrackerState::Close("Tracker did not answer".to_string())).ok();
+                        return false;
+                    },
+                    _ => self.retries += 1
+                }
+
+                self.request(ctx);
+            }
+        }
 
-    if let Err(v) = connection {
-        sender.send(TrackerState::Close(v.clone())).unwrap();
-        return;
+        true
     }
+}
 
-    let connection = connection.unwrap().connection_id;
-    sender.send(TrackerState::Connected(connection));
+/**
+ * Connect and announce to a UDP tracker from the event loop, sending it again every interval
+ */
+pub fn udp_tracker(events: &EventLoop, info: &Info, peer_port: u16, tracker_port: u16, sender: Sender<TrackerState>) {
+    let udp_addr = "0.0.0.0:".to_string() + &tracker_port.to_string();
 
-    loop {
-        let announced = udp_do_announce(&announce, connection, peer_port, &info.info_hash, &info.peer_id, &mut socket); 
+    //Resolved once up front, the event loop must not wait on DNS
+    let tracker = Url::parse(&info.announce)
+        .map_err(|e| e.to_string())
+        .and_then(|announce| cerr(announce.to_socket_addrs()))
+        .and_then(|mut addrs| addrs.find(|a| a.is_ipv4()).ok_or("Tracker has no IPv4 address".to_string()));
 
-        if let Err(v) = announced {
-            sender.send(TrackerState::Close(v.clone())).unwrap();
+    let tracker = match tracker {
+        Ok(tracker) => tracker,
+        Err(v) => {
+            sender.send(TrackerState::Close(v)).ok();
             return;
         }
+    };
 
-        let announced = announced.unwrap();
+    println!("UDP Tracker {} to {}", udp_addr, tracker);
 
-        sender.send(TrackerState::Announced(announced.peers.clone()));
-        
-        thread::sleep(time::Duration::from_millis(announced.interval as u64));
-    }
+    let socket = match cerr(net::UdpSocket::bind(udp_addr)).and_then(|socket| cerr(UdpSocket::from_socket(socket))) {
+        Ok(socket) => socket,
+        Err(v) => {
+            sender.send(TrackerState::Close(v)).ok();
+            return;
+        }
+    };
+
+    events.spawn(Box::new(UdpTracker {
+        socket: socket,
+        tracker: tracker,
+        info: info.clone(),
+        peer_port: peer_port,
+        sender: sender,
+        step: Step::Connecting,
+        transaction_id: 0,
+        retries: 0
+    }));
 }
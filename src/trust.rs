@@ -0,0 +1,174 @@
+/**
+ * Trust scores for the peers that send us pieces, used to ban addresses that feed us corrupt data.
+ * Every peer that contributed to a piece failing its hash check loses trust, passing pieces earn a little back.
+ * With smart ban the blocks of a failed piece are remembered and compared against the
+ * good copy once it arrives, so the peer that actually sent bad data can be banned outright.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use sha1;
+use picker::BLOCK_SIZE;
+
+/** Trust lost by each contributor to a piece that fails its hash check **/
+const FAIL_PENALTY: i32 = 2;
+
+/** Trust lost by a peer that sent every block of a failed piece, a one-off bit flip shouldn't cost a ban **/
+const SOLE_FAIL_PENALTY: i32 = 4;
+
+/** Trust regained by each contributor to a piece that passes **/
+const PASS_REWARD: i32 = 1;
+
+/** Good behaviour can't buy more than this much tolerance **/
+const MAX_TRUST: i32 = 8;
+
+fn block_digests(data: &[u8]) -> Vec<Vec<u8>> {
+    data.chunks(BLOCK_SIZE).map(|block| {
+        let mut digest = sha1::Sha1::new();
+        digest.update(block);
+        digest.digest().bytes().to_vec()
+    }).collect()
+}
+
+fn distinct(contributors: &[IpAddr]) -> Vec<IpAddr> {
+    let mut ips = contributors.to_vec();
+    ips.sort();
+    ips.dedup();
+    ips
+}
+
+pub struct Trust {
+    threshold: i32,
+    smart_ban: bool,
+    scores: HashMap<IpAddr, i32>,
+    banned: HashSet<IpAddr>,
+    failed_blocks: HashMap<usize, Vec<(usize, IpAddr, Vec<u8>)>> /* Piece to (block, sender, digest) of failed copies */
+}
+
+impl Trust {
+    pub fn new(threshold: i32, smart_ban: bool) -> Trust {
+        Trust {
+            threshold: threshold,
+            smart_ban: smart_ban,
+            scores: HashMap::new(),
+            banned: HashSet::new(),
+            failed_blocks: HashMap::new()
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    pub fn score(&self, ip: &IpAddr) -> i32 {
+        self.scores.get(ip).cloned().unwrap_or(0)
+    }
+
+    fn ban(&mut self, ip: IpAddr, banned: &mut Vec<IpAddr>) {
+        if self.banned.insert(ip) {
+            banned.push(ip);
+        }
+    }
+
+    /**
+     * A piece failed its hash check, contributors holds the sender of each block.
+     * Returns addresses that are newly banned.
+     */
+    pub fn piece_failed(&mut self, piece: usize, data: &[u8], contributors: &[IpAddr]) -> Vec<IpAddr> {
+        let mut banned = Vec::new();
+        let senders = distinct(contributors);
+
+        //Nobody else could have corrupted it
+        let penalty = if senders.len() == 1 { SOLE_FAIL_PENALTY } else { FAIL_PENALTY };
+
+        for ip in senders {
+            let score = {
+                let score = self.scores.entry(ip).or_insert(0);
+                *score -= penalty;
+                *score
+            };
+
+            if score <= -self.threshold {
+                self.ban(ip, &mut banned);
+            }
+        }
+
+        if self.smart_ban {
+            let blocks = self.failed_blocks.entry(piece).or_insert(Vec::new());
+
+            for (idx, digest) in block_digests(data).into_iter().enumerate() {
+                blocks.push((idx, contributors[idx], digest));
+            }
+        }
+
+        banned
+    }
+
+    /**
+     * A piece passed its hash check. Returns addresses that are newly banned because
+     * smart ban found they had sent a different copy of one of its blocks.
+     */
+    pub fn piece_passed(&mut self, piece: usize, data: &[u8], contributors: &[IpAddr]) -> Vec<IpAddr> {
+        let mut banned = Vec::new();
+
+        for ip in distinct(contributors) {
+            let score = self.scores.entry(ip).or_insert(0);
+            *score = (*score + PASS_REWARD).min(MAX_TRUST);
+        }
+
+        if let Some(failed) = self.failed_blocks.remove(&piece) {
+            let good = block_digests(data);
+
+            for (idx, ip, digest) in failed {
+                if good.get(idx) != Some(&digest) {
+                    self.ban(ip, &mut banned);
+                }
+            }
+        }
+
+        banned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use trust::Trust;
+    use picker::BLOCK_SIZE;
+    use std::net::IpAddr;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn bans_after_repeated_failures() {
+        let mut trust = Trust::new(7, false);
+        let data = vec![0; BLOCK_SIZE * 2];
+
+        //A sole contributor loses more, but survives a single failure
+        assert!(trust.piece_failed(0, &data, &[ip(1), ip(1)]).is_empty());
+        assert_eq!(trust.piece_failed(1, &data, &[ip(1), ip(1)]), vec![ip(1)]);
+
+        assert!(trust.piece_failed(1, &data, &[ip(2), ip(3)]).is_empty());
+        trust.piece_passed(1, &data, &[ip(2), ip(3)]);
+        assert_eq!(trust.score(&ip(2)), -1);
+
+        for piece in 2..4 {
+            assert!(trust.piece_failed(piece, &data, &[ip(2), ip(3)]).is_empty());
+        }
+
+        assert_eq!(trust.piece_failed(4, &data, &[ip(2), ip(3)]), vec![ip(2), ip(3)]);
+        assert!(trust.is_banned(&ip(3)) && !trust.is_banned(&ip(4)));
+    }
+
+    #[test]
+    fn smart_ban_finds_the_bad_block() {
+        let mut trust = Trust::new(7, true);
+        let mut bad = vec![1; BLOCK_SIZE * 2];
+        bad[BLOCK_SIZE] = 2;
+
+        assert!(trust.piece_failed(0, &bad, &[ip(1), ip(2)]).is_empty());
+        assert_eq!(trust.piece_passed(0, &vec![1; BLOCK_SIZE * 2], &[ip(3), ip(3)]), vec![ip(2)]);
+        assert!(!trust.is_banned(&ip(1)));
+    }
+}
@@ -0,0 +1,419 @@
+/**
+ * State of one uTP connection: reliable in-order delivery over UDP with a send window set by
+ * LEDBAT, which grows while the one way delay stays near its minimum and shrinks as queues
+ * build up, so uTP gives way to other traffic on the link.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{UdpSocket, SocketAddr};
+use std::time::{Duration, Instant};
+use utp::packet::{Packet, PacketType, HEADER_LEN};
+
+/** Largest payload, keeping packets under a typical MTU **/
+pub const MAX_PAYLOAD: usize = 1400 - HEADER_LEN;
+
+/** Queueing delay LEDBAT aims for, and how quickly the window may grow towards it **/
+const TARGET_DELAY_MICROS: f64 = 100_000.0;
+const MAX_WINDOW_INCREASE_PER_RTT: f64 = 3000.0;
+
+const MIN_WINDOW: f64 = MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = (1024 * 1024) as f64;
+
+/** Bytes we buffer for the reader, advertised to the sender as our window **/
+pub const RECV_BUFFER: usize = 1024 * 1024;
+
+/** Written bytes waiting to be sent before writers block **/
+pub const SEND_BUFFER: usize = 256 * 1024;
+
+const MIN_TIMEOUT_MS: f64 = 500.0;
+const MAX_TRANSMISSIONS: u32 = 6;
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+
+/** Base delay is the minimum seen over the last two of these, so a route change is noticed **/
+const BASE_DELAY_INTERVAL_SECS: u64 = 60;
+
+/** a is before b allowing for sequence numbers wrapping **/
+fn seq_before(a: u16, b: u16) -> bool {
+    let diff = b.wrapping_sub(a);
+    diff != 0 && diff < 0x8000
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    SynSent,
+    Connected,
+    Closed
+}
+
+struct Sent {
+    kind: PacketType,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent: Instant,
+    transmissions: u32
+}
+
+pub struct Connection {
+    socket: UdpSocket,
+    epoch: Instant, /* Our microsecond clock counts from here */
+    pub addr: SocketAddr,
+    send_id: u16,
+    pub state: State,
+    pub error: Option<io::ErrorKind>,
+
+    seq_nr: u16, /* Next sequence number we send */
+    ack_nr: u16, /* Last sequence number received in order */
+
+    pending: VecDeque<u8>, /* Written but not yet sent */
+    in_flight: VecDeque<Sent>,
+    window: f64, /* LEDBAT congestion window in bytes */
+    peer_window: usize,
+    last_ack: u16,
+    duplicate_acks: usize,
+    rtt: f64,
+    rtt_var: f64,
+    timeout: Duration,
+
+    reply_micros: u32, /* Delay to echo back in timestamp_diff */
+    base_delay: (u32, u32), /* Minimum delay this interval and last */
+    base_delay_since: Instant,
+
+    pub received: VecDeque<u8>,
+    out_of_order: HashMap<u16, (PacketType, Vec<u8>)>,
+    out_of_order_bytes: usize, /* Counted against RECV_BUFFER along with received */
+    eof: bool,
+
+    pub closing: bool, /* Send a FIN once everything written has gone */
+    fin_sent: bool,
+
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+    pub nonblocking: bool /* Reads and writes that can't proceed fail with WouldBlock rather than waiting */
+}
+
+impl Connection {
+    fn new(socket: UdpSocket, epoch: Instant, addr: SocketAddr, send_id: u16, state: State, seq_nr: u16, ack_nr: u16) -> Connection {
+        Connection {
+            socket: socket,
+            epoch: epoch,
+            addr: addr,
+            send_id: send_id,
+            state: state,
+            error: None,
+            seq_nr: seq_nr,
+            ack_nr: ack_nr,
+            pending: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            window: MIN_WINDOW * 2.0,
+            peer_window: RECV_BUFFER,
+            last_ack: 0,
+            duplicate_acks: 0,
+            rtt: 0.0,
+            rtt_var: 0.0,
+            timeout: Duration::from_millis(1000),
+            reply_micros: 0,
+            base_delay: (u32::max_value(), u32::max_value()),
+            base_delay_since: Instant::now(),
+            received: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_bytes: 0,
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            read_timeout: None,
+            write_timeout: None,
+            nonblocking: false
+        }
+    }
+
+    /** Start a connection, the SYN goes out with our receive id and later packets with the one above it **/
+    pub fn connect(socket: UdpSocket, epoch: Instant, addr: SocketAddr, recv_id: u16) -> Connection {
+        let mut conn = Connection::new(socket, epoch, addr, recv_id.wrapping_add(1), State::SynSent, 1, 0);
+        conn.send_new(PacketType::Syn, Vec::new());
+        conn
+    }
+
+    /** Answer a SYN, our first data packet reuses the sequence number of the ack **/
+    pub fn accept(socket: UdpSocket, epoch: Instant, addr: SocketAddr, syn: &Packet, seq_nr: u16) -> Connection {
+        let mut conn = Connection::new(socket, epoch, addr, syn.connection_id, State::Connected, seq_nr, syn.seq_nr);
+        conn.reply_micros = conn.now_micros().wrapping_sub(syn.timestamp);
+        conn.send_ack();
+        conn
+    }
+
+    fn now_micros(&self) -> u32 {
+        let elapsed = self.epoch.elapsed();
+        (elapsed.as_secs() * 1_000_000 + elapsed.subsec_nanos() as u64 / 1000) as u32
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.eof && self.received.is_empty()
+    }
+
+    fn send_packet(&self, kind: PacketType, seq_nr: u16, payload: &[u8]) {
+        let packet = Packet {
+            kind: kind,
+            connection_id: if kind == PacketType::Syn { self.send_id.wrapping_sub(1) } else { self.send_id },
+            timestamp: self.now_micros(),
+            timestamp_diff: self.reply_micros,
+            wnd_size: RECV_BUFFER.saturating_sub(self.buffered()) as u32,
+            seq_nr: seq_nr,
+            ack_nr: self.ack_nr,
+            payload: payload.to_vec()
+        };
+
+        //Lost packets are recovered by retransmission
+        self.socket.send_to(&packet.encode(), self.addr).ok();
+    }
+
+    pub fn send_ack(&self) {
+        self.send_packet(PacketType::State, self.seq_nr, &[]);
+    }
+
+    fn send_new(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+        self.send_packet(kind, seq_nr, &payload);
+
+        self.in_flight.push_back(Sent {
+            kind: kind,
+            seq_nr: seq_nr,
+            payload: payload,
+            sent: Instant::now(),
+            transmissions: 1
+        });
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight.iter().map(|s| s.payload.len()).sum()
+    }
+
+    /** Send as much written data as the congestion and receive windows allow, then a FIN once closing **/
+    pub fn flush(&mut self) {
+        if self.state != State::Connected {
+            return;
+        }
+
+        let window = (self.window as usize).min(self.peer_window);
+
+        while !self.pending.is_empty() {
+            let size = self.pending.len().min(MAX_PAYLOAD);
+
+            //Always allow one packet so a tiny window still probes the link
+            if !self.in_flight.is_empty() && self.bytes_in_flight() + size > window {
+                break;
+            }
+
+            let payload: Vec<u8> = self.pending.drain(0..size).collect();
+            self.send_new(PacketType::Data, payload);
+        }
+
+        if self.closing && self.pending.is_empty() && !self.fin_sent {
+            self.fin_sent = true;
+            self.send_new(PacketType::Fin, Vec::new());
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        self.state = State::Closed;
+        self.error = Some(kind);
+    }
+
+    /** Adjust the window towards the target queueing delay for bytes newly acked **/
+    fn ledbat(&mut self, delay: u32, bytes_acked: usize) {
+        if self.base_delay_since.elapsed() > Duration::from_secs(BASE_DELAY_INTERVAL_SECS) {
+            self.base_delay = (u32::max_value(), self.base_delay.0);
+            self.base_delay_since = Instant::now();
+        }
+
+        self.base_delay.0 = self.base_delay.0.min(delay);
+        let base = self.base_delay.0.min(self.base_delay.1);
+
+        let queueing = delay.wrapping_sub(base) as f64;
+        let off_target = (TARGET_DELAY_MICROS - queueing) / TARGET_DELAY_MICROS;
+        let window_factor = (bytes_acked as f64).min(self.window) / self.window.max(bytes_acked as f64);
+
+        self.window += MAX_WINDOW_INCREASE_PER_RTT * off_target * window_factor;
+        self.window = self.window.max(MIN_WINDOW).min(MAX_WINDOW);
+    }
+
+    fn rtt_sample(&mut self, sent: Instant) {
+        let elapsed = sent.elapsed();
+        let sample = elapsed.as_secs() as f64 * 1000.0 + elapsed.subsec_nanos() as f64 / 1_000_000.0;
+
+        if self.rtt == 0.0 {
+            self.rtt = sample;
+            self.rtt_var = sample / 2.0;
+        } else {
+            self.rtt_var += ((self.rtt - sample).abs() - self.rtt_var) / 4.0;
+            self.rtt += (sample - self.rtt) / 8.0;
+        }
+
+        self.timeout = Duration::from_millis((self.rtt + self.rtt_var * 4.0).max(MIN_TIMEOUT_MS) as u64);
+    }
+
+    fn process_ack(&mut self, packet: &Packet) {
+        let mut bytes_acked = 0;
+        let mut acked_any = false;
+
+        while self.in_flight.front().map(|s| !seq_before(packet.ack_nr, s.seq_nr)).unwrap_or(false) {
+            let sent = self.in_flight.pop_front().unwrap();
+
+            //Retransmitted packets give ambiguous round trip times
+            if sent.transmissions == 1 {
+                self.rtt_sample(sent.sent);
+            }
+
+            bytes_acked += sent.payload.len();
+            acked_any = true;
+        }
+
+        if acked_any {
+            self.duplicate_acks = 0;
+
+            if bytes_acked > 0 && packet.timestamp_diff != 0 {
+                self.ledbat(packet.timestamp_diff, bytes_acked);
+            }
+        } else if packet.kind == PacketType::State && packet.ack_nr == self.last_ack && !self.in_flight.is_empty() {
+            //Three duplicate acks mean the packet after them was lost
+            self.duplicate_acks += 1;
+
+            if self.duplicate_acks == 3 {
+                self.window = (self.window / 2.0).max(MIN_WINDOW);
+                self.retransmit_first();
+            }
+        }
+
+        self.last_ack = packet.ack_nr;
+    }
+
+    fn retransmit_first(&mut self) {
+        if let Some((kind, seq_nr, payload)) = self.in_flight.front().map(|s| (s.kind, s.seq_nr, s.payload.clone())) {
+            self.send_packet(kind, seq_nr, &payload);
+
+            let first = self.in_flight.front_mut().unwrap();
+            first.sent = Instant::now();
+            first.transmissions += 1;
+        }
+    }
+
+    /** Take a packet in sequence, anything it unblocks from the out of order buffer follows it **/
+    fn deliver(&mut self, kind: PacketType, payload: Vec<u8>) {
+        let mut next = Some((kind, payload));
+
+        while let Some((kind, payload)) = next {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+
+            if kind == PacketType::Fin {
+                self.eof = true;
+                self.out_of_order.clear();
+                self.out_of_order_bytes = 0;
+                return;
+            }
+
+            self.received.extend(payload);
+            next = self.out_of_order.remove(&self.ack_nr.wrapping_add(1));
+
+            if let Some((_, ref payload)) = next {
+                self.out_of_order_bytes -= payload.len();
+            }
+        }
+    }
+
+    /** Bytes held for the reader, whether in order or waiting on a gap **/
+    fn buffered(&self) -> usize {
+        self.received.len() + self.out_of_order_bytes
+    }
+
+    pub fn on_packet(&mut self, packet: Packet) {
+        self.reply_micros = self.now_micros().wrapping_sub(packet.timestamp);
+
+        if packet.kind == PacketType::Reset {
+            self.fail(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        if self.state == State::SynSent {
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+        }
+
+        self.peer_window = packet.wnd_size as usize;
+        self.process_ack(&packet);
+
+        match packet.kind {
+            PacketType::Data | PacketType::Fin if !self.eof => {
+                let expected = self.ack_nr.wrapping_add(1);
+
+                if packet.seq_nr == expected {
+                    self.deliver(packet.kind, packet.payload);
+                } else if seq_before(expected, packet.seq_nr) && !self.out_of_order.contains_key(&packet.seq_nr) && self.buffered() + packet.payload.len() <= RECV_BUFFER {
+                    self.out_of_order_bytes += packet.payload.len();
+                    self.out_of_order.insert(packet.seq_nr, (packet.kind, packet.payload));
+                }
+
+                self.send_ack();
+            },
+            PacketType::Data | PacketType::Fin => self.send_ack(),
+            _ => {}
+        }
+
+        self.flush();
+    }
+
+    /** Retransmit on timeout, giving up on a connection that stays silent **/
+    pub fn on_tick(&mut self) {
+        if self.state == State::Closed {
+            return;
+        }
+
+        let (expired, transmissions, syn) = match self.in_flight.front() {
+            Some(first) => (first.sent.elapsed() > self.timeout, first.transmissions, first.kind == PacketType::Syn),
+            None => (false, 0, false)
+        };
+
+        if expired {
+            let limit = if syn { MAX_SYN_TRANSMISSIONS } else { MAX_TRANSMISSIONS };
+
+            if transmissions >= limit {
+                self.fail(io::ErrorKind::TimedOut);
+                return;
+            }
+
+            self.window = MIN_WINDOW;
+            self.timeout = self.timeout * 2;
+            self.retransmit_first();
+        }
+
+        //Our FIN is only sent once the stream is dropped, so nobody is left to read after it is acked
+        if self.fin_sent && self.in_flight.is_empty() {
+            self.state = State::Closed;
+        }
+    }
+
+    /** Queue written bytes, returning how many fit in the send buffer **/
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let space = SEND_BUFFER.saturating_sub(self.pending.len());
+        let taken = data.len().min(space);
+        self.pending.extend(&data[0..taken]);
+        self.flush();
+        taken
+    }
+
+    /** Copy out received bytes, telling the sender when a full buffer has drained **/
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let was_full = RECV_BUFFER.saturating_sub(self.buffered()) < MAX_PAYLOAD;
+        let count = buf.len().min(self.received.len());
+
+        for (i, b) in self.received.drain(0..count).enumerate() {
+            buf[i] = b;
+        }
+
+        if was_full && count > 0 {
+            self.send_ack();
+        }
+
+        count
+    }
+}
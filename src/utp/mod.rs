@@ -0,0 +1,505 @@
+/**
+ * uTP (BEP 29), a reliable stream over UDP for peer connections. One UDP socket carries every
+ * connection, the event loop reads packets off it and routes them by address and connection id.
+ */
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{UdpSocket, SocketAddr};
+use std::sync::{Arc, Mutex, Condvar};
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+use mio;
+use mio::{Poll, Token, Ready, PollOpt, Evented, Registration, SetReadiness};
+use mio_extras::channel;
+use event_loop::{EventLoop, Handler, Context, Event};
+
+mod packet;
+mod connection;
+
+use utp::packet::{Packet, PacketType};
+use utp::connection::{Connection, State};
+
+/** How often retransmission timers are checked **/
+const TICK_MILLIS: u64 = 50;
+
+/** A connection shared between its stream and the socket's handler, which signals changes **/
+struct Shared {
+    conn: Mutex<Connection>,
+    changed: Condvar,
+    readiness: Mutex<Option<SetReadiness>> /* Wakes the event loop the stream is registered with */
+}
+
+impl Shared {
+    fn new(conn: Connection) -> Arc<Shared> {
+        Arc::new(Shared {
+            conn: Mutex::new(conn),
+            changed: Condvar::new(),
+            readiness: Mutex::new(None)
+        })
+    }
+
+    /** Wake blocked readers and writers, and the event loop if the stream is registered **/
+    fn notify(&self) {
+        self.changed.notify_all();
+
+        if let Some(ref readiness) = *self.readiness.lock().unwrap() {
+            readiness.set_readiness(Ready::readable() | Ready::writable()).ok();
+        }
+    }
+}
+
+type Connections = Arc<Mutex<HashMap<(SocketAddr, u16), Arc<Shared>>>>;
+
+/**
+ * A bound uTP socket, cloned handles share it
+ */
+#[derive(Clone)]
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    epoch: Instant,
+    connections: Connections
+}
+
+/**
+ * One uTP connection, read and written like a TcpStream and registered with an event loop like
+ * one. Dropping it closes the connection once everything written has been delivered.
+ */
+pub struct UtpStream {
+    shared: Arc<Shared>,
+    peer: SocketAddr,
+    registration: Mutex<Option<Registration>>
+}
+
+impl UtpStream {
+    fn new(shared: Arc<Shared>, peer: SocketAddr) -> UtpStream {
+        UtpStream {
+            shared: shared,
+            peer: peer,
+            registration: Mutex::new(None)
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    /** Whether the peer has answered our SYN yet, an error once the attempt has failed **/
+    pub fn is_connected(&self) -> io::Result<bool> {
+        let conn = self.shared.conn.lock().unwrap();
+
+        match conn.error {
+            Some(kind) => Err(io::Error::new(kind, "uTP connect failed")),
+            None => Ok(conn.state != State::SynSent)
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.shared.conn.lock().unwrap().nonblocking = nonblocking;
+        Ok(())
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.shared.conn.lock().unwrap().read_timeout = timeout;
+        Ok(())
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.shared.conn.lock().unwrap().write_timeout = timeout;
+        Ok(())
+    }
+}
+
+/** Wait on a connection's condition, false once the timeout has passed **/
+fn wait<'a>(shared: &'a Shared, conn: ::std::sync::MutexGuard<'a, Connection>, deadline: Option<Instant>) -> (::std::sync::MutexGuard<'a, Connection>, bool) {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+
+            if now >= deadline {
+                return (conn, false);
+            }
+
+            (shared.changed.wait_timeout(conn, deadline - now).unwrap().0, true)
+        },
+        None => (shared.changed.wait(conn).unwrap(), true)
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        let deadline = conn.read_timeout.map(|t| Instant::now() + t);
+
+        loop {
+            if !conn.received.is_empty() {
+                return Ok(conn.read(buf));
+            }
+
+            if conn.is_eof() {
+                return Ok(0);
+            }
+
+            if let Some(kind) = conn.error {
+                return Err(io::Error::new(kind, "uTP connection failed"));
+            }
+
+            if conn.nonblocking {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "uTP read would block"));
+            }
+
+            let (next, waiting) = wait(&self.shared, conn, deadline);
+            conn = next;
+
+            if !waiting {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "uTP read timed out"));
+            }
+        }
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.shared.conn.lock().unwrap();
+        let deadline = conn.write_timeout.map(|t| Instant::now() + t);
+
+        loop {
+            if let Some(kind) = conn.error {
+                return Err(io::Error::new(kind, "uTP connection failed"));
+            }
+
+            let written = conn.write(buf);
+
+            if written > 0 || buf.is_empty() {
+                return Ok(written);
+            }
+
+            if conn.nonblocking {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "uTP send buffer full"));
+            }
+
+            let (next, waiting) = wait(&self.shared, conn, deadline);
+            conn = next;
+
+            if !waiting {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "uTP write timed out"));
+            }
+        }
+    }
+
+    /** Written data is sent by the socket as the window allows **/
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for UtpStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        //A registration belongs to one poll for good, so each register starts a new one
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, token, interest, opts)?;
+
+        //Anything that arrived while the stream wasn't registered is reported straight away
+        readiness.set_readiness(Ready::readable() | Ready::writable())?;
+
+        *self.registration.lock().unwrap() = Some(registration);
+        *self.shared.readiness.lock().unwrap() = Some(readiness);
+        Ok(())
+    }
+
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        match *self.registration.lock().unwrap() {
+            Some(ref registration) => poll.reregister(registration, token, interest, opts),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "uTP stream not registered"))
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        *self.shared.readiness.lock().unwrap() = None;
+
+        match self.registration.lock().unwrap().take() {
+            Some(registration) => poll.deregister(&registration),
+            None => Ok(())
+        }
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        *self.shared.readiness.lock().unwrap() = None;
+
+        let mut conn = self.shared.conn.lock().unwrap();
+
+        //Nobody is waiting on an unanswered attempt any more
+        if conn.state == State::SynSent {
+            conn.state = State::Closed;
+        }
+
+        conn.closing = true;
+        conn.flush();
+    }
+}
+
+impl UtpSocket {
+    pub fn local_port(&self) -> u16 {
+        self.socket.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    /** Start connecting out from this socket, the stream reports is_connected once the peer answers **/
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        let socket = self.socket.try_clone()?;
+        let mut connections = self.connections.lock().unwrap();
+
+        //Our receive id must be unused for this peer, as must the send id above it
+        let mut recv_id: u16 = thread_rng().gen();
+
+        while connections.contains_key(&(addr, recv_id)) {
+            recv_id = thread_rng().gen();
+        }
+
+        let shared = Shared::new(Connection::connect(socket, self.epoch, addr, recv_id));
+        connections.insert((addr, recv_id), shared.clone());
+
+        Ok(UtpStream::new(shared, addr))
+    }
+
+    fn dispatch(&self, data: &[u8], from: SocketAddr, incoming: &channel::Sender<UtpStream>) {
+        let packet = match Packet::decode(data) {
+            Ok(packet) => packet,
+            Err(_) => return
+        };
+
+        let mut connections = self.connections.lock().unwrap();
+
+        if packet.kind == PacketType::Syn {
+            let key = (from, packet.connection_id.wrapping_add(1));
+
+            //Our ack to a repeated SYN was lost
+            if let Some(shared) = connections.get(&key) {
+                shared.conn.lock().unwrap().send_ack();
+                return;
+            }
+
+            let socket = match self.socket.try_clone() {
+                Ok(socket) => socket,
+                Err(_) => return
+            };
+
+            let shared = Shared::new(Connection::accept(socket, self.epoch, from, &packet, thread_rng().gen()));
+            connections.insert(key, shared.clone());
+
+            //Nobody is accepting, the dropped stream closes the connection
+            incoming.send(UtpStream::new(shared, from)).ok();
+
+            return;
+        }
+
+        match connections.get(&(from, packet.connection_id)) {
+            Some(shared) => {
+                shared.conn.lock().unwrap().on_packet(packet);
+                shared.notify();
+            },
+            None if packet.kind != PacketType::Reset => {
+                let reset = Packet {
+                    kind: PacketType::Reset,
+                    connection_id: packet.connection_id,
+                    timestamp: 0,
+                    timestamp_diff: 0,
+                    wnd_size: 0,
+                    seq_nr: 0,
+                    ack_nr: packet.seq_nr,
+                    payload: Vec::new()
+                };
+
+                self.socket.send_to(&reset.encode(), from).ok();
+            },
+            None => {}
+        }
+    }
+
+    /** Run retransmission timers and forget closed connections **/
+    fn tick(&self) {
+        let mut connections = self.connections.lock().unwrap();
+
+        connections.retain(|_, shared| {
+            let mut conn = shared.conn.lock().unwrap();
+            let open = conn.state != State::Closed;
+            conn.on_tick();
+
+            //Wake anyone waiting on a connection that has just failed
+            if open && conn.state == State::Closed {
+                shared.notify();
+            }
+
+            conn.state != State::Closed || Arc::strong_count(shared) > 1
+        });
+    }
+}
+
+/**
+ * Reads packets off the shared socket for every connection and runs their timers
+ */
+struct Dispatcher {
+    utp: UtpSocket,
+    socket: mio::net::UdpSocket,
+    incoming: channel::Sender<UtpStream>
+}
+
+impl Handler for Dispatcher {
+    fn start(&mut self, ctx: &mut Context) -> bool {
+        ctx.set_timeout(Duration::from_millis(TICK_MILLIS));
+        ctx.register(0, &self.socket, Ready::readable()).is_ok()
+    }
+
+    fn event(&mut self, ctx: &mut Context, event: Event) -> bool {
+        match event {
+            Event::Ready(..) => {
+                let mut buffer = [0; 2048];
+
+                while let Ok((len, from)) = self.socket.recv_from(&mut buffer) {
+                    self.utp.dispatch(&buffer[..len], from, &self.incoming);
+                }
+            },
+            Event::Timeout => {
+                self.utp.tick();
+                ctx.set_timeout(Duration::from_millis(TICK_MILLIS));
+            }
+        }
+
+        true
+    }
+}
+
+/**
+ * Bind a uTP socket on port, driven by the event loop. Connections peers open to us arrive on
+ * the returned channel.
+ */
+pub fn utp_socket(events: &EventLoop, port: u16) -> io::Result<(UtpSocket, channel::Receiver<UtpStream>)> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    let receiver = mio::net::UdpSocket::from_socket(socket.try_clone()?)?;
+
+    let utp = UtpSocket {
+        socket: Arc::new(socket),
+        epoch: Instant::now(),
+        connections: Arc::new(Mutex::new(HashMap::new()))
+    };
+
+    let (send, recv) = channel::channel();
+
+    events.spawn(Box::new(Dispatcher {
+        utp: utp.clone(),
+        socket: receiver,
+        incoming: send
+    }));
+
+    Ok((utp, recv))
+}
+
+#[cfg(test)]
+mod tests {
+    use utp::utp_socket;
+    use utp::packet::{Packet, PacketType};
+    use utp::connection::{Connection, MAX_PAYLOAD, RECV_BUFFER};
+    use event_loop::{event_loop, recv_timeout};
+    use std::io::{Read, Write, ErrorKind};
+    use std::net::{UdpSocket, SocketAddr};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn transfer_over_loopback() {
+        let events = event_loop(1).unwrap();
+        let (a, _) = utp_socket(&events, 0).unwrap();
+        let (b, incoming) = utp_socket(&events, 0).unwrap();
+
+        let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let sent = data.clone();
+
+        let addr = SocketAddr::from(([127, 0, 0, 1], b.local_port()));
+        let mut stream = a.connect(addr).unwrap();
+
+        let writer = thread::spawn(move || {
+            stream.write_all(&sent).unwrap();
+        });
+
+        let mut accepted = recv_timeout(&incoming, Duration::from_secs(5)).unwrap();
+        accepted.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).unwrap();
+
+        writer.join().unwrap();
+        assert!(received == data);
+    }
+
+    #[test]
+    fn reset_fails_connection() {
+        let events = event_loop(1).unwrap();
+        let (a, _) = utp_socket(&events, 0).unwrap();
+        let raw = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut stream = a.connect(raw.local_addr().unwrap()).unwrap();
+        assert!(!stream.is_connected().unwrap());
+
+        let mut buffer = [0; 2048];
+        let (len, from) = raw.recv_from(&mut buffer).unwrap();
+        let syn = Packet::decode(&buffer[..len]).unwrap();
+
+        let reset = Packet {
+            kind: PacketType::Reset,
+            connection_id: syn.connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr: 0,
+            ack_nr: syn.seq_nr,
+            payload: Vec::new()
+        };
+
+        raw.send_to(&reset.encode(), from).unwrap();
+
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap_err().kind(), ErrorKind::ConnectionReset);
+        assert!(stream.is_connected().is_err());
+    }
+
+    #[test]
+    fn out_of_order_data_counts_against_the_window() {
+        let ours = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let theirs = UdpSocket::bind("127.0.0.1:0").unwrap();
+        theirs.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let packet = |kind: PacketType, seq_nr: u16, payload: Vec<u8>| Packet {
+            kind: kind,
+            connection_id: 7,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: RECV_BUFFER as u32,
+            seq_nr: seq_nr,
+            ack_nr: 99,
+            payload: payload
+        };
+
+        let mut buffer = [0; 2048];
+        let mut acked_window = || {
+            let len = theirs.recv(&mut buffer).unwrap();
+            Packet::decode(&buffer[..len]).unwrap().wnd_size as usize
+        };
+
+        let mut conn = Connection::accept(ours, Instant::now(), theirs.local_addr().unwrap(), &packet(PacketType::Syn, 1, Vec::new()), 100);
+        assert_eq!(acked_window(), RECV_BUFFER);
+
+        //Packet 2 is missing, so everything after it waits in the out of order buffer
+        let fits = RECV_BUFFER / MAX_PAYLOAD;
+
+        for i in 0..fits + 10 {
+            conn.on_packet(packet(PacketType::Data, 3 + i as u16, vec![1; MAX_PAYLOAD]));
+            assert_eq!(acked_window(), RECV_BUFFER - (i + 1).min(fits) * MAX_PAYLOAD);
+        }
+
+        //Filling the gap hands the reader only what was buffered
+        conn.on_packet(packet(PacketType::Data, 2, vec![1; MAX_PAYLOAD]));
+        assert_eq!(conn.received.len(), (fits + 1) * MAX_PAYLOAD);
+    }
+}
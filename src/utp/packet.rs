@@ -0,0 +1,146 @@
+/**
+ * uTP packet header (BEP 29), 20 bytes ahead of the payload
+ */
+
+use std::io::Cursor;
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+
+pub const HEADER_LEN: usize = 20;
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketType {
+    Data,
+    Fin,
+    State, /* Carries no data, used to ack */
+    Reset,
+    Syn
+}
+
+impl PacketType {
+    fn id(&self) -> u8 {
+        match *self {
+            PacketType::Data => 0,
+            PacketType::Fin => 1,
+            PacketType::State => 2,
+            PacketType::Reset => 3,
+            PacketType::Syn => 4
+        }
+    }
+
+    fn from_id(id: u8) -> Option<PacketType> {
+        match id {
+            0 => Some(PacketType::Data),
+            1 => Some(PacketType::Fin),
+            2 => Some(PacketType::State),
+            3 => Some(PacketType::Reset),
+            4 => Some(PacketType::Syn),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    pub timestamp: u32, /* Sender's clock in microseconds when sent */
+    pub timestamp_diff: u32, /* Sender's clock minus our timestamp on the last packet it received, the one way delay plus clock offset */
+    pub wnd_size: u32, /* Receive buffer space the sender has left */
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    pub payload: Vec<u8>
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        res.push((self.kind.id() << 4) | VERSION);
+        res.push(0); //No extensions
+        res.write_u16::<BE>(self.connection_id).unwrap();
+        res.write_u32::<BE>(self.timestamp).unwrap();
+        res.write_u32::<BE>(self.timestamp_diff).unwrap();
+        res.write_u32::<BE>(self.wnd_size).unwrap();
+        res.write_u16::<BE>(self.seq_nr).unwrap();
+        res.write_u16::<BE>(self.ack_nr).unwrap();
+        res.extend_from_slice(&self.payload);
+        res
+    }
+
+    /** Parse a packet, skipping any extensions such as selective acks **/
+    pub fn decode(data: &[u8]) -> Result<Packet, String> {
+        if data.len() < HEADER_LEN {
+            return Err("uTP packet too short".to_string());
+        }
+
+        if data[0] & 0x0f != VERSION {
+            return Err("Unsupported uTP version".to_string());
+        }
+
+        let kind = PacketType::from_id(data[0] >> 4).ok_or("Unknown uTP packet type")?;
+
+        let mut header = Cursor::new(&data[2..HEADER_LEN]);
+
+        let mut packet = Packet {
+            kind: kind,
+            connection_id: header.read_u16::<BE>().unwrap(),
+            timestamp: header.read_u32::<BE>().unwrap(),
+            timestamp_diff: header.read_u32::<BE>().unwrap(),
+            wnd_size: header.read_u32::<BE>().unwrap(),
+            seq_nr: header.read_u16::<BE>().unwrap(),
+            ack_nr: header.read_u16::<BE>().unwrap(),
+            payload: Vec::new()
+        };
+
+        let mut extension = data[1];
+        let mut offset = HEADER_LEN;
+
+        while extension != 0 {
+            if offset + 2 > data.len() {
+                return Err("Truncated uTP extension".to_string());
+            }
+
+            extension = data[offset];
+            offset += 2 + data[offset + 1] as usize;
+
+            if offset > data.len() {
+                return Err("Truncated uTP extension".to_string());
+            }
+        }
+
+        packet.payload = data[offset..].to_vec();
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utp::packet::{Packet, PacketType};
+
+    #[test]
+    fn round_trip_and_extensions() {
+        let packet = Packet {
+            kind: PacketType::Data,
+            connection_id: 0x1234,
+            timestamp: 1,
+            timestamp_diff: 2,
+            wnd_size: 3,
+            seq_nr: 4,
+            ack_nr: 5,
+            payload: b"payload".to_vec()
+        };
+
+        let encoded = packet.encode();
+        assert_eq!(Packet::decode(&encoded).unwrap(), packet);
+
+        //A selective ack extension with a 4 byte bitmask is skipped
+        let mut with_sack = encoded[0..20].to_vec();
+        with_sack[1] = 1;
+        with_sack.extend_from_slice(&[0, 4, 0xff, 0xff, 0xff, 0xff]);
+        with_sack.extend_from_slice(b"payload");
+        assert_eq!(Packet::decode(&with_sack).unwrap(), packet);
+
+        assert!(Packet::decode(&with_sack[0..23]).is_err());
+        assert!(Packet::decode(&[0x51; 20]).is_err());
+    }
+}